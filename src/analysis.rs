@@ -0,0 +1,444 @@
+//! Board scanning helpers for the analysis overlays. These work on a plain
+//! grid so they can look at hypothetical positions without touching the
+//! game itself.
+
+use chess::{Chess, Color, PieceType, Position};
+
+use crate::BOARD_SQUARES;
+
+/// `grid[x][y]` is the piece on that square, white's back rank at `y == 0`.
+pub type Grid = [[Option<(PieceType, Color)>; BOARD_SQUARES]; BOARD_SQUARES];
+
+/// A move as the squares it goes from and to.
+pub type FromTo = ((usize, usize), (usize, usize));
+
+pub const ORTHOGONAL: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+pub const DIAGONAL: [(i32, i32); 4] = [(1, 1), (1, -1), (-1, 1), (-1, -1)];
+
+pub fn grid(board: &Chess) -> Grid {
+    let mut grid = [[None; BOARD_SQUARES]; BOARD_SQUARES];
+    for piece in board.board.iter().flatten() {
+        grid[piece.position.x][piece.position.y] = Some((piece.piece_type, piece.color));
+    }
+    grid
+}
+
+pub fn step(pos: (usize, usize), dir: (i32, i32)) -> Option<(usize, usize)> {
+    let x = pos.0 as i32 + dir.0;
+    let y = pos.1 as i32 + dir.1;
+    let side = 0..BOARD_SQUARES as i32;
+    if side.contains(&x) && side.contains(&y) {
+        Some((x as usize, y as usize))
+    } else {
+        None
+    }
+}
+
+pub fn find_king(grid: &Grid, color: Color) -> Option<(usize, usize)> {
+    (0..BOARD_SQUARES)
+        .flat_map(|x| (0..BOARD_SQUARES).map(move |y| (x, y)))
+        .find(|&(x, y)| matches!(grid[x][y], Some((PieceType::King, c)) if c == color))
+}
+
+/// Whether `piece` slides along `dir`.
+pub fn slides_along(piece: PieceType, dir: (i32, i32)) -> bool {
+    let diagonal = dir.0 != 0 && dir.1 != 0;
+    match piece {
+        PieceType::Queen => true,
+        PieceType::Rook => !diagonal,
+        PieceType::Bishop => diagonal,
+        _ => false,
+    }
+}
+
+/// Pieces of `color` that are absolutely pinned: moving them off the line
+/// would expose their king to an enemy slider.
+pub fn pinned_pieces(grid: &Grid, color: Color) -> Vec<Position> {
+    let king = if let Some(king) = find_king(grid, color) {
+        king
+    } else {
+        return Vec::new();
+    };
+    let mut pinned = Vec::new();
+    for dir in ORTHOGONAL.into_iter().chain(DIAGONAL) {
+        let mut candidate = None;
+        let mut pos = king;
+        while let Some(next) = step(pos, dir) {
+            pos = next;
+            let (piece, piece_color) = if let Some(piece) = grid[pos.0][pos.1] {
+                piece
+            } else {
+                continue;
+            };
+            match candidate {
+                None if piece_color == color => candidate = Some(pos),
+                Some((x, y)) if piece_color != color && slides_along(piece, dir) => {
+                    pinned.push(Position { x, y });
+                    break;
+                }
+                _ => break,
+            }
+        }
+    }
+    pinned
+}
+
+/// Whether neither side can ever deliver mate: only kings and pawns are
+/// left, no pawn can move or capture, and neither king can get through the
+/// pawns to an undefended enemy pawn. Deliberately conservative, so it misses
+/// plenty of dead positions but shouldn't call a live one dead.
+pub fn is_dead_position(grid: &Grid) -> bool {
+    for (x, column) in grid.iter().enumerate() {
+        for (y, square) in column.iter().enumerate() {
+            match *square {
+                None | Some((PieceType::King, _)) => {}
+                Some((PieceType::Pawn, color)) => {
+                    if pawn_can_move(grid, (x, y), color) {
+                        return false;
+                    }
+                }
+                Some(_) => return false,
+            }
+        }
+    }
+    !king_reaches_pawn(grid, Color::White) && !king_reaches_pawn(grid, Color::Black)
+}
+
+fn forward(color: Color) -> i32 {
+    if color == Color::White {
+        1
+    } else {
+        -1
+    }
+}
+
+fn opponent(color: Color) -> Color {
+    if color == Color::White {
+        Color::Black
+    } else {
+        Color::White
+    }
+}
+
+/// A pawn only counts as stuck behind another pawn; a king in the way can
+/// always step aside.
+fn pawn_can_move(grid: &Grid, pos: (usize, usize), color: Color) -> bool {
+    let dir = forward(color);
+    let blocked = step(pos, (0, dir))
+        .is_some_and(|ahead| matches!(grid[ahead.0][ahead.1], Some((PieceType::Pawn, _))));
+    let can_capture = [-1, 1].into_iter().any(|dx| {
+        step(pos, (dx, dir))
+            .is_some_and(|target| matches!(grid[target.0][target.1], Some((_, c)) if c != color))
+    });
+    !blocked || can_capture
+}
+
+fn attacked_by_pawn(grid: &Grid, pos: (usize, usize), color: Color) -> bool {
+    let dir = forward(color);
+    [-1, 1].into_iter().any(|dx| {
+        step(pos, (dx, -dir)).is_some_and(
+            |from| matches!(grid[from.0][from.1], Some((PieceType::Pawn, c)) if c == color),
+        )
+    })
+}
+
+/// Whether the king of `color` can walk to an enemy pawn that no other pawn
+/// defends. The enemy king is ignored, which only lets this king go further.
+fn king_reaches_pawn(grid: &Grid, color: Color) -> bool {
+    let king = if let Some(king) = find_king(grid, color) {
+        king
+    } else {
+        return true;
+    };
+    let enemy = opponent(color);
+    let mut visited = [[false; BOARD_SQUARES]; BOARD_SQUARES];
+    visited[king.0][king.1] = true;
+    let mut stack = vec![king];
+    while let Some(pos) = stack.pop() {
+        for dir in ORTHOGONAL.into_iter().chain(DIAGONAL) {
+            let next = if let Some(next) = step(pos, dir) {
+                next
+            } else {
+                continue;
+            };
+            if visited[next.0][next.1] || attacked_by_pawn(grid, next, enemy) {
+                continue;
+            }
+            visited[next.0][next.1] = true;
+            match grid[next.0][next.1] {
+                Some((PieceType::Pawn, c)) if c == color => {}
+                Some((PieceType::Pawn, _)) => return true,
+                _ => stack.push(next),
+            }
+        }
+    }
+    false
+}
+
+const KNIGHT_JUMPS: [(i32, i32); 8] = [
+    (1, 2),
+    (2, 1),
+    (2, -1),
+    (1, -2),
+    (-1, -2),
+    (-2, -1),
+    (-2, 1),
+    (-1, 2),
+];
+
+/// Squares of the pieces of `color` that attack `target`.
+pub fn attackers(grid: &Grid, target: (usize, usize), color: Color) -> Vec<(usize, usize)> {
+    let is = |pos: (usize, usize), piece: PieceType| matches!(grid[pos.0][pos.1], Some((p, c)) if c == color && p as usize == piece as usize);
+    let mut found = Vec::new();
+    for dx in [-1, 1] {
+        found.extend(step(target, (dx, -forward(color))).filter(|&pos| is(pos, PieceType::Pawn)));
+    }
+    for jump in KNIGHT_JUMPS {
+        found.extend(step(target, jump).filter(|&pos| is(pos, PieceType::Knight)));
+    }
+    for dir in ORTHOGONAL.into_iter().chain(DIAGONAL) {
+        found.extend(step(target, dir).filter(|&pos| is(pos, PieceType::King)));
+        let mut pos = target;
+        while let Some(next) = step(pos, dir) {
+            pos = next;
+            if let Some((piece, c)) = grid[pos.0][pos.1] {
+                if c == color && slides_along(piece, dir) {
+                    found.push(pos);
+                }
+                break;
+            }
+        }
+    }
+    found
+}
+
+/// Whether a piece of `color` attacks `target`.
+pub fn attacked_by(grid: &Grid, target: (usize, usize), color: Color) -> bool {
+    !attackers(grid, target, color).is_empty()
+}
+
+pub fn in_check(grid: &Grid, color: Color) -> bool {
+    find_king(grid, color).is_some_and(|king| attacked_by(grid, king, opponent(color)))
+}
+
+/// The moves among `candidates` that uncover an attack on the enemy king by
+/// a piece of `color` other than the one moving. Castling and en passant are
+/// treated as plain moves of the piece, so those discoveries are missed.
+pub fn discovered_checks(grid: &Grid, color: Color, candidates: &[FromTo]) -> Vec<FromTo> {
+    let enemy_king = if let Some(king) = find_king(grid, opponent(color)) {
+        king
+    } else {
+        return Vec::new();
+    };
+    candidates
+        .iter()
+        .copied()
+        .filter(|&(from, to)| {
+            let mut after = *grid;
+            after[to.0][to.1] = after[from.0][from.1].take();
+            attackers(&after, enemy_king, color)
+                .into_iter()
+                .any(|pos| pos != to)
+        })
+        .collect()
+}
+
+/// Why `color` can't move `from -> to`, for a move the board refused.
+pub fn illegal_reason(
+    grid: &Grid,
+    from: (usize, usize),
+    to: (usize, usize),
+    color: Color,
+) -> &'static str {
+    if [from.0, from.1, to.0, to.1]
+        .iter()
+        .any(|&i| i >= BOARD_SQUARES)
+    {
+        return "the move leaves the board";
+    }
+    match grid[from.0][from.1] {
+        None => return "there is no piece on that square",
+        Some((_, c)) if c != color => return "that piece isn't yours",
+        _ => {}
+    }
+    if matches!(grid[to.0][to.1], Some((_, c)) if c == color) {
+        return "the target square holds a piece of your own";
+    }
+    let mut after = *grid;
+    after[to.0][to.1] = after[from.0][from.1].take();
+    if !in_check(&after, color) {
+        "that piece can't move there"
+    } else if in_check(grid, color) {
+        "it doesn't get the king out of check"
+    } else {
+        "it would expose the king to check"
+    }
+}
+
+/// Squares on the back rank of `color` where an enemy rook or queen could
+/// land straight down its file and mate: the king is on that rank with
+/// every square in front of it blocked by its own pieces or attacked, the
+/// way along the rank to the king is clear, and nothing but the king guards
+/// the landing square. A heuristic, so it ignores interpositions.
+pub fn back_rank_threats(grid: &Grid, color: Color) -> Vec<(usize, usize)> {
+    let back_rank = if color == Color::White {
+        0
+    } else {
+        BOARD_SQUARES - 1
+    };
+    let king = match find_king(grid, color) {
+        Some(king) if king.1 == back_rank => king,
+        _ => return Vec::new(),
+    };
+    let enemy = opponent(color);
+    let boxed_in = [-1, 0, 1].into_iter().all(|dx| {
+        step(king, (dx, forward(color))).is_none_or(|pos| {
+            matches!(grid[pos.0][pos.1], Some((_, c)) if c == color)
+                || attacked_by(grid, pos, enemy)
+        })
+    });
+    if !boxed_in {
+        return Vec::new();
+    }
+    (0..BOARD_SQUARES)
+        .map(|x| (x, back_rank))
+        .filter(|&target| {
+            target != king && !matches!(grid[target.0][target.1], Some((_, c)) if c == enemy)
+        })
+        .filter(|&target| {
+            let (low, high) = (target.0.min(king.0), target.0.max(king.0));
+            (low + 1..high).all(|x| grid[x][back_rank].is_none())
+        })
+        .filter(|&target| {
+            attackers(grid, target, color)
+                .into_iter()
+                .all(|pos| pos == king)
+        })
+        .filter(|&target| {
+            let mut pos = target;
+            while let Some(next) = step(pos, (0, forward(color))) {
+                pos = next;
+                if let Some((piece, c)) = grid[pos.0][pos.1] {
+                    return c == enemy && slides_along(piece, (0, 1));
+                }
+            }
+            false
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The grid for the placement field of a FEN.
+    fn grid_from_placement(placement: &str) -> Grid {
+        let mut grid = [[None; BOARD_SQUARES]; BOARD_SQUARES];
+        for (rank, row) in placement.split('/').enumerate() {
+            let y = 7 - rank;
+            let mut x = 0;
+            for c in row.chars() {
+                if let Some(empty) = c.to_digit(10) {
+                    x += empty as usize;
+                    continue;
+                }
+                let piece = match c.to_ascii_lowercase() {
+                    'k' => PieceType::King,
+                    'q' => PieceType::Queen,
+                    'r' => PieceType::Rook,
+                    'b' => PieceType::Bishop,
+                    'n' => PieceType::Knight,
+                    'p' => PieceType::Pawn,
+                    _ => panic!("'{}' is not a piece", c),
+                };
+                let color = if c.is_ascii_uppercase() {
+                    Color::White
+                } else {
+                    Color::Black
+                };
+                grid[x][y] = Some((piece, color));
+                x += 1;
+            }
+        }
+        grid
+    }
+
+    #[test]
+    fn locked_pawns_with_kings_are_dead() {
+        let grid = grid_from_placement("8/8/4k3/1p1p1p1p/1P1P1P1P/4K3/8/8");
+        assert!(is_dead_position(&grid));
+    }
+
+    #[test]
+    fn middlegame_is_not_dead() {
+        let grid = grid_from_placement("r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R");
+        assert!(!is_dead_position(&grid));
+    }
+
+    #[test]
+    fn king_that_reaches_a_loose_pawn_is_not_dead() {
+        // Every pawn is blocked, but nothing stops either king walking
+        // through the middle to an enemy pawn.
+        let grid = grid_from_placement("8/8/4k3/p6p/P6P/4K3/8/8");
+        assert!(!is_dead_position(&grid));
+    }
+
+    #[test]
+    fn knight_in_front_of_its_king_is_pinned() {
+        let grid = grid_from_placement("4r1k1/8/8/8/8/8/4N3/4K3");
+        let pinned: Vec<_> = pinned_pieces(&grid, Color::White)
+            .into_iter()
+            .map(|pos| (pos.x, pos.y))
+            .collect();
+        assert_eq!(pinned, [(4, 1)]);
+        assert!(pinned_pieces(&grid, Color::Black).is_empty());
+    }
+
+    #[test]
+    fn attackers_and_defenders_of_a_square() {
+        // The bishop on b2 and the rooks on e1 and a5 are blocked from the
+        // black pawn on e5.
+        let grid = grid_from_placement("4q3/8/2np4/rP2p3/3P4/4PN2/1B6/4R3");
+        let sorted = |mut squares: Vec<(usize, usize)>| {
+            squares.sort_unstable();
+            squares
+        };
+        assert_eq!(
+            sorted(attackers(&grid, (4, 4), Color::White)),
+            [(3, 3), (5, 2)]
+        );
+        assert_eq!(
+            sorted(attackers(&grid, (4, 4), Color::Black)),
+            [(2, 5), (3, 5), (4, 7)]
+        );
+    }
+
+    #[test]
+    fn knight_moves_uncover_the_rook_behind_it() {
+        // A white knight on e4 between the rook on e1 and the king on e8.
+        let grid = grid_from_placement("4k3/8/8/8/4N3/8/8/4R1K1");
+        let candidates = [
+            ((4, 3), (3, 5)),
+            ((4, 3), (2, 2)),
+            ((4, 0), (4, 1)),
+            ((4, 0), (0, 0)),
+            ((6, 0), (7, 0)),
+        ];
+        assert_eq!(
+            discovered_checks(&grid, Color::White, &candidates),
+            [((4, 3), (3, 5)), ((4, 3), (2, 2))]
+        );
+        assert!(discovered_checks(&grid, Color::Black, &[((4, 7), (3, 7))]).is_empty());
+    }
+
+    #[test]
+    fn boxed_in_king_warns_of_a_back_rank_mate() {
+        let weak = grid_from_placement("4r1k1/5ppp/8/8/8/8/5PPP/6K1");
+        assert_eq!(back_rank_threats(&weak, Color::White), [(4, 0)]);
+        // A rook guarding the rank, or a pawn moved to make room.
+        let guarded = grid_from_placement("4r1k1/5ppp/8/8/8/8/5PPP/R5K1");
+        assert!(back_rank_threats(&guarded, Color::White).is_empty());
+        let luft = grid_from_placement("4r1k1/5ppp/8/8/8/7P/5PP1/6K1");
+        assert!(back_rank_threats(&luft, Color::White).is_empty());
+    }
+}
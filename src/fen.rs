@@ -0,0 +1,241 @@
+//! Writing positions out as FEN, and syntax checks for FEN strings. The
+//! board can't be set up from one, so a FEN we are given is only checked
+//! for being well formed.
+
+use std::fmt;
+
+use chess::{Chess, Color, PieceType, Position};
+
+use crate::{
+    analysis, en_passant_target, fullmove_number, halfmove_clock, square_name, PlayedMove,
+    BOARD_SQUARES,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FenError {
+    /// Not exactly six space separated fields.
+    FieldCount,
+    /// The placement field doesn't have eight ranks.
+    RankCount,
+    /// A rank, counted from the eighth, doesn't cover eight squares.
+    RankLength(usize),
+    InvalidPiece(char),
+    ActiveColor,
+    Castling,
+    EnPassant,
+    HalfmoveClock,
+    FullmoveNumber,
+}
+
+impl fmt::Display for FenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::FieldCount => write!(f, "expected six fields"),
+            Self::RankCount => write!(f, "expected eight ranks"),
+            Self::RankLength(rank) => {
+                write!(f, "rank {} isn't eight squares long", BOARD_SQUARES - rank)
+            }
+            Self::InvalidPiece(c) => write!(f, "'{}' is not a piece", c),
+            Self::ActiveColor => write!(f, "active color must be w or b"),
+            Self::Castling => write!(f, "bad castling rights"),
+            Self::EnPassant => write!(f, "bad en passant square"),
+            Self::HalfmoveClock => write!(f, "bad halfmove clock"),
+            Self::FullmoveNumber => write!(f, "bad fullmove number"),
+        }
+    }
+}
+
+pub const STARTING_POSITION: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+pub fn validate_fen(s: &str) -> Result<(), FenError> {
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    if fields.len() != 6 {
+        return Err(FenError::FieldCount);
+    }
+    let (placement, color, castling) = (fields[0], fields[1], fields[2]);
+    let (en_passant, halfmove, fullmove) = (fields[3], fields[4], fields[5]);
+    validate_placement(placement)?;
+    if color != "w" && color != "b" {
+        return Err(FenError::ActiveColor);
+    }
+    validate_castling(castling)?;
+    validate_en_passant(en_passant)?;
+    halfmove
+        .parse::<u32>()
+        .map_err(|_| FenError::HalfmoveClock)?;
+    match fullmove.parse::<u32>() {
+        Ok(n) if n >= 1 => Ok(()),
+        _ => Err(FenError::FullmoveNumber),
+    }
+}
+
+fn validate_placement(placement: &str) -> Result<(), FenError> {
+    let ranks: Vec<&str> = placement.split('/').collect();
+    if ranks.len() != BOARD_SQUARES {
+        return Err(FenError::RankCount);
+    }
+    for (i, rank) in ranks.iter().enumerate() {
+        let mut squares = 0;
+        for c in rank.chars() {
+            match c {
+                '1'..='8' => squares += c as usize - '0' as usize,
+                'K' | 'Q' | 'R' | 'B' | 'N' | 'P' | 'k' | 'q' | 'r' | 'b' | 'n' | 'p' => {
+                    squares += 1
+                }
+                _ => return Err(FenError::InvalidPiece(c)),
+            }
+        }
+        if squares != BOARD_SQUARES {
+            return Err(FenError::RankLength(i));
+        }
+    }
+    Ok(())
+}
+
+/// `-`, or some of `KQkq` in that order.
+fn validate_castling(castling: &str) -> Result<(), FenError> {
+    if castling == "-" {
+        return Ok(());
+    }
+    let mut rest = "KQkq";
+    for c in castling.chars() {
+        match rest.find(c) {
+            Some(i) => rest = &rest[i + 1..],
+            None => return Err(FenError::Castling),
+        }
+    }
+    Ok(())
+}
+
+/// `-`, or a square on the third or sixth rank.
+fn validate_en_passant(square: &str) -> Result<(), FenError> {
+    if square == "-" {
+        return Ok(());
+    }
+    match square.as_bytes() {
+        [b'a'..=b'h', b'3' | b'6'] => Ok(()),
+        _ => Err(FenError::EnPassant),
+    }
+}
+
+/// The FEN of `board`, which `history` must have led to from the starting
+/// position.
+pub fn position_fen(board: &Chess, history: &[PlayedMove]) -> String {
+    let grid = analysis::grid(board);
+    let ranks: Vec<String> = (0..BOARD_SQUARES)
+        .rev()
+        .map(|y| {
+            let mut rank = String::new();
+            let mut empty = 0;
+            for column in &grid {
+                match column[y] {
+                    Some((piece, color)) => {
+                        if empty > 0 {
+                            rank += &empty.to_string();
+                            empty = 0;
+                        }
+                        rank.push(piece_char(piece, color));
+                    }
+                    None => empty += 1,
+                }
+            }
+            if empty > 0 {
+                rank += &empty.to_string();
+            }
+            rank
+        })
+        .collect();
+    let color = if board.turn == Color::White { "w" } else { "b" };
+    let en_passant = en_passant_target(history).map_or_else(|| "-".to_owned(), square_name);
+    format!(
+        "{} {} {} {} {} {}",
+        ranks.join("/"),
+        color,
+        castling_rights(history),
+        en_passant,
+        halfmove_clock(history),
+        fullmove_number(history.len())
+    )
+}
+
+fn piece_char(piece: PieceType, color: Color) -> char {
+    let c = match piece {
+        PieceType::King => 'k',
+        PieceType::Queen => 'q',
+        PieceType::Rook => 'r',
+        PieceType::Bishop => 'b',
+        PieceType::Knight => 'n',
+        PieceType::Pawn => 'p',
+    };
+    if color == Color::White {
+        c.to_ascii_uppercase()
+    } else {
+        c
+    }
+}
+
+/// A right is gone once anything has moved from or to its king's or rook's
+/// starting square.
+fn castling_rights(history: &[PlayedMove]) -> String {
+    let rights = [
+        ('K', (4, 0), (7, 0)),
+        ('Q', (4, 0), (0, 0)),
+        ('k', (4, 7), (7, 7)),
+        ('q', (4, 7), (0, 7)),
+    ];
+    let touched = |square: (usize, usize)| {
+        let on = |pos: Position| (pos.x, pos.y) == square;
+        history.iter().any(|mv| on(mv.from) || on(mv.to))
+    };
+    let rights: String = rights
+        .into_iter()
+        .filter(|&(_, king, rook)| !touched(king) && !touched(rook))
+        .map(|(right, _, _)| right)
+        .collect();
+    if rights.is_empty() {
+        "-".to_owned()
+    } else {
+        rights
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn well_formed_positions_pass() {
+        for fen in [
+            STARTING_POSITION,
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1",
+            "8/8/8/8/8/8/8/K6k w - - 99 150",
+            "r3k2r/8/8/8/8/8/8/R3K2R w Kq - 0 20",
+        ] {
+            assert_eq!(validate_fen(fen), Ok(()), "{}", fen);
+        }
+    }
+
+    #[test]
+    fn each_bad_field_is_named() {
+        let cases = [
+            ("8/8/8/8/8/8/8/8 w - - 0", FenError::FieldCount),
+            ("8/8/8/8/8/8/8 w - - 0 1", FenError::RankCount),
+            ("8/8/8/8/8/8/8/8/8 w - - 0 1", FenError::RankCount),
+            ("8/8/9/8/8/8/8/8 w - - 0 1", FenError::InvalidPiece('9')),
+            ("8/8/7/8/8/8/8/8 w - - 0 1", FenError::RankLength(2)),
+            ("8/8/8/8/8/8/8/K7k w - - 0 1", FenError::RankLength(7)),
+            ("8/8/8/8/8/8/8/X7 w - - 0 1", FenError::InvalidPiece('X')),
+            ("8/8/8/8/8/8/8/8 W - - 0 1", FenError::ActiveColor),
+            ("8/8/8/8/8/8/8/8 w QK - 0 1", FenError::Castling),
+            ("8/8/8/8/8/8/8/8 w KKq - 0 1", FenError::Castling),
+            ("8/8/8/8/8/8/8/8 w - e4 0 1", FenError::EnPassant),
+            ("8/8/8/8/8/8/8/8 w - i3 0 1", FenError::EnPassant),
+            ("8/8/8/8/8/8/8/8 w - - -1 1", FenError::HalfmoveClock),
+            ("8/8/8/8/8/8/8/8 w - - 0 0", FenError::FullmoveNumber),
+            ("8/8/8/8/8/8/8/8 w - - 0 x", FenError::FullmoveNumber),
+        ];
+        for (fen, error) in cases {
+            assert_eq!(validate_fen(fen), Err(error), "{}", fen);
+        }
+    }
+}
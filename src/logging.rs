@@ -0,0 +1,102 @@
+//! A small leveled logger. Messages go to stderr and, if one was given, a
+//! log file; anything less severe than the configured level is dropped.
+
+use std::{
+    fs::File,
+    io::Write,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Mutex,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(Self::Error),
+            "warn" => Some(Self::Warn),
+            "info" => Some(Self::Info),
+            "debug" => Some(Self::Debug),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Warn as u8);
+static FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Sets the level and log file for the rest of the run.
+pub fn init(level: Level, path: Option<&str>) -> std::io::Result<()> {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    if let Some(path) = path {
+        *FILE.lock().unwrap() = Some(File::create(path)?);
+    }
+    Ok(())
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn log(level: Level, msg: &str) {
+    if !enabled(level) {
+        return;
+    }
+    let line = format!("[{}] {}", level.name(), msg);
+    eprintln!("{}", line);
+    if let Some(file) = FILE.lock().unwrap().as_mut() {
+        let _ = writeln!(file, "{}", line);
+    }
+}
+
+pub fn error(msg: impl AsRef<str>) {
+    log(Level::Error, msg.as_ref());
+}
+
+pub fn warn(msg: impl AsRef<str>) {
+    log(Level::Warn, msg.as_ref());
+}
+
+pub fn info(msg: impl AsRef<str>) {
+    log(Level::Info, msg.as_ref());
+}
+
+pub fn debug(msg: impl AsRef<str>) {
+    log(Level::Debug, msg.as_ref());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_below_the_level_are_dropped() {
+        let path = std::env::temp_dir().join(format!("chess-log-test-{}", std::process::id()));
+        init(Level::Error, path.to_str()).unwrap();
+        assert!(!enabled(Level::Info));
+        info("an info message");
+        error("an error message");
+        let logged = std::fs::read_to_string(&path).unwrap();
+        init(Level::Warn, None).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(logged.contains("[error] an error message"));
+        assert!(!logged.contains("an info message"));
+    }
+}
@@ -1,799 +1,7393 @@
+mod analysis;
+mod easing;
+mod fen;
+mod logging;
+mod msgpack;
+mod pgn;
+mod replay;
+mod sound;
+mod websocket;
+
 use std::{
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
+    fmt,
+    hash::{BuildHasher, Hash, Hasher},
     io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    sync::{Arc, RwLock},
+    iter::Peekable,
+    net::{Shutdown, TcpListener, TcpStream},
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
 use chess::{Chess, Color as ChessColor, Move, PieceType, Position, Status, ValidationResult};
 use chess_networking::{Ack, GameState, PromotionPiece, Start};
+use easing::Easing;
 use ggez::{
-    conf::WindowMode,
+    conf::{NumSamples, WindowMode},
     event::{self, MouseButton},
     glam::*,
     graphics::{
-        self, Canvas, Color, DrawParam, Drawable, Image, ImageFormat, Mesh, Text,
-        TextFragment,
+        self, Canvas, Color, DrawParam, Drawable, Image, ImageFormat, Mesh, Text, TextFragment,
     },
     input::keyboard::KeyCode,
-    Context, GameResult,
+    Context, GameError, GameResult,
 };
+use serde::{Deserialize, Serialize};
+use sound::MoveSounds;
+use websocket::WsTransport;
 
 const WIDTH: f32 = 800.0;
 const HEIGHT: f32 = 800.0;
 const PIECE_TEX_SIZE: f32 = 1024.0;
+/// Squares along each side of the board. The `chess` crate only plays on
+/// 8x8, so this is only here to keep the number in one place.
+const BOARD_SQUARES: usize = 8;
+const SQUARE_SIZE: f32 = WIDTH / BOARD_SQUARES as f32;
+const RECOVERY_FILE: &str = "chess-recovery.txt";
+const CONFIG_FILE: &str = "chess.conf";
+const PGN_FILE: &str = "game.pgn";
+/// The addresses last hosted and connected on, as `host = ...` and
+/// `client = ...` lines.
+const LAST_ADDRESS_FILE: &str = "chess-last-address.conf";
+const BORDER_WIDTH: f32 = 6.0;
+const MAX_LOGGED_ERRORS: usize = 10;
+const MINIMAP_SCALE: f32 = 0.2;
+/// How long a promotion can still be changed with `--promotion-undo`.
+const PROMOTION_EDIT_WINDOW: Duration = Duration::from_secs(2);
+/// How long J flashes the opponent's last move.
+const LAST_MOVE_FLASH: Duration = Duration::from_millis(1500);
+/// Presses at least this long count as long presses for
+/// `--long-press-preview`.
+const LONG_PRESS: Duration = Duration::from_millis(500);
+/// With nothing happening for this long, frames slow down to `IDLE_FPS`.
+const IDLE_AFTER: Duration = Duration::from_secs(2);
+const IDLE_FPS: u32 = 10;
+/// Quiet half-moves after which the draw watch warns about the fifty-move
+/// rule, which draws at 100.
+const FIFTY_MOVE_WATCH: usize = 80;
+const MAX_CHAT_LINES: usize = 8;
+const MAX_CHAT_MESSAGE: usize = 200;
+/// Sent as plain text to anyone who connects once the host has an opponent.
+const GAME_FULL: &str = "Game full\n";
+/// A `--selftest` game this long is taken to be stuck.
+const SELF_TEST_MAX_PLIES: usize = 2000;
 
-fn get_board_coordinate(x: f32, y: f32, sc_width: f32, sc_height: f32) -> Option<(u8, u8)> {
-    let sq_size = WIDTH / 8.0;
-    let x = (WIDTH - sc_width) / 2. + x;
-    let y = (HEIGHT - sc_height) / 2. + y;
-    if x < 0.0 || y < 0.0 {
-        return None;
-    }
-    let x = (x / sq_size) as u8;
-    let y = (y / sq_size) as u8;
-    if x >= 8 || y >= 8 {
-        return None;
-    }
-    Some((x, y))
-}
-
-#[derive(Debug, Clone)]
-enum GameType {
-    Local,
-    Host(String),
-    Client(String),
+/// Where the board goes in a `sc_width` by `sc_height` window: the largest
+/// square that fits, centered, with the rest left as margins.
+fn board_viewport(sc_width: f32, sc_height: f32) -> graphics::Rect {
+    let size = sc_width.min(sc_height);
+    graphics::Rect::new((sc_width - size) / 2., (sc_height - size) / 2., size, size)
 }
 
-#[derive(Debug, Clone)]
-struct Player {
-    color: ChessColor,
-    name: Option<String>,
-    local: bool,
+/// Maps a window position to view space: the `WIDTH` by `HEIGHT` square
+/// the unzoomed board fills.
+fn to_view_space(x: f32, y: f32, sc_width: f32, sc_height: f32) -> Vec2 {
+    let viewport = board_viewport(sc_width, sc_height);
+    Vec2::new(
+        (x - viewport.x) * WIDTH / viewport.w,
+        (y - viewport.y) * HEIGHT / viewport.h,
+    )
 }
 
-struct Players {
-    black: Player,
-    white: Player,
+/// The screen coordinates that letterbox view space in the window.
+fn view_screen_coordinates(sc_width: f32, sc_height: f32) -> graphics::Rect {
+    let viewport = board_viewport(sc_width, sc_height);
+    let scale_x = WIDTH / viewport.w;
+    let scale_y = HEIGHT / viewport.h;
+    graphics::Rect::new(
+        -viewport.x * scale_x,
+        -viewport.y * scale_y,
+        sc_width * scale_x,
+        sc_height * scale_y,
+    )
 }
 
-impl Players {
-    fn get_player(&self, color: ChessColor) -> &Player {
-        if color == ChessColor::White {
-            &self.white
-        } else {
-            &self.black
-        }
-    }
+/// Where a `width` by `height` box in view space goes so that it sits in the
+/// middle of an `sc_width` by `sc_height` window.
+fn centered_in_window(width: f32, height: f32, sc_width: f32, sc_height: f32) -> Vec2 {
+    to_view_space(sc_width / 2., sc_height / 2., sc_width, sc_height)
+        - Vec2::new(width, height) / 2.
 }
 
-enum NetworkType {
-    Host {
-        listener: TcpListener,
-        stream: TcpStream,
-    },
-    Client(TcpStream),
-}
+const MAX_ZOOM: f32 = 4.0;
 
-#[derive(Debug, Clone)]
-enum PacketType {
-    Start(Start),
-    Move(chess_networking::Move),
-    Ack(Ack),
+/// Zoom and pan of the board. Board space is where squares are 100 units
+/// wide; at zoom 1 it lines up with view space.
+#[derive(Debug, Clone, Copy)]
+struct Camera {
+    zoom: f32,
+    /// The board space point in the middle of the view.
+    center: Vec2,
 }
 
-impl TryFrom<&[u8]> for PacketType {
-    type Error = ();
-    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
-        if let Ok(start) = Start::try_from(data) {
-            return Ok(Self::Start(start));
-        }
-        if let Ok(mv) = chess_networking::Move::try_from(data) {
-            return Ok(Self::Move(mv));
-        }
-        if let Ok(ack) = Ack::try_from(data) {
-            return Ok(Self::Ack(ack));
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            zoom: 1.,
+            center: Vec2::new(WIDTH / 2., HEIGHT / 2.),
         }
-        Err(())
     }
 }
 
-impl TryFrom<PacketType> for Vec<u8> {
-    type Error = rmp_serde::encode::Error;
-    fn try_from(packet: PacketType) -> Result<Self, Self::Error> {
-        match packet {
-            PacketType::Start(start) => Vec::try_from(start),
-            PacketType::Move(mv) => Vec::try_from(mv),
-            PacketType::Ack(ack) => Vec::try_from(ack),
-        }
+impl Camera {
+    fn to_board(self, view: Vec2) -> Vec2 {
+        self.center + (view - Vec2::new(WIDTH / 2., HEIGHT / 2.)) / self.zoom
     }
-}
 
-struct Network {
-    ty: NetworkType,
-    cache: Arc<RwLock<VecDeque<PacketType>>>,
-    thread_handle: std::thread::JoinHandle<()>,
-}
+    /// The part of board space shown in `view`, a rectangle in view space.
+    fn visible(self, view: graphics::Rect) -> graphics::Rect {
+        let top_left = self.to_board(Vec2::new(view.x, view.y));
+        graphics::Rect::new(
+            top_left.x,
+            top_left.y,
+            view.w / self.zoom,
+            view.h / self.zoom,
+        )
+    }
 
-impl Network {
-    fn new_host(host: &str) -> Self {
-        let listener = TcpListener::bind(host).unwrap();
-        let (stream, _) = listener.accept().unwrap();
-        let cache = Arc::new(RwLock::new(VecDeque::new()));
-        let cache_clone = cache.clone();
-        let thread_handle = Self::spawn_thread(stream.try_clone().unwrap(), cache_clone);
-        Self {
-            ty: NetworkType::Host { listener, stream },
-            cache,
-            thread_handle,
-        }
+    /// Zooms by `factor`, keeping the board under the view point `anchor`
+    /// in place.
+    fn zoom_at(&mut self, anchor: Vec2, factor: f32) {
+        let fixed = self.to_board(anchor);
+        self.zoom = (self.zoom * factor).clamp(1., MAX_ZOOM);
+        self.center = fixed - (anchor - Vec2::new(WIDTH / 2., HEIGHT / 2.)) / self.zoom;
+        self.clamp();
     }
 
-    fn spawn_thread(
-        stream: TcpStream,
-        cache: Arc<RwLock<VecDeque<PacketType>>>,
-    ) -> std::thread::JoinHandle<()> {
-        std::thread::spawn(move || {
-            let mut stream = stream;
-            loop {
-                let mut data = [0u8; 1024];
-                if let Ok(size) = stream.read(&mut data) {
-                    let packet = PacketType::try_from(&data[..size]).unwrap();
-                    let mut cache = cache.write().unwrap();
-                    cache.push_back(packet);
-                }
-            }
-        })
+    /// Moves the board along with a drag of `delta` in view space.
+    fn pan(&mut self, delta: Vec2) {
+        self.center -= delta / self.zoom;
+        self.clamp();
     }
 
-    fn new_client(host: &str) -> Self {
-        let stream = TcpStream::connect(host).unwrap();
-        let cache = Arc::new(RwLock::new(VecDeque::new()));
-        let cache_clone = cache.clone();
-        let thread_handle = Self::spawn_thread(stream.try_clone().unwrap(), cache_clone);
-        Self {
-            ty: NetworkType::Client(stream),
-            cache,
-            thread_handle,
-        }
+    /// Keeps the view on the board.
+    fn clamp(&mut self) {
+        let half_width = WIDTH / 2. / self.zoom;
+        let half_height = HEIGHT / 2. / self.zoom;
+        self.center.x = self.center.x.clamp(half_width, WIDTH - half_width);
+        self.center.y = self.center.y.clamp(half_height, HEIGHT - half_height);
     }
+}
 
-    fn send(&mut self, data: &[u8]) {
-        match self.ty {
-            NetworkType::Host { ref mut stream, .. } => {
-                stream.write(data).unwrap();
-            }
-            NetworkType::Client(ref mut stream) => {
-                stream.write(data).unwrap();
-            }
-        }
+fn get_board_coordinate(
+    x: f32,
+    y: f32,
+    sc_width: f32,
+    sc_height: f32,
+    camera: Camera,
+) -> Option<(u8, u8)> {
+    let pos = camera.to_board(to_view_space(x, y, sc_width, sc_height));
+    if pos.x < 0.0 || pos.y < 0.0 || pos.x >= WIDTH || pos.y >= HEIGHT {
+        return None;
     }
+    let x = (pos.x / SQUARE_SIZE) as u8;
+    let y = (pos.y / SQUARE_SIZE) as u8;
+    Some((x, y))
+}
 
-    fn init(&mut self) -> Players {
-        match self.ty {
-            NetworkType::Host { .. } => {
-                let start = if let PacketType::Start(start) = self.get_packet_blocking() {
-                    start
-                } else {
-                    panic!("Failed to receive start packet");
-                };
-                let start_packet = PacketType::Start(Start {
-                    name: None,
-                    is_white: true,
-                    fen: None,
-                    time: None,
-                    inc: None,
-                });
-                self.send_packet(start_packet);
-                let main = Player {
-                    color: ChessColor::White,
-                    name: None,
-                    local: true,
-                };
-                let opp = Player {
-                    color: ChessColor::Black,
-                    name: start.name,
-                    local: false,
-                };
-                Players {
-                    white: main,
-                    black: opp,
-                }
-            }
-            NetworkType::Client(_) => {
-                let start = Start {
-                    name: None,
-                    is_white: true,
-                    fen: None,
-                    time: None,
-                    inc: None,
-                };
-                let start_packet = PacketType::Start(start);
-                self.send_packet(start_packet);
-                let start_packet = self.get_packet_blocking();
-                if let PacketType::Start(start) = start_packet {
-                    if start.is_white {
-                        let main = Player {
-                            color: ChessColor::Black,
-                            name: start.name,
-                            local: true,
-                        };
-                        let opp = Player {
-                            color: ChessColor::White,
-                            name: None,
-                            local: false,
-                        };
-                        return Players {
-                            white: opp,
-                            black: main,
-                        };
-                    } else {
-                        let main = Player {
-                            color: ChessColor::White,
-                            name: start.name,
-                            local: true,
-                        };
-                        let opp = Player {
-                            color: ChessColor::Black,
-                            name: None,
-                            local: false,
-                        };
-                        return Players {
-                            white: main,
-                            black: opp,
-                        };
-                    }
-                } else {
-                    panic!("Failed to receive start packet");
-                }
-            }
+/// The keyboard and mouse as one update sees them, read from the context
+/// up front so the handlers don't need one.
+struct Input {
+    /// Keys pressed since the last update.
+    keys: HashSet<KeyCode>,
+    clicked: HashSet<MouseButton>,
+    released: HashSet<MouseButton>,
+    held: HashSet<MouseButton>,
+    /// The cursor in window coordinates.
+    cursor: Vec2,
+    mouse_delta: Vec2,
+    window: (f32, f32),
+}
+
+impl Input {
+    fn read(ctx: &Context) -> Input {
+        let keyboard = &ctx.keyboard;
+        let buttons = [MouseButton::Left, MouseButton::Right, MouseButton::Middle];
+        let buttons_where = |test: &dyn Fn(MouseButton) -> bool| {
+            buttons.into_iter().filter(|&button| test(button)).collect()
+        };
+        let cursor = ctx.mouse.position();
+        let delta = ctx.mouse.delta();
+        Input {
+            keys: keyboard
+                .pressed_keys()
+                .iter()
+                .copied()
+                .filter(|&key| keyboard.is_key_just_pressed(key))
+                .collect(),
+            clicked: buttons_where(&|button| ctx.mouse.button_just_pressed(button)),
+            released: buttons_where(&|button| ctx.mouse.button_just_released(button)),
+            held: buttons_where(&|button| ctx.mouse.button_pressed(button)),
+            cursor: Vec2::new(cursor.x, cursor.y),
+            mouse_delta: Vec2::new(delta.x, delta.y),
+            window: ctx.gfx.size(),
         }
     }
 
-    fn get_packet(&mut self) -> Option<PacketType> {
-        let mut cache = self.cache.write().unwrap();
-        let packet = cache.pop_front();
-        packet
+    fn key(&self, key: KeyCode) -> bool {
+        self.keys.contains(&key)
     }
 
-    fn get_packet_blocking(&mut self) -> PacketType {
-        loop {
-            if let Some(packet) = self.get_packet() {
-                return packet;
-            }
-        }
+    fn clicked(&self, button: MouseButton) -> bool {
+        self.clicked.contains(&button)
     }
 
-    fn send_packet(&mut self, packet: PacketType) {
-        let data: Vec<u8> = Vec::try_from(packet).unwrap();
-        self.send(&data);
+    fn released(&self, button: MouseButton) -> bool {
+        self.released.contains(&button)
     }
 
-    fn close(self) {
-        match self.ty {
-            NetworkType::Host { listener, stream } => {
-                drop(listener);
-                drop(stream);
-            }
-            NetworkType::Client(stream) => {
-                drop(stream);
-            }
+    fn held(&self, button: MouseButton) -> bool {
+        self.held.contains(&button)
+    }
+}
+
+/// Nothing pressed, in a window the size of the board.
+impl Default for Input {
+    fn default() -> Self {
+        Input {
+            keys: HashSet::new(),
+            clicked: HashSet::new(),
+            released: HashSet::new(),
+            held: HashSet::new(),
+            cursor: Vec2::ZERO,
+            mouse_delta: Vec2::ZERO,
+            window: (WIDTH, HEIGHT),
         }
-        self.thread_handle.join().unwrap();
     }
 }
 
-struct PlayerHandler {
-    game_type: GameType,
-    players: Players,
-    network: Option<Network>,
+/// Index of the square `x, y` in the lists `generate_valid_moves` returns.
+fn move_index(x: usize, y: usize) -> usize {
+    x + y * BOARD_SQUARES
 }
 
-impl PlayerHandler {
-    fn new(game_type: GameType) -> Self {
-        let mut network = match &game_type {
-            GameType::Host(host) => Some(Network::new_host(host)),
-            GameType::Client(host) => Some(Network::new_client(host)),
+/// Optional analysis aids drawn on top of the board.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Overlays {
+    /// Mark pieces of the side to move that are pinned to their king.
+    pins: bool,
+    /// Mark squares where the side to move could be mated on its back rank.
+    back_rank: bool,
+    /// Write each piece's point value on its square, toggled with V.
+    piece_values: bool,
+    /// Draw the moves that would give a discovered check.
+    discovered_checks: bool,
+    /// Draw an arrow along the opponent's last move.
+    last_move_arrow: bool,
+    /// Mark what attacks and defends the piece under the cursor.
+    hover_threats: bool,
+}
+
+/// The display aids that clean view turns off, as they were before it.
+struct ViewSnapshot {
+    overlays: Overlays,
+    hover_moves: bool,
+    square_tooltips: bool,
+    minimap: bool,
+    show_errors: bool,
+}
+
+/// How the board edges are labelled: standard algebraic files and ranks, or
+/// the zero-based indices the code uses, for teaching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CoordinateStyle {
+    Algebraic,
+    Numeric,
+}
+
+impl CoordinateStyle {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "algebraic" => Some(Self::Algebraic),
+            "numeric" => Some(Self::Numeric),
             _ => None,
-        };
-        let players = match game_type {
-            GameType::Local => Players {
-                white: Player {
-                    color: ChessColor::White,
-                    name: None,
-                    local: true,
-                },
-                black: Player {
-                    color: ChessColor::Black,
-                    name: None,
-                    local: true,
-                },
-            },
-            _ => {
-                let network = network.as_mut().unwrap();
-                network.init()
-            }
-        };
-        Self {
-            game_type,
-            players,
-            network,
         }
     }
 
-    fn can_move(&self, color: ChessColor) -> bool {
-        if let GameType::Local = self.game_type {
-            return true;
+    fn toggled(self) -> Self {
+        match self {
+            Self::Algebraic => Self::Numeric,
+            Self::Numeric => Self::Algebraic,
         }
-        let player = self.players.get_player(color);
-        player.local
     }
 
-    fn both_local(&self) -> bool {
-        self.players.black.local && self.players.white.local
+    /// The file and rank labels of `pos`, "a" and "1" or "0" and "0".
+    fn labels(self, pos: Position) -> (String, String) {
+        match self {
+            Self::Algebraic => (
+                ((b'a' + pos.x as u8) as char).to_string(),
+                (pos.y + 1).to_string(),
+            ),
+            Self::Numeric => (pos.x.to_string(), pos.y.to_string()),
+        }
     }
 
-    fn one_local(&self) -> Option<ChessColor> {
-        if self.players.black.local {
-            Some(ChessColor::Black)
-        } else if self.players.white.local {
-            Some(ChessColor::White)
-        } else {
-            None
+    /// The whole name of `pos`, "a1" or "0,0".
+    fn square_label(self, pos: Position) -> String {
+        let (file, rank) = self.labels(pos);
+        match self {
+            Self::Algebraic => file + &rank,
+            Self::Numeric => format!("{},{}", file, rank),
         }
     }
 }
 
-#[derive(Clone, Debug)]
-enum MoveKind {
-    Builtin(Move),
-    Network(chess_networking::Move),
+/// The colors and line weights the board is drawn with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Theme {
+    Classic,
+    Green,
+    Blue,
+    Gray,
+    /// Black and white squares, bold outlines and larger move dots, for
+    /// low-vision players. Square images are ignored.
+    HighContrast,
 }
 
-impl MoveKind {
-    fn to(&self) -> Position {
+impl Theme {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "classic" => Some(Self::Classic),
+            "green" => Some(Self::Green),
+            "blue" => Some(Self::Blue),
+            "gray" => Some(Self::Gray),
+            "high-contrast" => Some(Self::HighContrast),
+            _ => None,
+        }
+    }
+
+    /// The theme after this one when cycling with T.
+    fn next(self) -> Self {
         match self {
-            MoveKind::Builtin(mv) => mv.to,
-            MoveKind::Network(mv) => Position {
-                x: mv.to.0 as usize,
-                y: mv.to.1 as usize,
-            },
+            Self::Classic => Self::Green,
+            Self::Green => Self::Blue,
+            Self::Blue => Self::Gray,
+            Self::Gray => Self::HighContrast,
+            Self::HighContrast => Self::Classic,
         }
     }
 
-    fn from(&self) -> Position {
+    fn light_square(self) -> Color {
         match self {
-            MoveKind::Builtin(mv) => mv.from,
-            MoveKind::Network(mv) => Position {
-                x: mv.from.0 as usize,
-                y: mv.from.1 as usize,
-            },
+            Self::Classic => Color::from_rgb(255, 206, 158),
+            Self::Green => Color::from_rgb(238, 238, 210),
+            Self::Blue => Color::from_rgb(222, 227, 230),
+            Self::Gray => Color::from_rgb(200, 200, 200),
+            Self::HighContrast => Color::from_rgb(255, 255, 255),
         }
     }
 
-    fn promotion(&self) -> PieceType {
+    fn dark_square(self) -> Color {
         match self {
-            // Always promote to queen, had to change this since networking would
-            // require a super dumb hackfix to work.
-            // TODO: If time permits, think of something smart to do here
-            MoveKind::Builtin(_) => PieceType::Queen,
-            MoveKind::Network(mv) => {
-                if let Some(promotion) = &mv.promotion {
-                    match promotion {
-                        PromotionPiece::Queen => PieceType::Queen,
-                        PromotionPiece::Rook => PieceType::Rook,
-                        PromotionPiece::Bishop => PieceType::Bishop,
-                        PromotionPiece::Knight => PieceType::Knight,
-                    }
-                } else {
-                    PieceType::Queen
-                }
-            }
+            Self::Classic => Color::from_rgb(209, 139, 71),
+            Self::Green => Color::from_rgb(118, 150, 86),
+            Self::Blue => Color::from_rgb(140, 162, 173),
+            Self::Gray => Color::from_rgb(120, 120, 120),
+            Self::HighContrast => Color::from_rgb(0, 0, 0),
         }
     }
-}
 
-enum Phase {
-    Move,
-    Validate(MoveKind),
-    End(Status),
+    /// The dot marking a legal move, in a color that shows up on both square
+    /// colors.
+    fn move_dot(self) -> (Color, f32) {
+        match self {
+            Self::HighContrast => (Color::from_rgb(255, 0, 200), 28.),
+            _ => (Color::from_rgba(255, 255, 255, 128), 20.),
+        }
+    }
+
+    fn border_width(self) -> f32 {
+        match self {
+            Self::HighContrast => 2. * BORDER_WIDTH,
+            _ => BORDER_WIDTH,
+        }
+    }
 }
 
-struct MainState {
-    board: Chess,
-    board_texture: Image,
-    piece_textures: [Image; 12],
-    move_to_dot: Mesh,
-    current_moves: Option<[Vec<Move>; 64]>,
-    selected_square: Option<(u8, u8)>,
-    text_prompt: Option<Text>,
-    player_handler: PlayerHandler,
-    phase: Phase,
+/// The colors highlights are drawn in, separate from the board's theme.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Palette {
+    Standard,
+    /// Orange and blue instead of red and green, with defenders drawn as
+    /// rings so the two sides differ in shape as well.
+    ColorBlind,
 }
 
-impl MainState {
-    fn new(ctx: &mut Context, game_type: GameType) -> GameResult<MainState> {
-        let board = Chess::new();
-        let mut pixels = Vec::with_capacity(WIDTH as usize * HEIGHT as usize * 4);
-        let sq_size = WIDTH / 8.0;
-        for y in 0..8 {
-            for _ in 0..sq_size as usize {
-                for x in 0..8 {
-                    let color = if (x + y) % 2 == 0 {
-                        Color::from_rgb(255, 206, 158)
-                    } else {
-                        Color::from_rgb(209, 139, 71)
-                    };
-                    let color_slice = color.to_rgba();
-                    let color_slice = [color_slice.0, color_slice.1, color_slice.2, color_slice.3];
-                    for _ in 0..sq_size as usize {
-                        pixels.extend_from_slice(&color_slice);
-                    }
-                }
-            }
+impl Palette {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "standard" => Some(Self::Standard),
+            "color-blind" => Some(Self::ColorBlind),
+            _ => None,
         }
-        let board_texture = Image::from_pixels(
-            ctx,
-            &pixels,
-            ImageFormat::Rgba8Unorm,
-            WIDTH as u32,
-            HEIGHT as u32,
-        );
-        let piece_textures = [
-            Image::from_bytes(ctx, include_bytes!("../assets/k_w.png"))?,
-            Image::from_bytes(ctx, include_bytes!("../assets/q_w.png"))?,
-            Image::from_bytes(ctx, include_bytes!("../assets/r_w.png"))?,
-            Image::from_bytes(ctx, include_bytes!("../assets/b_w.png"))?,
-            Image::from_bytes(ctx, include_bytes!("../assets/n_w.png"))?,
-            Image::from_bytes(ctx, include_bytes!("../assets/p_w.png"))?,
-            Image::from_bytes(ctx, include_bytes!("../assets/k_b.png"))?,
-            Image::from_bytes(ctx, include_bytes!("../assets/q_b.png"))?,
-            Image::from_bytes(ctx, include_bytes!("../assets/r_b.png"))?,
-            Image::from_bytes(ctx, include_bytes!("../assets/b_b.png"))?,
-            Image::from_bytes(ctx, include_bytes!("../assets/n_b.png"))?,
-            Image::from_bytes(ctx, include_bytes!("../assets/p_b.png"))?,
-        ];
-
-        let move_to_dot = Mesh::new_circle(
-            ctx,
-            graphics::DrawMode::fill(),
-            Vec2::new(0., 0.),
-            20.,
-            2.,
-            Color::from_rgba(255, 255, 255, 128),
-        )?;
-
-        Ok(MainState {
-            board,
-            board_texture,
-            move_to_dot,
-            piece_textures,
-            current_moves: None,
-            selected_square: None,
-            text_prompt: None,
-            player_handler: PlayerHandler::new(game_type),
-            phase: Phase::Move,
-        })
     }
 
-    fn get_moves(&self) -> Option<&Vec<Move>> {
-        let selected_square = self.selected_square?;
-        let moves = self.current_moves.as_ref().unwrap();
-        Some(&moves[selected_square.0 as usize + selected_square.1 as usize * 8])
+    fn attacker(self) -> Color {
+        match self {
+            Self::Standard => Color::from_rgb(220, 40, 40),
+            Self::ColorBlind => Color::from_rgb(230, 159, 0),
+        }
     }
 
-    fn draw_pieces(&self, canvas: &mut Canvas) -> GameResult {
-        let reverse = self.should_reverse();
-        let pieces = &self.board.board;
-        for piece in pieces {
-            let piece = if let Some(piece) = piece {
-                piece
-            } else {
-                continue;
-            };
-            let texture_idx = piece.piece_type as usize
-                + if piece.color == ChessColor::White {
-                    0
-                } else {
-                    6
-                };
-            let texture = &self.piece_textures[texture_idx];
-            let x = piece.position.x as f32 * WIDTH / 8.0;
-            let y = piece.position.y as f32 * HEIGHT / 8.0;
-            let mut dest = Vec2::new(x, y);
-            if reverse {
-                dest.y = 700. - dest.y;
-            }
-            const SCALE: f32 = 100.0 / PIECE_TEX_SIZE;
-            let draw_params = DrawParam::new().dest(dest).scale(Vec2::new(SCALE, SCALE));
-            canvas.draw(texture, draw_params);
+    fn defender(self) -> Color {
+        match self {
+            Self::Standard => Color::from_rgb(40, 200, 80),
+            Self::ColorBlind => Color::from_rgb(0, 114, 178),
         }
-        Ok(())
     }
+}
 
-    fn draw_selected(&self, canvas: &mut Canvas) -> GameResult {
-        let reverse = self.should_reverse();
-        let moves = self.get_moves();
-        if moves.is_none() {
-            return Ok(());
+/// Which of our moves wait for Enter before they are played.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfirmMode {
+    Never,
+    Always,
+    /// Only moves that take a piece, the costly mistakes.
+    Captures,
+}
+
+impl ConfirmMode {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "never" => Some(Self::Never),
+            "always" => Some(Self::Always),
+            "captures" => Some(Self::Captures),
+            _ => None,
         }
-        let moves = moves.unwrap();
-        for mv in moves {
-            let x = mv.to.x as f32 * WIDTH / 8.0;
-            let y = mv.to.y as f32 * HEIGHT / 8.0;
-            let mut dest = Vec2::new(50. + x, y);
-            if reverse {
-                dest.y = 700. - dest.y;
-            }
-            dest.y += 50.;
-            canvas.draw(&self.move_to_dot, DrawParam::new().dest(dest));
+    }
+
+    fn requires(self, board: &Chess, mv: &Move) -> bool {
+        match self {
+            Self::Never => false,
+            Self::Always => true,
+            Self::Captures => captured_piece(board, mv.from, mv.to).is_some(),
         }
-        Ok(())
     }
+}
 
-    fn draw_prompt(&self, ctx: &mut Context, canvas: &mut Canvas) -> GameResult {
-        if let Some(text) = &self.text_prompt {
-            let dims = text.dimensions(ctx).unwrap();
-            let width = dims.w;
-            let height = dims.h;
-            let x = WIDTH / 2. - width / 2.;
-            let y = HEIGHT / 2. - height / 2.;
-            let dest = Vec2::new(x, y);
-            canvas.draw(text, DrawParam::new().dest(dest));
+/// How pieces are moved with the mouse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InputScheme {
+    /// Click the piece, then click its target.
+    Click,
+    /// Press on the piece and release over its target.
+    Drag,
+    Both,
+}
+
+impl InputScheme {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "click" => Some(Self::Click),
+            "drag" => Some(Self::Drag),
+            "both" => Some(Self::Both),
+            _ => None,
         }
-        Ok(())
     }
 
-    fn client_move(&mut self, ctx: &mut Context) -> GameResult<()> {
-        if !self.player_handler.can_move(self.board.turn) {
-            return Ok(());
+    fn clicks(self) -> bool {
+        self != Self::Drag
+    }
+
+    fn drags(self) -> bool {
+        self != Self::Click
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct NetworkSettings {
+    /// Bytes asked for per read from the connection.
+    read_buffer: usize,
+    /// Largest packet accepted. A packet saying it is longer drops the
+    /// connection before any more of it is read.
+    max_packet: usize,
+    /// Held back from every packet we send, to try the game out over a slow
+    /// connection.
+    sim_latency: Duration,
+    /// How many more times a client tries to reach the host after the first
+    /// attempt fails, waiting twice as long each time.
+    connect_retries: u32,
+    /// Received packets waiting to be handled. Chat arriving while this many
+    /// are queued is dropped; a game packet pushes out the oldest chat, or
+    /// drops the connection if there is none.
+    max_queued: usize,
+    /// Chat messages beyond this many a second are dropped. Moves, acks and
+    /// take-back requests never are, since losing one breaks the game.
+    chat_per_second: u32,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            read_buffer: 1024,
+            max_packet: 64 * 1024,
+            sim_latency: Duration::ZERO,
+            connect_retries: 5,
+            max_queued: 256,
+            chat_per_second: 5,
         }
-        if !ctx.mouse.button_just_pressed(MouseButton::Left) {
-            return Ok(());
+    }
+}
+
+/// Optional tweaks to how the game looks and behaves, set from the config
+/// file and the command line. Everything here has a sensible default.
+#[derive(Debug, Clone)]
+struct Config {
+    light_square_image: Option<String>,
+    dark_square_image: Option<String>,
+    /// Replace the classic theme's square colors.
+    light_square_color: Option<Color>,
+    dark_square_color: Option<Color>,
+    /// Local games are written to the recovery file after this many unsaved
+    /// moves...
+    autosave_moves: usize,
+    /// ...or once this much time has passed with unsaved moves.
+    autosave_interval: Duration,
+    /// Closing the window during a local game asks first, offering to save
+    /// the game to the recovery file.
+    confirm_quit: bool,
+    /// Suggest offering a draw once this many half-moves pass without a
+    /// capture or pawn move.
+    draw_hint_halfmoves: usize,
+    /// Speak WebSocket on top of TCP when hosting or joining, for browser
+    /// clients.
+    websocket: bool,
+    /// Off, pieces and captures snap to their new squares at once.
+    animations: bool,
+    /// How long a piece takes to slide to its new square.
+    animation_duration: Duration,
+    animation_easing: Easing,
+    overlays: Overlays,
+    /// Our name, as shown to the opponent.
+    name: Option<String>,
+    /// Toggled in game with C.
+    coordinates: CoordinateStyle,
+    /// Label every square in its corner instead of labelling the edges.
+    coordinates_in_squares: bool,
+    /// Toggled in game with T.
+    theme: Theme,
+    palette: Palette,
+    /// Print every move in words to stdout.
+    announce_moves: bool,
+    /// Preview the legal moves of the piece under the cursor.
+    hover_moves: bool,
+    /// With a piece selected, a click anywhere but one of its targets drops
+    /// the selection instead of selecting the clicked square.
+    deselect_on_miss: bool,
+    /// Holding a piece down and letting go on the same square only shows its
+    /// moves, for touch screens where a tap is the way to select.
+    long_press_preview: bool,
+    /// In games without a network opponent, allow changing a promotion for
+    /// a moment after it happens.
+    promotion_undo: bool,
+    confirm_moves: ConfirmMode,
+    input_scheme: InputScheme,
+    /// In local games, turn the board to the winner's side once the game is
+    /// won.
+    flip_to_winner: bool,
+    /// Play a random game in the terminal and exit, see `self_test`.
+    self_test: bool,
+    /// Write each finished game to this replay file.
+    record_file: Option<String>,
+    /// Fade captured pieces out while the capturing piece moves in.
+    capture_fade: bool,
+    /// Where the addresses last hosted and connected on are kept.
+    last_address_file: String,
+    /// Where unfinished local games are saved and restored from.
+    recovery_file: String,
+    /// Show the name of the hovered square next to the cursor.
+    square_tooltips: bool,
+    /// Show a scaled down copy of the board.
+    minimap: bool,
+    /// Start a new game this long after one ends, without waiting for
+    /// Space.
+    auto_reset: Option<Duration>,
+    log_level: logging::Level,
+    log_file: Option<String>,
+    network: NetworkSettings,
+    /// Played for every move without a sound of its own in `piece_sounds`.
+    move_sound: Option<String>,
+    /// Indexed by `PieceType as usize`.
+    piece_sounds: [Option<String>; 6],
+    /// Stay quiet for moves made by a local player.
+    opponent_sounds_only: bool,
+    /// What pawns moved on this board promote to, and what our moves tell
+    /// the opponent to promote to.
+    promotion: PromotionPiece,
+    /// Multisampling for smoother mesh edges.
+    samples: NumSamples,
+    vsync: bool,
+    /// Sleep between frames so there are no more than this many a second.
+    max_fps: Option<u32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            light_square_image: None,
+            dark_square_image: None,
+            light_square_color: None,
+            dark_square_color: None,
+            autosave_moves: 5,
+            autosave_interval: Duration::from_secs(30),
+            confirm_quit: false,
+            draw_hint_halfmoves: 40,
+            websocket: false,
+            animations: true,
+            animation_duration: Duration::from_millis(150),
+            animation_easing: Easing::EaseInOut,
+            overlays: Overlays::default(),
+            name: None,
+            coordinates: CoordinateStyle::Algebraic,
+            coordinates_in_squares: false,
+            theme: Theme::Classic,
+            palette: Palette::Standard,
+            announce_moves: false,
+            hover_moves: false,
+            deselect_on_miss: false,
+            promotion_undo: false,
+            long_press_preview: false,
+            confirm_moves: ConfirmMode::Never,
+            input_scheme: InputScheme::Both,
+            flip_to_winner: false,
+            self_test: false,
+            record_file: None,
+            capture_fade: false,
+            last_address_file: LAST_ADDRESS_FILE.to_owned(),
+            recovery_file: RECOVERY_FILE.to_owned(),
+            square_tooltips: false,
+            minimap: false,
+            auto_reset: None,
+            log_level: logging::Level::Warn,
+            log_file: None,
+            network: NetworkSettings::default(),
+            move_sound: None,
+            piece_sounds: Default::default(),
+            opponent_sounds_only: false,
+            promotion: PromotionPiece::Queen,
+            samples: NumSamples::One,
+            vsync: true,
+            max_fps: None,
         }
-        let pos = ctx.mouse.position();
-        let (x, y) = (pos.x, pos.y);
-        let (sc_width, sc_height) = ctx.gfx.size();
-        let reverse = self.should_reverse();
-        let board_coords = get_board_coordinate(x, y, sc_width, sc_height);
-        let mut clicked = if let Some(coords) = board_coords {
-            coords
+    }
+}
+
+/// What a board square is filled with: a flat color, or an image scaled to
+/// the square.
+enum SquareFill {
+    Solid(Color),
+    Pattern {
+        width: usize,
+        height: usize,
+        pixels: Vec<u8>,
+    },
+}
+
+impl SquareFill {
+    fn load(ctx: &mut Context, path: Option<&str>, fallback: Color) -> GameResult<Self> {
+        let path = if let Some(path) = path {
+            path
         } else {
-            return Ok(());
+            return Ok(Self::Solid(fallback));
         };
-        if reverse {
-            clicked.1 = 7 - clicked.1;
-        }
-        if let Some(current) = self.selected_square {
-            if current == clicked {
-                self.selected_square = None;
-                return Ok(());
-            }
-            let mv = {
-                let moves = self.get_moves();
-                if moves.is_none() {
-                    return Ok(());
-                }
-                let moves = moves.unwrap();
-                moves
-                    .iter()
-                    .find(|mv| (mv.to.x as u8, mv.to.y as u8) == clicked)
-            };
-            if let Some(mv) = mv {
-                let mv = mv.clone();
-                self.phase = Phase::Validate(MoveKind::Builtin(mv));
-            } else {
-                self.selected_square = Some(clicked);
-            }
-        } else {
-            self.selected_square = Some(clicked);
-        }
-        Ok(())
+        let bytes = std::fs::read(path)
+            .map_err(|err| GameError::ResourceLoadError(format!("{}: {}", path, err)))?;
+        let image = Image::from_bytes(ctx, &bytes)?;
+        Ok(Self::Pattern {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            pixels: image.to_pixels(ctx)?,
+        })
     }
 
-    fn network_move(&mut self) -> GameResult<()> {
-        if let Some(network) = &mut self.player_handler.network {
-            if let Some(packet) = network.get_packet() {
-                match packet {
-                    PacketType::Move(mv) => {
-                        self.phase = Phase::Validate(MoveKind::Network(mv));
-                    }
-                    _ => {}
-                }
+    /// RGBA of the pixel at `(u, v)` inside a square that is `size` pixels wide.
+    fn pixel(&self, u: usize, v: usize, size: usize) -> [u8; 4] {
+        match self {
+            Self::Solid(color) => {
+                let (r, g, b, a) = color.to_rgba();
+                [r, g, b, a]
+            }
+            Self::Pattern {
+                width,
+                height,
+                pixels,
+            } => {
+                let px = u * width / size;
+                let py = v * height / size;
+                let idx = (py * width + px) * 4;
+                [
+                    pixels[idx],
+                    pixels[idx + 1],
+                    pixels[idx + 2],
+                    pixels[idx + 3],
+                ]
             }
         }
-        Ok(())
     }
+}
 
-    fn client_validate(&mut self, mv: MoveKind) -> GameResult<()> {
-        let current_turn = self.board.turn;
-        let result = self.board.move_piece(mv.from(), mv.to());
-        match result {
-            ValidationResult::Valid(mut status) => {
-                if self.board.status == Status::AwaitingPromotion {
-                    status = self.board.promote_piece(mv.promotion()).unwrap();
-                }
-                let end_state = match status {
-                    Status::Checkmate(_) => Some(GameState::CheckMate),
-                    Status::Draw(_) => Some(GameState::Draw),
-                    _ => None,
-                };
-                if end_state.is_some() {
-                    self.phase = Phase::End(status);
-                } else {
-                    self.phase = Phase::Move;
-                }
-                self.selected_square = None;
-                self.current_moves = None;
-                let one_local = self.player_handler.one_local();
-                if let Some(network) = &mut self.player_handler.network {
-                    if one_local == Some(current_turn) {
-                        let packet = PacketType::Move(chess_networking::Move {
-                            from: (mv.from().x as u8, mv.from().y as u8),
-                            to: (mv.to().x as u8, mv.to().y as u8),
-                            promotion: Some(chess_networking::PromotionPiece::Queen),
-                            forfeit: false,
-                            offer_draw: false,
-                        });
-                        network.send_packet(packet);
-                    } else {
-                        let ack = Ack {
-                            ok: true,
-                            end_state,
-                        };
-                        let packet = PacketType::Ack(ack);
-                        network.send_packet(packet);
-                    }
-                }
-            }
-            _ => {
-                if let Some(network) = &mut self.player_handler.network {
-                    let ack = Ack {
-                        ok: false,
-                        end_state: None,
-                    };
-                    let packet = PacketType::Ack(ack);
-                    network.send_packet(packet);
+/// Raw RGBA pixels of the whole board, light square in the top left.
+fn board_pixels(light: &SquareFill, dark: &SquareFill) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity(WIDTH as usize * HEIGHT as usize * 4);
+    let sq_size = SQUARE_SIZE as usize;
+    for y in 0..BOARD_SQUARES {
+        for v in 0..sq_size {
+            for x in 0..BOARD_SQUARES {
+                let fill = if (x + y) % 2 == 0 { light } else { dark };
+                for u in 0..sq_size {
+                    pixels.extend_from_slice(&fill.pixel(u, v, sq_size));
                 }
-                self.phase = Phase::Move;
-                self.selected_square = None;
             }
         }
-        Ok(())
     }
+    pixels
+}
 
-    fn should_reverse(&self) -> bool {
-        (self.board.turn == ChessColor::White && self.player_handler.both_local())
-            || self
-                .player_handler
-                .one_local()
-                .is_some_and(|color| color == ChessColor::White)
+/// The light and dark square colors for the configured theme.
+fn square_colors(config: &Config) -> (Color, Color) {
+    let theme = config.theme;
+    match theme {
+        Theme::Classic => (
+            config.light_square_color.unwrap_or(theme.light_square()),
+            config.dark_square_color.unwrap_or(theme.dark_square()),
+        ),
+        _ => (theme.light_square(), theme.dark_square()),
     }
 }
 
-impl event::EventHandler<ggez::GameError> for MainState {
-    fn update(&mut self, ctx: &mut Context) -> GameResult {
-        match &self.phase {
-            Phase::Move => {
-                if self.player_handler.both_local() {
-                    self.client_move(ctx)?;
-                } else if self.player_handler.one_local() == Some(self.board.turn) {
-                    self.client_move(ctx)?;
-                } else {
-                    self.network_move()?;
-                }
-            }
-            Phase::Validate(mv) => {
-                self.client_validate(mv.clone())?;
-            }
-            Phase::End(status) => {
-                match status {
-                    Status::Checkmate(_) => {
-                        let text = if self.board.turn == ChessColor::White {
-                            "Black wins"
-                        } else {
-                            "White wins"
-                        };
-                        let text = Text::new(
-                            TextFragment::new(text)
-                                .color(Color::from_rgb(255, 0, 0))
-                                .scale(64.),
-                        );
-                        self.text_prompt = Some(text);
-                    }
-                    Status::Draw(draw_type) => {
-                        let text = match draw_type {
-                            chess::DrawType::Stalemate => "Stalemate",
-                            chess::DrawType::ThreefoldRepetition => "Threefold Repetition",
-                            chess::DrawType::FiftyMoveRule => "Fifty Move Rule",
-                        };
-                        let text = Text::new(
-                            TextFragment::new(text)
-                                .color(Color::from_rgb(255, 0, 0))
-                                .scale(64.),
-                        );
-                        self.text_prompt = Some(text);
-                    }
-                    _ => {}
-                }
-                if ctx.keyboard.is_key_just_pressed(KeyCode::Space) {
-                    self.board = Chess::new();
-                    self.current_moves = None;
-                    self.text_prompt = None;
-                    self.phase = Phase::Move;
-                    if let Some(network) = &mut self.player_handler.network {
-                        network.init();
-                    }
-                }
-            }
-        }
-
-        if self.current_moves.is_none() {
-            self.current_moves = Some(self.board.generate_valid_moves());
-        }
-        Ok(())
-    }
+/// The board texture, move dot and turn border for the configured theme.
+/// Square images and colors only replace the classic theme's colors.
+fn themed_graphics(ctx: &mut Context, config: &Config) -> GameResult<(Image, Mesh, Mesh)> {
+    let theme = config.theme;
+    let (light_image, dark_image) = match theme {
+        Theme::Classic => (
+            config.light_square_image.as_deref(),
+            config.dark_square_image.as_deref(),
+        ),
+        _ => (None, None),
+    };
+    let (light_color, dark_color) = square_colors(config);
+    let light = SquareFill::load(ctx, light_image, light_color)?;
+    let dark = SquareFill::load(ctx, dark_image, dark_color)?;
+    let pixels = board_pixels(&light, &dark);
+    let board_texture = Image::from_pixels(
+        ctx,
+        &pixels,
+        ImageFormat::Rgba8Unorm,
+        WIDTH as u32,
+        HEIGHT as u32,
+    );
 
-    fn draw(&mut self, ctx: &mut Context) -> GameResult {
-        let mut canvas =
-            graphics::Canvas::from_frame(ctx, graphics::Color::from([0.1, 0.2, 0.3, 1.0]));
-        let reverse = self.should_reverse();
-        let offset = if reverse { 800. } else { 0. };
-        let scale = Vec2::new(1.0, if reverse { -1.0 } else { 1.0 });
-        let dest = Vec2::new(0., offset);
-        let draw_params = DrawParam::new().scale(scale).dest(dest);
-        canvas.draw(&self.board_texture, draw_params);
+    let (dot_color, dot_radius) = theme.move_dot();
+    let move_to_dot = Mesh::new_circle(
+        ctx,
+        graphics::DrawMode::fill(),
+        Vec2::new(0., 0.),
+        dot_radius,
+        2.,
+        dot_color,
+    )?;
 
-        self.draw_pieces(&mut canvas)?;
-        self.draw_selected(&mut canvas)?;
-        self.draw_prompt(ctx, &mut canvas)?;
+    let border_width = theme.border_width();
+    let border = Mesh::new_rectangle(
+        ctx,
+        graphics::DrawMode::stroke(border_width),
+        graphics::Rect::new(
+            border_width / 2.,
+            border_width / 2.,
+            WIDTH - border_width,
+            HEIGHT - border_width,
+        ),
+        Color::WHITE,
+    )?;
+    Ok((board_texture, move_to_dot, border))
+}
 
-        canvas.finish(ctx)?;
+fn square_name(pos: Position) -> String {
+    format!("{}{}", (b'a' + pos.x as u8) as char, pos.y + 1)
+}
 
-        Ok(())
+fn parse_square(name: &str) -> Option<Position> {
+    let bytes = name.as_bytes();
+    if bytes.len() != 2 || !(b'a'..=b'h').contains(&bytes[0]) || !(b'1'..=b'8').contains(&bytes[1])
+    {
+        return None;
     }
+    Some(Position {
+        x: (bytes[0] - b'a') as usize,
+        y: (bytes[1] - b'1') as usize,
+    })
 }
 
-pub fn main() -> GameResult {
-    let cli_flags = std::env::args().collect::<Vec<_>>();
-    let game_type = if cli_flags.len() == 1 {
-        GameType::Local
-    } else if cli_flags.len() == 2 {
-        if cli_flags[1] == "--host" {
-            GameType::Host("localhost:3000".to_owned())
-        } else if cli_flags[1] == "--client" {
-            GameType::Client("localhost:3000".to_owned())
-        } else {
-            panic!("Invalid flag");
-        }
-    } else if cli_flags.len() == 3 {
-        if cli_flags[1] == "--host" {
-            GameType::Host(cli_flags[2].to_owned())
-        } else if cli_flags[1] == "--client" {
-            GameType::Client(cli_flags[2].to_owned())
-        } else {
-            panic!("Invalid flag");
-        }
-    } else {
-        panic!("Invalid flag");
-    };
+/// The squares of a move plus the promotion choice, which is all that is
+/// needed to replay it.
+#[derive(Clone, Copy)]
+struct MoveSpec {
+    from: Position,
+    to: Position,
+    promotion: Option<PieceType>,
+}
 
-    let title = match game_type {
-        GameType::Local => "Chess",
-        GameType::Host(_) => "Chess Host",
-        GameType::Client(_) => "Chess Client",
+impl MoveSpec {
+    /// Long algebraic notation, e.g. `e2e4` or `e7e8q`.
+    fn to_uci(self) -> String {
+        let promotion = match self.promotion {
+            Some(PieceType::Queen) => "q",
+            Some(PieceType::Rook) => "r",
+            Some(PieceType::Bishop) => "b",
+            Some(PieceType::Knight) => "n",
+            _ => "",
+        };
+        format!(
+            "{}{}{}",
+            square_name(self.from),
+            square_name(self.to),
+            promotion
+        )
+    }
+
+    fn from_uci(text: &str) -> Option<Self> {
+        if !text.is_ascii() || !(4..=5).contains(&text.len()) {
+            return None;
+        }
+        let promotion = match &text[4..] {
+            "" => None,
+            "q" => Some(PieceType::Queen),
+            "r" => Some(PieceType::Rook),
+            "b" => Some(PieceType::Bishop),
+            "n" => Some(PieceType::Knight),
+            _ => return None,
+        };
+        Some(Self {
+            from: parse_square(&text[0..2])?,
+            to: parse_square(&text[2..4])?,
+            promotion,
+        })
+    }
+}
+
+fn piece_at(board: &Chess, pos: Position) -> Option<(PieceType, ChessColor)> {
+    board
+        .board
+        .iter()
+        .flatten()
+        .find(|piece| piece.position.x == pos.x && piece.position.y == pos.y)
+        .map(|piece| (piece.piece_type, piece.color))
+}
+
+/// What moving `from -> to` would capture, counting en passant.
+fn captured_piece(board: &Chess, from: Position, to: Position) -> Option<PieceType> {
+    if let Some((piece_type, _)) = piece_at(board, to) {
+        return Some(piece_type);
+    }
+    match piece_at(board, from) {
+        Some((PieceType::Pawn, _)) if from.x != to.x => Some(PieceType::Pawn),
+        _ => None,
+    }
+}
+
+/// A move that has been applied to the board.
+#[derive(Clone, Copy)]
+struct PlayedMove {
+    from: Position,
+    to: Position,
+    promotion: Option<PieceType>,
+    piece: PieceType,
+    captured: Option<PieceType>,
+}
+
+impl PlayedMove {
+    /// Describes `spec` as played on `board`, which must still be in the
+    /// position before the move.
+    fn new(board: &Chess, spec: MoveSpec) -> Option<Self> {
+        let (piece, _) = piece_at(board, spec.from)?;
+        Some(Self {
+            from: spec.from,
+            to: spec.to,
+            promotion: spec.promotion,
+            piece,
+            captured: captured_piece(board, spec.from, spec.to),
+        })
+    }
+
+    fn spec(&self) -> MoveSpec {
+        MoveSpec {
+            from: self.from,
+            to: self.to,
+            promotion: self.promotion,
+        }
+    }
+
+    /// The move in words, for screen readers: "White pawn e2 to e4",
+    /// "Black knight takes pawn on e4".
+    fn announcement(&self, color: ChessColor) -> String {
+        let side = if color == ChessColor::White {
+            "White"
+        } else {
+            "Black"
+        };
+        let mut text = format!("{} {} ", side, piece_name(self.piece));
+        if let Some(captured) = self.captured {
+            text += &format!("takes {} on {}", piece_name(captured), square_name(self.to));
+        } else {
+            text += &format!("{} to {}", square_name(self.from), square_name(self.to));
+        }
+        if let Some(promotion) = self.promotion {
+            text += &format!(", promotes to {}", piece_name(promotion));
+        }
+        text
+    }
+}
+
+/// The usual teaching value of a piece; the king has none.
+fn piece_value(piece: PieceType) -> Option<u32> {
+    match piece {
+        PieceType::King => None,
+        PieceType::Queen => Some(9),
+        PieceType::Rook => Some(5),
+        PieceType::Bishop | PieceType::Knight => Some(3),
+        PieceType::Pawn => Some(1),
+    }
+}
+
+fn parse_piece_name(name: &str) -> Option<PieceType> {
+    match name {
+        "king" => Some(PieceType::King),
+        "queen" => Some(PieceType::Queen),
+        "rook" => Some(PieceType::Rook),
+        "bishop" => Some(PieceType::Bishop),
+        "knight" => Some(PieceType::Knight),
+        "pawn" => Some(PieceType::Pawn),
+        _ => None,
+    }
+}
+
+fn piece_name(piece: PieceType) -> &'static str {
+    match piece {
+        PieceType::King => "king",
+        PieceType::Queen => "queen",
+        PieceType::Rook => "rook",
+        PieceType::Bishop => "bishop",
+        PieceType::Knight => "knight",
+        PieceType::Pawn => "pawn",
+    }
+}
+
+/// Identifies the position reached by `history`. Every game starts from the
+/// same position, so the moves played pin it down completely.
+fn position_key(history: &[PlayedMove]) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    for mv in history {
+        (mv.from.x, mv.from.y, mv.to.x, mv.to.y).hash(&mut hasher);
+        mv.promotion.map(|piece| piece as usize).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Half-moves since the last capture or pawn move.
+fn halfmove_clock(history: &[PlayedMove]) -> usize {
+    history
+        .iter()
+        .rev()
+        .take_while(|mv| mv.captured.is_none() && !matches!(mv.piece, PieceType::Pawn))
+        .count()
+}
+
+/// The full-move number after `plies` half-moves from the starting position,
+/// counted the way PGN does: it goes up after each black move.
+fn fullmove_number(plies: usize) -> usize {
+    plies / 2 + 1
+}
+
+/// The square a pawn can capture onto en passant, if the last move was a
+/// pawn's double step.
+fn en_passant_target(history: &[PlayedMove]) -> Option<Position> {
+    let last = history.last()?;
+    if matches!(last.piece, PieceType::Pawn) && last.from.y.abs_diff(last.to.y) == 2 {
+        Some(Position {
+            x: last.from.x,
+            y: (last.from.y + last.to.y) / 2,
+        })
+    } else {
+        None
+    }
+}
+
+/// How many times the position at the end of `history` has occurred,
+/// counting positions as equal when their FEN matches up to the clocks.
+fn repetitions(history: &[PlayedMove]) -> usize {
+    let key = |board: &Chess, plies: &[PlayedMove]| {
+        let fen = fen::position_fen(board, plies);
+        fen.split(' ').take(4).collect::<Vec<_>>().join(" ")
     };
+    let mut board = Chess::new();
+    let mut keys = vec![key(&board, &[])];
+    for (i, mv) in history.iter().enumerate() {
+        if !matches!(board.move_piece(mv.from, mv.to), ValidationResult::Valid(_)) {
+            return 1;
+        }
+        if board.status == Status::AwaitingPromotion {
+            board.promote_piece(mv.promotion.unwrap_or(PieceType::Queen));
+        }
+        keys.push(key(&board, &history[..=i]));
+    }
+    let last = keys.last().unwrap();
+    keys.iter().filter(|&k| k == last).count()
+}
 
-    let cb = ggez::ContextBuilder::new("Chess GUI", "Dexter WS")
-        .window_mode(
-            WindowMode::default()
-                .dimensions(WIDTH, HEIGHT)
-                .max_dimensions(WIDTH, HEIGHT)
-                .resizable(false),
-        )
-        .window_setup(ggez::conf::WindowSetup::default().title(title));
-    let (mut ctx, event_loop) = cb.build()?;
+fn save_moves(path: &str, moves: &[PlayedMove]) -> std::io::Result<()> {
+    let text: String = moves.iter().map(|mv| mv.spec().to_uci() + "\n").collect();
+    std::fs::write(path, text)
+}
 
-    let state = MainState::new(&mut ctx, game_type)?;
-    event::run(ctx, event_loop, state)
+fn load_moves(path: &str) -> Option<Vec<MoveSpec>> {
+    read_moves(path).ok()
+}
+
+/// Like `load_moves`, but says why the file couldn't be read.
+fn read_moves(path: &str) -> Result<Vec<MoveSpec>, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            MoveSpec::from_uci(line)
+                .ok_or_else(|| format!("line {}: '{}' is not a UCI move", i + 1, line))
+        })
+        .collect()
+}
+
+/// Plays `moves` from the starting position, or `None` if any of them is
+/// illegal.
+fn replay_moves(moves: &[MoveSpec]) -> Option<(Chess, Vec<PlayedMove>)> {
+    let mut board = Chess::new();
+    let mut history = Vec::with_capacity(moves.len());
+    for &spec in moves {
+        let mut played = PlayedMove::new(&board, spec)?;
+        if !matches!(
+            board.move_piece(spec.from, spec.to),
+            ValidationResult::Valid(_)
+        ) {
+            return None;
+        }
+        if board.status == Status::AwaitingPromotion {
+            let piece = spec.promotion.unwrap_or(PieceType::Queen);
+            board.promote_piece(piece)?;
+            played.promotion = Some(piece);
+        }
+        history.push(played);
+    }
+    Some((board, history))
+}
+
+/// Plays a random game to the end without opening a window, for checking
+/// that move generation, moving and end detection hold together. Returns
+/// the exit code.
+fn self_test() -> i32 {
+    let mut board = Chess::new();
+    let mut history = Vec::new();
+    let outcome = loop {
+        if history.len() == SELF_TEST_MAX_PLIES {
+            logging::error(format!("No result after {} plies", SELF_TEST_MAX_PLIES));
+            return 1;
+        }
+        let moves: Vec<Move> = board.generate_valid_moves().into_iter().flatten().collect();
+        if moves.is_empty() {
+            break GameOutcome::from_position(&mut board).expect("No moves but not over");
+        }
+        let seed = std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish();
+        let mv = &moves[seed as usize % moves.len()];
+        let spec = MoveSpec {
+            from: mv.from,
+            to: mv.to,
+            promotion: None,
+        };
+        let mut played =
+            PlayedMove::new(&board, spec).expect("Generated a move from an empty square");
+        let mut status = match board.move_piece(mv.from, mv.to) {
+            ValidationResult::Valid(status) => status,
+            _ => {
+                logging::error(format!(
+                    "Generated move {} -> {} was refused",
+                    square_name(mv.from),
+                    square_name(mv.to)
+                ));
+                return 1;
+            }
+        };
+        if board.status == Status::AwaitingPromotion {
+            status = board
+                .promote_piece(PieceType::Queen)
+                .expect("Promotion failed");
+            played.promotion = Some(PieceType::Queen);
+        }
+        history.push(played);
+        if let Some(outcome) = GameOutcome::from_status(&status, &mut board) {
+            break outcome;
+        }
+    };
+    match pgn::san_moves(&history) {
+        Some(san) => println!("{}", pgn::numbered_moves(&san)),
+        None => {
+            logging::error("The game played doesn't replay");
+            return 1;
+        }
+    }
+    println!("{} after {} plies", outcome.message(), history.len());
+    0
+}
+
+#[derive(Debug, Clone)]
+enum GameType {
+    Local,
+    Host(String),
+    Client(String),
+    /// Packets go over stdin/stdout, for running behind SSH or a wrapper
+    /// process. One end still has to play the host's part of the handshake.
+    Stdio {
+        host: bool,
+    },
+    /// A local game against the moves of a saved game, played for the side
+    /// `user` doesn't play for as long as the user follows them.
+    Practice {
+        moves_file: String,
+        user: ChessColor,
+    },
+    /// Watching a replay file play back. Nobody moves.
+    Replay(String),
+}
+
+impl GameType {
+    /// How the game is played, as written to replay files.
+    fn description(&self) -> String {
+        match self {
+            Self::Local => "local".to_owned(),
+            Self::Host(address) => format!("host {}", address),
+            Self::Client(address) => format!("client {}", address),
+            Self::Stdio { host: true } => "stdio host".to_owned(),
+            Self::Stdio { host: false } => "stdio client".to_owned(),
+            Self::Practice { moves_file, .. } => format!("practice {}", moves_file),
+            Self::Replay(path) => format!("replay {}", path),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Player {
+    color: ChessColor,
+    name: Option<String>,
+    local: bool,
+}
+
+struct Players {
+    black: Player,
+    white: Player,
+}
+
+impl Players {
+    fn get_player(&self, color: ChessColor) -> &Player {
+        if color == ChessColor::White {
+            &self.white
+        } else {
+            &self.black
+        }
+    }
+
+    fn get_player_mut(&mut self, color: ChessColor) -> &mut Player {
+        if color == ChessColor::White {
+            &mut self.white
+        } else {
+            &mut self.black
+        }
+    }
+
+    /// The side played here when exactly one of them is.
+    fn sole_local(&self) -> Option<ChessColor> {
+        match (self.white.local, self.black.local) {
+            (true, false) => Some(ChessColor::White),
+            (false, true) => Some(ChessColor::Black),
+            _ => None,
+        }
+    }
+}
+
+/// A byte stream that packets can be sent over. The reader thread gets its
+/// own handle to it through `try_clone`.
+trait Transport: Read + Write + Send {
+    fn try_clone(&self) -> std::io::Result<Box<dyn Transport>>;
+    /// Ends the connection so the other side sees it close right away.
+    fn shutdown(&mut self);
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> std::io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn shutdown(&mut self) {
+        let _ = TcpStream::shutdown(self, Shutdown::Both);
+    }
+}
+
+/// A handle on the opponent's connection, closed if we panic so they see
+/// the game drop right away.
+static OPEN_CONNECTION: Mutex<Option<Box<dyn Transport>>> = Mutex::new(None);
+
+fn register_connection(transport: &dyn Transport) {
+    *OPEN_CONNECTION.lock().unwrap() = transport.try_clone().ok();
+}
+
+/// Shuts down the registered connection. Returns whether there was one.
+fn close_open_connection() -> bool {
+    let open = OPEN_CONNECTION.lock().ok().and_then(|mut open| open.take());
+    if let Some(mut transport) = open {
+        transport.shutdown();
+        true
+    } else {
+        false
+    }
+}
+
+/// Logs a panic during the game and runs `cleanup` before the default hook
+/// reports it.
+fn install_panic_hook(cleanup: impl Fn() + Send + Sync + 'static) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        logging::error(format!("The game crashed: {}", info));
+        cleanup();
+        default_hook(info);
+    }));
+}
+
+/// The game's panic cleanup: the connection is closed here, and move
+/// sounds stop with their sources once the panic unwinds out of the event
+/// loop and drops the game.
+fn close_on_panic() {
+    if close_open_connection() {
+        logging::error("Closed the connection to the opponent");
+    }
+}
+
+/// Reads from stdin and writes to stdout, or whatever pipes stand in for
+/// them.
+#[derive(Clone)]
+struct StdioTransport {
+    input: Arc<Mutex<dyn Read + Send>>,
+    output: Arc<Mutex<dyn Write + Send>>,
+}
+
+impl StdioTransport {
+    fn new() -> Self {
+        Self {
+            input: Arc::new(Mutex::new(std::io::stdin())),
+            output: Arc::new(Mutex::new(std::io::stdout())),
+        }
+    }
+}
+
+impl Read for StdioTransport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.input.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for StdioTransport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.lock().unwrap().flush()
+    }
+}
+
+impl Transport for StdioTransport {
+    fn try_clone(&self) -> std::io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    /// The pipes can't be closed from here, so this only makes sure what
+    /// was written gets out before they close with the process.
+    fn shutdown(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Which side of the handshake we play.
+enum NetworkType {
+    Host {
+        /// Only set when we're hosting over TCP.
+        listener: Option<TcpListener>,
+    },
+    Client,
+}
+
+/// Packets of our own on top of the shared protocol. They encode as a
+/// tagged msgpack value, which never decodes as one of the shared packets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Control {
+    /// Take back the move just sent, as long as the opponent hasn't replied
+    /// to it yet.
+    Retract,
+    RetractReply {
+        accepted: bool,
+    },
+    Chat {
+        text: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum PacketType {
+    Start(Start),
+    Move(chess_networking::Move),
+    Ack(Ack),
+    Control(Control),
+}
+
+impl TryFrom<&[u8]> for PacketType {
+    type Error = ();
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        if let Ok(start) = Start::try_from(data) {
+            return Ok(Self::Start(start));
+        }
+        if let Ok(mv) = chess_networking::Move::try_from(data) {
+            return Ok(Self::Move(mv));
+        }
+        if let Ok(ack) = Ack::try_from(data) {
+            return Ok(Self::Ack(ack));
+        }
+        if let Ok(control) = rmp_serde::from_slice(data) {
+            return Ok(Self::Control(control));
+        }
+        Err(())
+    }
+}
+
+impl TryFrom<PacketType> for Vec<u8> {
+    type Error = rmp_serde::encode::Error;
+    fn try_from(packet: PacketType) -> Result<Self, Self::Error> {
+        match packet {
+            PacketType::Start(start) => Vec::try_from(start),
+            PacketType::Move(mv) => Vec::try_from(mv),
+            PacketType::Ack(ack) => Vec::try_from(ack),
+            PacketType::Control(control) => rmp_serde::to_vec_named(&control),
+        }
+    }
+}
+
+/// Games always start from the initial position, so a position the
+/// opponent asks for is checked and then ignored.
+fn check_start_fen(fen: Option<&str>) {
+    match fen.map(fen::validate_fen) {
+        None => {}
+        Some(Err(err)) => logging::error(format!("Opponent sent an invalid FEN: {}", err)),
+        Some(Ok(())) if fen == Some(fen::STARTING_POSITION) => {}
+        Some(Ok(())) => {
+            logging::warn("Opponent asked for a custom position, starting from the initial one")
+        }
+    }
+}
+
+/// Splits what the reader thread receives into packets. Several packets
+/// can come in one read and one packet over several, so whatever follows
+/// the last complete packet is kept for the next read.
+struct PacketReader {
+    settings: NetworkSettings,
+    pending: Vec<u8>,
+    chat_window: Instant,
+    chat_count: u32,
+}
+
+impl PacketReader {
+    fn new(settings: NetworkSettings) -> Self {
+        Self {
+            settings,
+            pending: Vec::new(),
+            chat_window: Instant::now(),
+            chat_count: 0,
+        }
+    }
+
+    /// Queues every packet `data` completes. An error means the opponent
+    /// broke a limit and the connection should be dropped.
+    fn receive(&mut self, data: &[u8], cache: &mut VecDeque<PacketType>) -> Result<(), String> {
+        self.pending.extend_from_slice(data);
+        loop {
+            let len = match msgpack::value_len(&self.pending, self.settings.max_packet) {
+                Ok(Some(len)) => len,
+                Ok(None) => return Ok(()),
+                Err(msgpack::FrameError::TooLarge) => {
+                    return Err(format!(
+                        "Opponent sent a packet over {} bytes",
+                        self.settings.max_packet
+                    ))
+                }
+                Err(msgpack::FrameError::InvalidMarker) => {
+                    return Err("Opponent sent something that isn't msgpack".to_owned())
+                }
+            };
+            let packet = PacketType::try_from(&self.pending[..len]);
+            self.pending.drain(..len);
+            match packet {
+                Ok(packet) => self.queue(packet, cache)?,
+                Err(()) => logging::warn("Ignoring a packet that isn't part of the protocol"),
+            }
+        }
+    }
+
+    /// Adds `packet` to `cache`, keeping it under `max_queued`. Only chat
+    /// is ever dropped; a game packet that can't be queued is an error.
+    fn queue(
+        &mut self,
+        packet: PacketType,
+        cache: &mut VecDeque<PacketType>,
+    ) -> Result<(), String> {
+        let is_chat =
+            |packet: &PacketType| matches!(packet, PacketType::Control(Control::Chat { .. }));
+        if is_chat(&packet) {
+            if self.chat_window.elapsed() >= Duration::from_secs(1) {
+                self.chat_window = Instant::now();
+                self.chat_count = 0;
+            }
+            self.chat_count += 1;
+            if self.chat_count > self.settings.chat_per_second {
+                logging::warn("Opponent is chatting too fast, dropping a message");
+                return Ok(());
+            }
+            if cache.len() >= self.settings.max_queued {
+                logging::warn("Dropping a chat message, the packet queue is full");
+                return Ok(());
+            }
+        } else if cache.len() >= self.settings.max_queued {
+            let oldest_chat = cache.iter().position(is_chat).ok_or_else(|| {
+                format!(
+                    "Opponent sent over {} packets we haven't handled yet",
+                    self.settings.max_queued
+                )
+            })?;
+            cache.remove(oldest_chat);
+            logging::warn("Dropping a chat message to make room in the packet queue");
+        }
+        cache.push_back(packet);
+        Ok(())
+    }
+}
+
+fn write_packet(transport: &mut dyn Transport, data: &[u8]) {
+    let result = transport.write_all(data).and_then(|_| transport.flush());
+    if let Err(err) = result {
+        logging::error(format!("Failed to send packet: {}", err));
+    }
+}
+
+/// A packet's bytes and when they are due to be sent.
+type DelayedPacket = (Instant, Vec<u8>);
+
+struct Network {
+    ty: NetworkType,
+    transport: Box<dyn Transport>,
+    cache: Arc<RwLock<VecDeque<PacketType>>>,
+    /// Set by the reader thread once the connection closes or the opponent
+    /// breaks the limits.
+    disconnected: Arc<AtomicBool>,
+    /// With simulated latency, packets go to a thread that sends each one
+    /// once it is due.
+    delayed: Option<(Duration, mpsc::Sender<DelayedPacket>)>,
+    thread_handle: std::thread::JoinHandle<()>,
+}
+
+impl Network {
+    fn new(ty: NetworkType, transport: Box<dyn Transport>, settings: NetworkSettings) -> Self {
+        register_connection(transport.as_ref());
+        let cache = Arc::new(RwLock::new(VecDeque::new()));
+        let disconnected = Arc::new(AtomicBool::new(false));
+        let thread_handle = Self::spawn_thread(
+            transport.try_clone().unwrap(),
+            cache.clone(),
+            disconnected.clone(),
+            settings,
+        );
+        let delayed = (!settings.sim_latency.is_zero()).then(|| {
+            let sender = Self::spawn_delayed_sender(transport.try_clone().unwrap());
+            (settings.sim_latency, sender)
+        });
+        Self {
+            ty,
+            transport,
+            cache,
+            disconnected,
+            delayed,
+            thread_handle,
+        }
+    }
+
+    fn spawn_delayed_sender(mut transport: Box<dyn Transport>) -> mpsc::Sender<DelayedPacket> {
+        let (sender, receiver) = mpsc::channel::<DelayedPacket>();
+        std::thread::spawn(move || {
+            for (due, data) in receiver {
+                std::thread::sleep(due.saturating_duration_since(Instant::now()));
+                write_packet(transport.as_mut(), &data);
+            }
+        });
+        sender
+    }
+
+    fn new_host(host: &str, websocket: bool, settings: NetworkSettings) -> Self {
+        let listener = TcpListener::bind(host)
+            .unwrap_or_else(|err| exit_with_error(format!("Couldn't listen on {}: {}", host, err)));
+        logging::info(format!("Waiting for an opponent on {}", host));
+        let (stream, address) = listener.accept().unwrap_or_else(|err| {
+            exit_with_error(format!("Couldn't accept an opponent on {}: {}", host, err))
+        });
+        logging::info(format!("Opponent connected from {}", address));
+        // Anyone else connecting is turned away from `reject_extra_connections`.
+        listener
+            .set_nonblocking(true)
+            .expect("Failed to make the listener non-blocking");
+        let ty = NetworkType::Host {
+            listener: Some(listener),
+        };
+        let transport: Box<dyn Transport> = if websocket {
+            let ws = WsTransport::accept(stream, settings.max_packet).unwrap_or_else(|err| {
+                exit_with_error(format!(
+                    "WebSocket handshake with {} failed: {}",
+                    address, err
+                ))
+            });
+            Box::new(ws)
+        } else {
+            Box::new(stream)
+        };
+        Self::new(ty, transport, settings)
+    }
+
+    fn new_stdio(host: bool, settings: NetworkSettings) -> Self {
+        let ty = if host {
+            NetworkType::Host { listener: None }
+        } else {
+            NetworkType::Client
+        };
+        Self::new(ty, Box::new(StdioTransport::new()), settings)
+    }
+
+    /// Reads packets into `cache` until the connection closes or the
+    /// opponent breaks one of the limits in `settings`.
+    fn spawn_thread<R: Read + Send + 'static>(
+        stream: R,
+        cache: Arc<RwLock<VecDeque<PacketType>>>,
+        disconnected: Arc<AtomicBool>,
+        settings: NetworkSettings,
+    ) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let mut stream = stream;
+            let mut data = vec![0u8; settings.read_buffer];
+            let mut reader = PacketReader::new(settings);
+            loop {
+                let size = match stream.read(&mut data) {
+                    Ok(0) => break,
+                    Ok(size) => size,
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                };
+                let mut cache = cache.write().unwrap();
+                if let Err(err) = reader.receive(&data[..size], &mut cache) {
+                    logging::error(format!("{}, dropping the connection", err));
+                    break;
+                }
+            }
+            disconnected.store(true, Ordering::Relaxed);
+        })
+    }
+
+    fn new_client(host: &str, websocket: bool, settings: NetworkSettings) -> Self {
+        let stream = connect_with_retries(
+            host,
+            settings.connect_retries,
+            Duration::from_millis(500),
+            |host| TcpStream::connect(host),
+        )
+        .unwrap_or_else(|err| exit_with_error(format!("Failed to connect to {}: {}", host, err)));
+        logging::info(format!("Connected to {}", host));
+        let transport: Box<dyn Transport> = if websocket {
+            let ws =
+                WsTransport::connect(stream, host, settings.max_packet).unwrap_or_else(|err| {
+                    exit_with_error(format!("WebSocket handshake with {} failed: {}", host, err))
+                });
+            Box::new(ws)
+        } else {
+            Box::new(stream)
+        };
+        Self::new(NetworkType::Client, transport, settings)
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Relaxed)
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        if let Some((latency, sender)) = &self.delayed {
+            let _ = sender.send((Instant::now() + *latency, data.to_vec()));
+        } else {
+            write_packet(self.transport.as_mut(), data);
+        }
+    }
+
+    /// Exchanges `Start` packets, introducing ourselves as `name`.
+    fn init(&mut self, name: Option<String>) -> Players {
+        match self.ty {
+            NetworkType::Host { .. } => {
+                let start = if let PacketType::Start(start) = self.get_packet_blocking() {
+                    start
+                } else {
+                    exit_with_error("The opponent didn't start with a start packet");
+                };
+                check_start_fen(start.fen.as_deref());
+                let start_packet = PacketType::Start(Start {
+                    name: name.clone(),
+                    is_white: true,
+                    fen: None,
+                    time: None,
+                    inc: None,
+                });
+                self.send_packet(start_packet);
+                let main = Player {
+                    color: ChessColor::White,
+                    name,
+                    local: true,
+                };
+                let opp = Player {
+                    color: ChessColor::Black,
+                    name: start.name,
+                    local: false,
+                };
+                Players {
+                    white: main,
+                    black: opp,
+                }
+            }
+            NetworkType::Client => {
+                let start = Start {
+                    name: name.clone(),
+                    is_white: true,
+                    fen: None,
+                    time: None,
+                    inc: None,
+                };
+                let start_packet = PacketType::Start(start);
+                self.send_packet(start_packet);
+                let start_packet = self.get_packet_blocking();
+                if let PacketType::Start(start) = start_packet {
+                    check_start_fen(start.fen.as_deref());
+                    if start.is_white {
+                        let main = Player {
+                            color: ChessColor::Black,
+                            name,
+                            local: true,
+                        };
+                        let opp = Player {
+                            color: ChessColor::White,
+                            name: start.name,
+                            local: false,
+                        };
+                        return Players {
+                            white: opp,
+                            black: main,
+                        };
+                    } else {
+                        let main = Player {
+                            color: ChessColor::White,
+                            name,
+                            local: true,
+                        };
+                        let opp = Player {
+                            color: ChessColor::Black,
+                            name: start.name,
+                            local: false,
+                        };
+                        return Players {
+                            white: main,
+                            black: opp,
+                        };
+                    }
+                } else {
+                    exit_with_error("The opponent didn't start with a start packet");
+                }
+            }
+        }
+    }
+
+    fn get_packet(&mut self) -> Option<PacketType> {
+        let mut cache = self.cache.write().unwrap();
+        let packet = cache.pop_front();
+        packet
+    }
+
+    fn get_packet_blocking(&mut self) -> PacketType {
+        loop {
+            if let Some(packet) = self.get_packet() {
+                return packet;
+            }
+        }
+    }
+
+    fn send_packet(&mut self, packet: PacketType) {
+        let data: Vec<u8> = Vec::try_from(packet).unwrap();
+        self.send(&data);
+    }
+
+    /// Tells everyone waiting on our listener that the game already has its
+    /// opponent, and hangs up on them.
+    fn reject_extra_connections(&self) {
+        let listener = if let NetworkType::Host {
+            listener: Some(listener),
+        } = &self.ty
+        {
+            listener
+        } else {
+            return;
+        };
+        while let Ok((mut stream, address)) = listener.accept() {
+            logging::info(format!("Turning away {}, the game is full", address));
+            let _ = stream.write_all(GAME_FULL.as_bytes());
+        }
+    }
+
+    fn close(self) {
+        if let NetworkType::Host { listener } = self.ty {
+            drop(listener);
+        }
+        drop(self.transport);
+        self.thread_handle.join().unwrap();
+    }
+}
+
+struct PlayerHandler {
+    game_type: GameType,
+    players: Players,
+    network: Option<Network>,
+    /// The one side the user controls, decided whenever `players` is set
+    /// rather than looked up from it. `None` when the user plays both.
+    local_color: Option<ChessColor>,
+}
+
+impl PlayerHandler {
+    fn new(game_type: GameType, config: &Config) -> Self {
+        let mut network = match &game_type {
+            GameType::Host(host) => Some(Network::new_host(host, config.websocket, config.network)),
+            GameType::Client(host) => {
+                Some(Network::new_client(host, config.websocket, config.network))
+            }
+            GameType::Stdio { host } => Some(Network::new_stdio(*host, config.network)),
+            _ => None,
+        };
+        let players = match game_type {
+            GameType::Local => Players {
+                white: Player {
+                    color: ChessColor::White,
+                    name: None,
+                    local: true,
+                },
+                black: Player {
+                    color: ChessColor::Black,
+                    name: None,
+                    local: true,
+                },
+            },
+            GameType::Replay(_) => Players {
+                white: Player {
+                    color: ChessColor::White,
+                    name: None,
+                    local: false,
+                },
+                black: Player {
+                    color: ChessColor::Black,
+                    name: None,
+                    local: false,
+                },
+            },
+            GameType::Practice { user, .. } => {
+                let player = |color| Player {
+                    color,
+                    name: if color == user {
+                        config.name.clone()
+                    } else {
+                        Some("Recorded game".to_owned())
+                    },
+                    local: color == user,
+                };
+                Players {
+                    white: player(ChessColor::White),
+                    black: player(ChessColor::Black),
+                }
+            }
+            _ => {
+                let network = network.as_mut().unwrap();
+                network.init(config.name.clone())
+            }
+        };
+        Self {
+            game_type,
+            local_color: players.sole_local(),
+            players,
+            network,
+        }
+    }
+
+    fn set_players(&mut self, players: Players) {
+        self.local_color = players.sole_local();
+        self.players = players;
+    }
+
+    fn set_local(&mut self, color: ChessColor, local: bool) {
+        self.players.get_player_mut(color).local = local;
+        self.local_color = self.players.sole_local();
+    }
+
+    fn can_move(&self, color: ChessColor) -> bool {
+        if let GameType::Local = self.game_type {
+            return true;
+        }
+        match self.local_color {
+            Some(local) => local == color,
+            None => self.both_local(),
+        }
+    }
+
+    fn both_local(&self) -> bool {
+        self.players.black.local && self.players.white.local
+    }
+
+    fn one_local(&self) -> Option<ChessColor> {
+        self.local_color
+    }
+}
+
+#[derive(Clone, Debug)]
+enum MoveKind {
+    Builtin(Move),
+    Network(chess_networking::Move),
+}
+
+impl MoveKind {
+    fn to(&self) -> Position {
+        match self {
+            MoveKind::Builtin(mv) => mv.to,
+            MoveKind::Network(mv) => Position {
+                x: mv.to.0 as usize,
+                y: mv.to.1 as usize,
+            },
+        }
+    }
+
+    fn from(&self) -> Position {
+        match self {
+            MoveKind::Builtin(mv) => mv.from,
+            MoveKind::Network(mv) => Position {
+                x: mv.from.0 as usize,
+                y: mv.from.1 as usize,
+            },
+        }
+    }
+
+    /// Moves made on this board promote to `default`, network moves to
+    /// whatever the packet says.
+    fn promotion(&self, default: &PromotionPiece) -> PieceType {
+        let promotion = match self {
+            MoveKind::Builtin(_) => default,
+            MoveKind::Network(mv) => mv.promotion.as_ref().unwrap_or(&PromotionPiece::Queen),
+        };
+        match promotion {
+            PromotionPiece::Queen => PieceType::Queen,
+            PromotionPiece::Rook => PieceType::Rook,
+            PromotionPiece::Bishop => PieceType::Bishop,
+            PromotionPiece::Knight => PieceType::Knight,
+        }
+    }
+}
+
+fn opponent(color: ChessColor) -> ChessColor {
+    if color == ChessColor::White {
+        ChessColor::Black
+    } else {
+        ChessColor::White
+    }
+}
+
+/// Tint of the frame around the board, showing whose turn it is.
+fn turn_border_color(turn: ChessColor) -> Color {
+    if turn == ChessColor::White {
+        Color::from_rgba(255, 255, 255, 160)
+    } else {
+        Color::from_rgba(0, 0, 0, 160)
+    }
+}
+
+enum DrawReason {
+    Stalemate,
+    ThreefoldRepetition,
+    FiftyMoveRule,
+    /// The opponent reported a draw over the network without saying why.
+    Reported,
+    /// We accepted the opponent's draw offer.
+    Agreed,
+}
+
+/// How a finished game ended, whether we worked it out from our own board or
+/// were told by the opponent.
+enum GameOutcome {
+    Checkmate {
+        winner: ChessColor,
+    },
+    Draw(DrawReason),
+    /// The opponent sent a forfeit, so `winner` is our own side.
+    OpponentResigned {
+        winner: ChessColor,
+    },
+}
+
+impl GameOutcome {
+    /// `status` describes `board` after the last move. Falls back on looking
+    /// at the position itself, in case the status doesn't say the game is
+    /// over when the side to move is already out of moves.
+    fn from_status(status: &Status, board: &mut Chess) -> Option<Self> {
+        match status {
+            Status::Checkmate(_) => Some(Self::Checkmate {
+                winner: opponent(board.turn),
+            }),
+            Status::Draw(draw_type) => Some(Self::Draw(match draw_type {
+                chess::DrawType::Stalemate => DrawReason::Stalemate,
+                chess::DrawType::ThreefoldRepetition => DrawReason::ThreefoldRepetition,
+                chess::DrawType::FiftyMoveRule => DrawReason::FiftyMoveRule,
+            })),
+            _ => Self::from_position(board),
+        }
+    }
+
+    /// Checkmate or stalemate if the side to move has no legal moves,
+    /// depending on whether its king is in check.
+    fn from_position(board: &mut Chess) -> Option<Self> {
+        if board
+            .generate_valid_moves()
+            .iter()
+            .any(|moves| !moves.is_empty())
+        {
+            return None;
+        }
+        let turn = board.turn;
+        if analysis::in_check(&analysis::grid(board), turn) {
+            Some(Self::Checkmate {
+                winner: opponent(turn),
+            })
+        } else {
+            Some(Self::Draw(DrawReason::Stalemate))
+        }
+    }
+
+    /// `mover` is the side whose move the opponent acknowledged. A reported
+    /// draw is put down to stalemate when our own board shows one.
+    fn from_end_state(end_state: &GameState, mover: ChessColor, board: &mut Chess) -> Self {
+        match end_state {
+            GameState::CheckMate => Self::Checkmate { winner: mover },
+            GameState::Draw => match Self::from_position(board) {
+                Some(stalemate @ Self::Draw(_)) => stalemate,
+                _ => Self::Draw(DrawReason::Reported),
+            },
+        }
+    }
+
+    /// What an ack reports about the game ending, which a resignation never
+    /// is.
+    fn end_state(&self) -> Option<GameState> {
+        match self {
+            Self::Checkmate { .. } => Some(GameState::CheckMate),
+            Self::Draw(_) => Some(GameState::Draw),
+            Self::OpponentResigned { .. } => None,
+        }
+    }
+
+    fn winner(&self) -> Option<ChessColor> {
+        match self {
+            Self::Checkmate { winner } | Self::OpponentResigned { winner } => Some(*winner),
+            Self::Draw(_) => None,
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            Self::Checkmate {
+                winner: ChessColor::White,
+            } => "White wins",
+            Self::Checkmate { .. } => "Black wins",
+            Self::Draw(DrawReason::Stalemate) => "Stalemate",
+            Self::Draw(DrawReason::ThreefoldRepetition) => "Threefold Repetition",
+            Self::Draw(DrawReason::FiftyMoveRule) => "Fifty Move Rule",
+            Self::Draw(DrawReason::Reported) => "Draw",
+            Self::Draw(DrawReason::Agreed) => "Draw by Agreement",
+            Self::OpponentResigned { .. } => "Opponent resigned — you win",
+        }
+    }
+
+    /// A line under the result saying why the game is over, for the endings
+    /// that are easy to mix up.
+    fn explanation(&self) -> Option<&'static str> {
+        match self {
+            Self::Checkmate { .. } => Some("Checkmate: in check with no legal moves"),
+            Self::Draw(DrawReason::Stalemate) => Some("No legal moves, not in check: draw"),
+            _ => None,
+        }
+    }
+}
+
+/// Things that happened to the game during an update, handled together at
+/// the end of it.
+enum GameEvent {
+    MoveApplied {
+        played: PlayedMove,
+        mover: ChessColor,
+    },
+    MoveRejected,
+    /// The phase has just become `Phase::End`.
+    GameEnded,
+}
+
+/// A copy of what the game looks like from outside, for logging and anything
+/// else that only needs to read it.
+struct GameSnapshot {
+    fen: String,
+    turn: ChessColor,
+    /// `moving`, `validating` or the result once the game is over.
+    phase: String,
+    white: String,
+    black: String,
+    /// In UCI notation.
+    last_move: Option<String>,
+}
+
+impl fmt::Display for GameSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let turn = if self.turn == ChessColor::White {
+            "white"
+        } else {
+            "black"
+        };
+        write!(
+            f,
+            "{} vs {}, {} to move, {}, last move {}: {}",
+            self.white,
+            self.black,
+            turn,
+            self.phase,
+            self.last_move.as_deref().unwrap_or("none"),
+            self.fen
+        )
+    }
+}
+
+/// The last move's piece sliding from its old square to its new one.
+struct Animation {
+    from: Position,
+    to: Position,
+    start: Instant,
+    /// The piece it took, fading out on its square with `--capture-fade`.
+    captured: Option<(PieceType, ChessColor, Position)>,
+}
+
+/// How opaque a captured piece still is `elapsed` into a fade lasting
+/// `duration`.
+fn fade_alpha(elapsed: Duration, duration: Duration) -> f32 {
+    if duration.is_zero() {
+        return 0.;
+    }
+    1. - (elapsed.as_secs_f32() / duration.as_secs_f32()).clamp(0., 1.)
+}
+
+/// The squares of the pieces attacking a square and of the ones defending
+/// it.
+#[derive(Debug, PartialEq, Eq)]
+struct SquareControl {
+    attackers: Vec<(usize, usize)>,
+    defenders: Vec<(usize, usize)>,
+}
+
+/// Results of the games played since the program started, by color.
+#[derive(Default)]
+struct Scoreboard {
+    white_wins: u32,
+    black_wins: u32,
+    draws: u32,
+}
+
+impl Scoreboard {
+    fn record(&mut self, outcome: &GameOutcome) {
+        match outcome {
+            GameOutcome::Checkmate {
+                winner: ChessColor::White,
+            } => self.white_wins += 1,
+            GameOutcome::Checkmate { .. } => self.black_wins += 1,
+            GameOutcome::OpponentResigned {
+                winner: ChessColor::White,
+            } => self.white_wins += 1,
+            GameOutcome::OpponentResigned { .. } => self.black_wins += 1,
+            GameOutcome::Draw(_) => self.draws += 1,
+        }
+    }
+
+    fn summary(&self, white: &str, black: &str) -> String {
+        format!(
+            "{} {} - {} {}, {} drawn",
+            white, self.white_wins, self.black_wins, black, self.draws
+        )
+    }
+}
+
+/// Stepping through the moves played so far. `index` is the number of moves
+/// applied to `board`, from 0 (the starting position) up to the full game.
+struct Review {
+    index: usize,
+    board: Chess,
+}
+
+/// Playing back a replay file by stepping through review mode, in time
+/// with `MainState::move_times`.
+struct Playback {
+    /// How far into the game playback has got.
+    position: Duration,
+    /// Toggled with Space.
+    playing: bool,
+}
+
+/// A piece being dragged with the mouse.
+struct Drag {
+    from: (u8, u8),
+    cursor: Vec2,
+    pressed_at: Instant,
+}
+
+fn is_long_press(pressed_at: Instant, released_at: Instant) -> bool {
+    released_at.saturating_duration_since(pressed_at) >= LONG_PRESS
+}
+
+enum Phase {
+    Move,
+    Validate(MoveKind),
+    End(GameOutcome),
+}
+
+/// The textures and meshes the board is drawn with, which need a window to
+/// be made.
+struct Graphics {
+    board_texture: Image,
+    piece_textures: [Image; 12],
+    move_to_dot: Mesh,
+    border: Mesh,
+    pin_marker: Mesh,
+    en_passant_marker: Mesh,
+    back_rank_marker: Mesh,
+    /// Tinted per use, marking the pieces that attack or defend the square
+    /// held with the right mouse button.
+    control_dot: Mesh,
+    control_ring: Mesh,
+    swatch: Mesh,
+}
+
+impl Graphics {
+    fn new(ctx: &mut Context, config: &Config) -> GameResult<Graphics> {
+        let (board_texture, move_to_dot, border) = themed_graphics(ctx, config)?;
+        let piece_textures = [
+            Image::from_bytes(ctx, include_bytes!("../assets/k_w.png"))?,
+            Image::from_bytes(ctx, include_bytes!("../assets/q_w.png"))?,
+            Image::from_bytes(ctx, include_bytes!("../assets/r_w.png"))?,
+            Image::from_bytes(ctx, include_bytes!("../assets/b_w.png"))?,
+            Image::from_bytes(ctx, include_bytes!("../assets/n_w.png"))?,
+            Image::from_bytes(ctx, include_bytes!("../assets/p_w.png"))?,
+            Image::from_bytes(ctx, include_bytes!("../assets/k_b.png"))?,
+            Image::from_bytes(ctx, include_bytes!("../assets/q_b.png"))?,
+            Image::from_bytes(ctx, include_bytes!("../assets/r_b.png"))?,
+            Image::from_bytes(ctx, include_bytes!("../assets/b_b.png"))?,
+            Image::from_bytes(ctx, include_bytes!("../assets/n_b.png"))?,
+            Image::from_bytes(ctx, include_bytes!("../assets/p_b.png"))?,
+        ];
+
+        let pin_marker = Mesh::new_circle(
+            ctx,
+            graphics::DrawMode::stroke(3.),
+            Vec2::new(0., 0.),
+            10.,
+            1.,
+            Color::from_rgba(80, 160, 255, 220),
+        )?;
+
+        let en_passant_marker = Mesh::new_rectangle(
+            ctx,
+            graphics::DrawMode::stroke(4.),
+            graphics::Rect::new(12., 12., 76., 76.),
+            Color::from_rgba(255, 160, 40, 220),
+        )?;
+
+        let back_rank_marker = Mesh::new_rectangle(
+            ctx,
+            graphics::DrawMode::stroke(4.),
+            graphics::Rect::new(6., 6., 88., 88.),
+            Color::from_rgba(230, 40, 40, 220),
+        )?;
+
+        let control_dot = Mesh::new_circle(
+            ctx,
+            graphics::DrawMode::fill(),
+            Vec2::new(0., 0.),
+            8.,
+            1.,
+            Color::WHITE,
+        )?;
+
+        let control_ring = Mesh::new_circle(
+            ctx,
+            graphics::DrawMode::stroke(4.),
+            Vec2::new(0., 0.),
+            8.,
+            1.,
+            Color::WHITE,
+        )?;
+
+        let swatch = Mesh::new_rectangle(
+            ctx,
+            graphics::DrawMode::fill(),
+            graphics::Rect::new(0., 0., 16., 16.),
+            Color::WHITE,
+        )?;
+        Ok(Graphics {
+            board_texture,
+            piece_textures,
+            move_to_dot,
+            border,
+            pin_marker,
+            en_passant_marker,
+            swatch,
+            control_dot,
+            control_ring,
+            back_rank_marker,
+        })
+    }
+}
+
+struct MainState {
+    board: Chess,
+    /// Always there with a window, tests run without one.
+    graphics: Option<Graphics>,
+    move_sounds: MoveSounds,
+    /// The move sound to play next update, left by `client_validate`.
+    queued_sound: Option<PieceType>,
+    /// Legal moves in the current position, regenerated whenever
+    /// `moves_key` falls behind the history.
+    current_moves: Option<[Vec<Move>; BOARD_SQUARES * BOARD_SQUARES]>,
+    moves_key: u64,
+    selected_square: Option<(u8, u8)>,
+    drag: Option<Drag>,
+    /// Set when the move being validated was dropped on its square, so it
+    /// shouldn't also slide there.
+    dropped_move: bool,
+    /// A move waiting for the player to confirm it.
+    unconfirmed_move: Option<Move>,
+    animation: Option<Animation>,
+    review: Option<Review>,
+    overlays: Overlays,
+    /// Our last move went out and the opponent hasn't answered with a move,
+    /// so it can still be taken back. The opponent's `Ack` doesn't count as
+    /// an answer since it's sent automatically.
+    awaiting_reply: bool,
+    retract_pending: bool,
+    input: Input,
+    text_prompt: Option<Text>,
+    player_handler: PlayerHandler,
+    phase: Phase,
+    config: Config,
+    history: Vec<PlayedMove>,
+    /// When each move in `history` was played, from `game_start`.
+    move_times: Vec<Duration>,
+    game_start: Instant,
+    /// Set while watching a replay file.
+    playback: Option<Playback>,
+    /// Moves found in the recovery file at startup, until the player either
+    /// restores or dismisses them.
+    pending_restore: Option<Vec<MoveSpec>>,
+    /// The saved game being practiced against, until the user plays a move
+    /// it doesn't have.
+    practice_line: Option<Vec<MoveSpec>>,
+    autosaved_moves: usize,
+    last_autosave: Instant,
+    draw_hint_dismissed: bool,
+    /// In hotseat games, show the board from the side not on move, toggled
+    /// with S.
+    sides_swapped: bool,
+    connection_lost: bool,
+    /// Recent non-fatal errors, oldest first.
+    errors: VecDeque<String>,
+    /// Toggled with E.
+    show_errors: bool,
+    /// Zoomed with the mouse wheel, panned by dragging with the middle
+    /// button and reset with 0.
+    camera: Camera,
+    /// When the current game ended, for the auto-reset countdown.
+    ended_at: Option<Instant>,
+    scoreboard: Scoreboard,
+    /// Opened on first use and kept, since on some platforms the copied
+    /// text goes away with it.
+    clipboard: Option<arboard::Clipboard>,
+    /// Set while H hides everything but the board and pieces, holding what
+    /// to bring back.
+    clean_view: Option<ViewSnapshot>,
+    /// N was pressed mid-game, waiting for Y or N.
+    confirm_discard: bool,
+    /// The opponent offered a draw, waiting for Y or N until we move.
+    draw_offer: bool,
+    /// The window was closed mid-game, waiting for S, Q or Escape.
+    quit_prompt: bool,
+    /// The player chose how to quit, so the next quit event goes through.
+    quitting: bool,
+    /// When the last move promoted a pawn that can still be changed, and
+    /// how long the history was then.
+    promotion_edit: Option<(Instant, usize)>,
+    /// When the board was last clicked during the opponent's turn, to
+    /// explain why nothing happened.
+    waiting_notice: Option<Instant>,
+    /// The opponent has moved in a network game and we haven't yet, so the
+    /// "your move" banner shows.
+    your_move: bool,
+    /// When J last jumped to our move, flashing the opponent's last move.
+    last_move_flash: Option<Instant>,
+    /// Toggled with Space in games without a network opponent. Nothing can
+    /// be played or changed until it is toggled back.
+    paused: bool,
+    events: Vec<GameEvent>,
+    /// Who said what, oldest first.
+    chat_log: VecDeque<(String, String)>,
+    /// The message being typed, while the chat box is open.
+    chat_input: Option<String>,
+    /// The keyboard went to the chat box this update, so nothing else
+    /// should look at it.
+    typing: bool,
+    /// `repetitions` of the current position, kept up to date along with
+    /// `current_moves`.
+    repetitions: usize,
+    pacer: FramePacer,
+}
+
+impl MainState {
+    fn new(ctx: &mut Context, game_type: GameType, config: Config) -> GameResult<MainState> {
+        let graphics = Graphics::new(ctx, &config)?;
+        let move_sounds =
+            MoveSounds::load(ctx, config.move_sound.as_deref(), &config.piece_sounds)?;
+        Ok(MainState::with_resources(
+            Some(graphics),
+            move_sounds,
+            game_type,
+            config,
+        ))
+    }
+
+    fn with_resources(
+        graphics: Option<Graphics>,
+        move_sounds: MoveSounds,
+        game_type: GameType,
+        config: Config,
+    ) -> MainState {
+        let pacer = FramePacer::new(config.max_fps, Instant::now());
+        let pending_restore = if let GameType::Local = game_type {
+            load_moves(&config.recovery_file).filter(|moves| !moves.is_empty())
+        } else {
+            None
+        };
+        let practice_line = if let GameType::Practice { moves_file, .. } = &game_type {
+            let moves = read_moves(moves_file)
+                .unwrap_or_else(|err| exit_with_error(format!("{}: {}", moves_file, err)));
+            Some(moves)
+        } else {
+            None
+        };
+        let replay = if let GameType::Replay(path) = &game_type {
+            let replay = replay::load(path)
+                .unwrap_or_else(|| exit_with_error(format!("{}: not a replay file", path)));
+            let specs: Vec<MoveSpec> = replay.moves.iter().map(|&(spec, _)| spec).collect();
+            let game = replay_moves(&specs).unwrap_or_else(|| {
+                exit_with_error(format!("{}: the replay doesn't hold a legal game", path))
+            });
+            Some((replay, game))
+        } else {
+            None
+        };
+        let text_prompt = pending_restore.as_ref().map(|_| {
+            Text::new(
+                TextFragment::new("Press R to restore the unfinished game")
+                    .color(Color::from_rgb(255, 0, 0))
+                    .scale(32.),
+            )
+        });
+
+        let mut state = MainState {
+            board: Chess::new(),
+            graphics,
+            move_sounds,
+            queued_sound: None,
+            current_moves: None,
+            moves_key: 0,
+            selected_square: None,
+            drag: None,
+            input: Input::default(),
+            dropped_move: false,
+            unconfirmed_move: None,
+            animation: None,
+            review: None,
+            overlays: config.overlays,
+            awaiting_reply: false,
+            retract_pending: false,
+            text_prompt,
+            player_handler: PlayerHandler::new(game_type, &config),
+            phase: Phase::Move,
+            config,
+            history: Vec::new(),
+            move_times: Vec::new(),
+            game_start: Instant::now(),
+            playback: None,
+            pending_restore,
+            practice_line,
+            autosaved_moves: 0,
+            last_autosave: Instant::now(),
+            draw_hint_dismissed: false,
+            sides_swapped: false,
+            connection_lost: false,
+            errors: VecDeque::new(),
+            show_errors: false,
+            camera: Camera::default(),
+            ended_at: None,
+            scoreboard: Scoreboard::default(),
+            clipboard: None,
+            clean_view: None,
+            confirm_discard: false,
+            draw_offer: false,
+            quit_prompt: false,
+            quitting: false,
+            promotion_edit: None,
+            waiting_notice: None,
+            your_move: false,
+            last_move_flash: None,
+            paused: false,
+            events: Vec::new(),
+            chat_log: VecDeque::new(),
+            chat_input: None,
+            typing: false,
+            repetitions: 1,
+            pacer,
+        };
+        if let Some((replay, game)) = replay {
+            state.start_playback(replay, game);
+        }
+        state
+    }
+
+    fn graphics(&self) -> &Graphics {
+        self.graphics.as_ref().expect("Drawing without a window")
+    }
+
+    /// Shows `replay` from its first position, `game` being its moves
+    /// played out.
+    fn start_playback(&mut self, replay: replay::Replay, game: (Chess, Vec<PlayedMove>)) {
+        let (board, history) = game;
+        self.board = board;
+        self.history = history;
+        let players = &mut self.player_handler.players;
+        players.white.name = Some(replay.white).filter(|name| !name.is_empty());
+        players.black.name = Some(replay.black).filter(|name| !name.is_empty());
+        logging::info(format!("Replaying a {} game", replay.mode));
+        self.move_times = replay.moves.into_iter().map(|(_, at)| at).collect();
+        self.playback = Some(Playback {
+            position: Duration::ZERO,
+            playing: true,
+        });
+        self.set_review(Some(0));
+    }
+
+    /// Steps through the replay in time with the moves, on top of the
+    /// review keys: stepping by hand moves playback along with it.
+    fn update_playback(&mut self, delta: Duration) {
+        let index = self
+            .review
+            .as_ref()
+            .map_or(self.history.len(), |review| review.index);
+        let playback = if let Some(playback) = &mut self.playback {
+            playback
+        } else {
+            return;
+        };
+        if self.input.key(KeyCode::Space) {
+            playback.playing = !playback.playing;
+        }
+        let shown = index
+            .checked_sub(1)
+            .map_or(Duration::ZERO, |i| self.move_times[i]);
+        let next = self.move_times.get(index).copied();
+        if playback.position < shown || next.is_some_and(|next| playback.position > next) {
+            playback.position = shown;
+        }
+        if !playback.playing {
+            return;
+        }
+        playback.position += delta;
+        match next {
+            Some(next) if playback.position >= next => self.set_review(Some(index + 1)),
+            Some(_) => {}
+            None => playback.playing = false,
+        }
+    }
+
+    /// Writes the game to the `--record` file.
+    fn save_replay(&mut self) {
+        let path = if let Some(path) = &self.config.record_file {
+            path.clone()
+        } else {
+            return;
+        };
+        let replay = replay::Replay {
+            white: self.player_name(ChessColor::White),
+            black: self.player_name(ChessColor::Black),
+            mode: self.player_handler.game_type.description(),
+            moves: self
+                .history
+                .iter()
+                .map(PlayedMove::spec)
+                .zip(self.move_times.iter().copied())
+                .collect(),
+        };
+        match replay::save(&path, &replay) {
+            Ok(()) => logging::info(format!("Saved the replay to {}", path)),
+            Err(err) => self.log_error(format!("Failed to write {}: {}", path, err)),
+        }
+    }
+
+    /// Records a problem that doesn't stop the game, for the error console
+    /// and stderr. Only the last `MAX_LOGGED_ERRORS` are kept.
+    fn log_error(&mut self, msg: impl Into<String>) {
+        let msg = msg.into();
+        logging::error(&msg);
+        if self.errors.len() == MAX_LOGGED_ERRORS {
+            self.errors.pop_front();
+        }
+        self.errors.push_back(msg);
+    }
+
+    fn dead_position(&self) -> bool {
+        analysis::is_dead_position(&analysis::grid(&self.board))
+    }
+
+    fn draw_hint_due(&self) -> bool {
+        halfmove_clock(&self.history) > self.config.draw_hint_halfmoves || self.dead_position()
+    }
+
+    fn draw_hint_active(&self) -> bool {
+        matches!(self.phase, Phase::Move) && !self.draw_hint_dismissed && self.draw_hint_due()
+    }
+
+    fn update_draw_hint(&mut self) {
+        if !self.draw_hint_due() {
+            self.draw_hint_dismissed = false;
+        } else if self.draw_hint_active() && self.input.key(KeyCode::D) {
+            self.draw_hint_dismissed = true;
+        }
+    }
+
+    fn handle_restore_prompt(&mut self) {
+        if self.pending_restore.is_none() {
+            return;
+        }
+        if self.input.key(KeyCode::R) {
+            let moves = self.pending_restore.take().unwrap();
+            if let Some((board, history)) = replay_moves(&moves) {
+                self.board = board;
+                self.autosaved_moves = history.len();
+                self.move_times = vec![Duration::ZERO; history.len()];
+                self.history = history;
+                self.selected_square = None;
+            } else {
+                self.log_error("Recovery file does not hold a legal game, ignoring it");
+            }
+            self.text_prompt = None;
+        } else if self.input.key(KeyCode::Escape) || !self.history.is_empty() {
+            self.pending_restore = None;
+            self.text_prompt = None;
+        }
+    }
+
+    /// Writes local games to the recovery file every few moves, so a crash
+    /// doesn't lose them.
+    fn autosave(&mut self) {
+        if !matches!(self.player_handler.game_type, GameType::Local) {
+            return;
+        }
+        let unsaved = self.history.len() - self.autosaved_moves;
+        if unsaved == 0 {
+            return;
+        }
+        if unsaved >= self.config.autosave_moves
+            || self.last_autosave.elapsed() >= self.config.autosave_interval
+        {
+            if let Err(err) = save_moves(&self.config.recovery_file, &self.history) {
+                self.log_error(format!("Failed to write recovery file: {}", err));
+            }
+            self.autosaved_moves = self.history.len();
+            self.last_autosave = Instant::now();
+        }
+    }
+
+    fn get_moves(&self) -> Option<&Vec<Move>> {
+        let selected_square = self.selected_square?;
+        let moves = self.current_moves.as_ref().unwrap();
+        Some(&moves[move_index(selected_square.0 as usize, selected_square.1 as usize)])
+    }
+
+    /// Where the top left corner of the piece on `pos` is drawn.
+    fn square_dest(&self, pos: Position) -> Vec2 {
+        let x = pos.x as f32 * SQUARE_SIZE;
+        let y = pos.y as f32 * SQUARE_SIZE;
+        let mut dest = Vec2::new(x, y);
+        if self.should_reverse() {
+            dest.y = HEIGHT - SQUARE_SIZE - dest.y;
+        }
+        dest
+    }
+
+    /// The live board, or the position being reviewed.
+    fn displayed_board(&self) -> &Chess {
+        self.review
+            .as_ref()
+            .map_or(&self.board, |review| &review.board)
+    }
+
+    fn review_index(&self) -> Option<usize> {
+        let current = self.review.as_ref().map(|review| review.index);
+        let last = self.history.len();
+        if self.input.key(KeyCode::Left) {
+            Some(current.unwrap_or(last).saturating_sub(1))
+        } else if self.input.key(KeyCode::Right) {
+            // Stepping past the last move goes back to the live game.
+            current
+                .map(|index| index + 1)
+                .filter(|&index| index <= last)
+        } else if self.input.key(KeyCode::Home) {
+            Some(0)
+        } else if self.input.key(KeyCode::End) {
+            Some(last)
+        } else if self.input.key(KeyCode::Escape) {
+            None
+        } else {
+            current
+        }
+    }
+
+    fn handle_review_keys(&mut self) {
+        if self.drag.is_some() {
+            return;
+        }
+        let index = self.review_index();
+        if index == self.review.as_ref().map(|review| review.index) {
+            return;
+        }
+        self.set_review(index);
+    }
+
+    /// Shows the banner whenever it is our turn in a network game.
+    fn update_your_move(&mut self) {
+        self.your_move =
+            self.player_handler.network.is_some() && self.player_handler.can_move(self.board.turn);
+    }
+
+    /// Back to the live board, if reviewing, with the opponent's last move
+    /// flashed. A selection made on the live board is kept.
+    fn jump_to_move(&mut self) {
+        if self.review.is_some() {
+            self.set_review(None);
+        }
+        self.last_move_flash = Some(Instant::now());
+    }
+
+    /// Shows the position after `index` moves, or the live game for `None`.
+    fn set_review(&mut self, index: Option<usize>) {
+        self.review = index.and_then(|index| {
+            let specs: Vec<MoveSpec> = self.history[..index].iter().map(PlayedMove::spec).collect();
+            let (board, _) = replay_moves(&specs)?;
+            Some(Review { index, board })
+        });
+        self.selected_square = None;
+    }
+
+    /// Texture index and destination of every piece on the board, in the
+    /// current orientation. Kept free of any GPU work so `draw_pieces` is
+    /// just a loop over this list.
+    fn piece_draw_commands(&self) -> Vec<(usize, Vec2)> {
+        let pieces = &self.displayed_board().board;
+        let mut commands = Vec::new();
+        let mut drag_command = None;
+        for piece in pieces {
+            let piece = if let Some(piece) = piece {
+                piece
+            } else {
+                continue;
+            };
+            let texture_idx = piece.piece_type as usize
+                + if piece.color == ChessColor::White {
+                    0
+                } else {
+                    6
+                };
+            let square = (piece.position.x as u8, piece.position.y as u8);
+            if let Some(drag) = self.drag.as_ref().filter(|drag| drag.from == square) {
+                // Drawn last so it stays on top, centered on the cursor.
+                drag_command = Some((texture_idx, drag.cursor - Vec2::new(50., 50.)));
+                continue;
+            }
+            let mut dest = self.square_dest(piece.position);
+            if let Some(animation) = self.animation.as_ref().filter(|_| self.review.is_none()) {
+                if (animation.to.x, animation.to.y) == (piece.position.x, piece.position.y) {
+                    let t = animation.start.elapsed().as_secs_f32()
+                        / self.config.animation_duration.as_secs_f32();
+                    let t = self.config.animation_easing.apply(t);
+                    let from = self.square_dest(animation.from);
+                    dest = from + (dest - from) * t;
+                }
+            }
+            commands.push((texture_idx, dest));
+        }
+        commands.extend(drag_command);
+        commands
+    }
+
+    fn draw_pieces(&self, canvas: &mut Canvas) -> GameResult {
+        const SCALE: f32 = 100.0 / PIECE_TEX_SIZE;
+        let fading = self
+            .animation
+            .as_ref()
+            .filter(|_| self.review.is_none())
+            .and_then(|animation| Some((animation.captured?, animation.start)));
+        if let Some(((piece, color, pos), start)) = fading {
+            let texture_idx = piece as usize + if color == ChessColor::White { 0 } else { 6 };
+            let alpha = fade_alpha(start.elapsed(), self.config.animation_duration);
+            let draw_params = DrawParam::new()
+                .dest(self.square_dest(pos))
+                .scale(Vec2::new(SCALE, SCALE))
+                .color(Color::new(1., 1., 1., alpha));
+            canvas.draw(&self.graphics().piece_textures[texture_idx], draw_params);
+        }
+        for (texture_idx, dest) in self.piece_draw_commands() {
+            let texture = &self.graphics().piece_textures[texture_idx];
+            let draw_params = DrawParam::new().dest(dest).scale(Vec2::new(SCALE, SCALE));
+            canvas.draw(texture, draw_params);
+        }
+        Ok(())
+    }
+
+    /// The piece draw commands shrunk into a minimap whose top left corner
+    /// is at `origin`.
+    fn minimap_draw_commands(&self, origin: Vec2) -> Vec<(usize, Vec2)> {
+        self.piece_draw_commands()
+            .into_iter()
+            .map(|(texture_idx, dest)| (texture_idx, origin + dest * MINIMAP_SCALE))
+            .collect()
+    }
+
+    /// A small copy of the whole board, in the margin beside the board if
+    /// the window leaves room for one and in its bottom right corner if not.
+    fn draw_minimap(&self, ctx: &Context, canvas: &mut Canvas) -> GameResult {
+        if !self.config.minimap {
+            return Ok(());
+        }
+        let size = WIDTH * MINIMAP_SCALE;
+        let (sc_width, sc_height) = ctx.gfx.size();
+        let viewport = board_viewport(sc_width, sc_height);
+        let margin = viewport.x * WIDTH / viewport.w;
+        let origin = if margin >= size + 20. {
+            Vec2::new(WIDTH + 10., 10.)
+        } else {
+            Vec2::new(WIDTH - size - 10., HEIGHT - size - 10.)
+        };
+
+        let reverse = self.should_reverse();
+        let (offset, flip) = if reverse { (size, -1.) } else { (0., 1.) };
+        canvas.draw(
+            &self.graphics().board_texture,
+            DrawParam::new()
+                .dest(origin + Vec2::new(0., offset))
+                .scale(Vec2::new(MINIMAP_SCALE, flip * MINIMAP_SCALE)),
+        );
+        let scale = MINIMAP_SCALE * 100.0 / PIECE_TEX_SIZE;
+        for (texture_idx, dest) in self.minimap_draw_commands(origin) {
+            let texture = &self.graphics().piece_textures[texture_idx];
+            canvas.draw(
+                texture,
+                DrawParam::new().dest(dest).scale(Vec2::new(scale, scale)),
+            );
+        }
+        Ok(())
+    }
+
+    fn draw_selected(&self, canvas: &mut Canvas) -> GameResult {
+        let reverse = self.should_reverse();
+        let moves = self.get_moves();
+        if moves.is_none() {
+            return Ok(());
+        }
+        let moves = moves.unwrap();
+        for mv in moves {
+            let x = mv.to.x as f32 * SQUARE_SIZE;
+            let y = mv.to.y as f32 * SQUARE_SIZE;
+            let mut dest = Vec2::new(50. + x, y);
+            if reverse {
+                dest.y = HEIGHT - SQUARE_SIZE - dest.y;
+            }
+            dest.y += 50.;
+            canvas.draw(&self.graphics().move_to_dot, DrawParam::new().dest(dest));
+        }
+        Ok(())
+    }
+
+    /// The moves of the piece under the cursor, shown before it's picked.
+    fn hover_preview(&self) -> &[Move] {
+        let local_turn = self.player_handler.both_local()
+            || self.player_handler.one_local() == Some(self.board.turn);
+        if !self.config.hover_moves
+            || !local_turn
+            || self.review.is_some()
+            || self.drag.is_some()
+            || !matches!(self.phase, Phase::Move)
+        {
+            return &[];
+        }
+        let hovered = if let Some(hovered) = self.hovered_square() {
+            hovered
+        } else {
+            return &[];
+        };
+        if self.selected_square == Some(hovered) {
+            return &[];
+        }
+        let pos = Position {
+            x: hovered.0 as usize,
+            y: hovered.1 as usize,
+        };
+        if !matches!(piece_at(&self.board, pos), Some((_, color)) if color == self.board.turn) {
+            return &[];
+        }
+        self.current_moves
+            .as_ref()
+            .map_or(&[], |moves| &moves[move_index(pos.x, pos.y)])
+    }
+
+    /// Faint dots for the legal moves of the local piece under the cursor,
+    /// so moves can be explored without selecting anything.
+    fn draw_hover_moves(&self, canvas: &mut Canvas) -> GameResult {
+        for mv in self.hover_preview() {
+            let dest = self.square_dest(mv.to) + Vec2::new(50., 50.);
+            canvas.draw(
+                &self.graphics().move_to_dot,
+                DrawParam::new()
+                    .dest(dest)
+                    .color(Color::from_rgba(255, 255, 255, 100)),
+            );
+        }
+        Ok(())
+    }
+
+    /// Where the arrow along the last move starts and where its tip is.
+    fn last_move_arrow(&self) -> (Vec2, Vec2) {
+        let last = self.history.last().expect("No move to draw an arrow for");
+        let center = Vec2::new(SQUARE_SIZE / 2., SQUARE_SIZE / 2.);
+        (
+            self.square_dest(last.from) + center,
+            self.square_dest(last.to) + center,
+        )
+    }
+
+    /// An arrow from where the last move started to where it ended, unless
+    /// it was our own move in a game against someone else.
+    fn draw_last_move_arrow(&self, ctx: &Context, canvas: &mut Canvas) -> GameResult {
+        if !self.overlays.last_move_arrow || self.review.is_some() {
+            return Ok(());
+        }
+        if self.history.is_empty()
+            || self.player_handler.one_local() == Some(opponent(self.board.turn))
+        {
+            return Ok(());
+        }
+        let (start, tip) = self.last_move_arrow();
+        let dir = (tip - start).normalize();
+        let side = Vec2::new(-dir.y, dir.x) * 14.;
+        let base = tip - dir * 28.;
+        let color = Color::from_rgba(255, 170, 0, 200);
+        let shaft = Mesh::new_line(ctx, &[start, base], 8., color)?;
+        let head = Mesh::new_polygon(
+            ctx,
+            graphics::DrawMode::fill(),
+            &[tip, base + side, base - side],
+            color,
+        )?;
+        canvas.draw(&shaft, DrawParam::new());
+        canvas.draw(&head, DrawParam::new());
+        Ok(())
+    }
+
+    fn draw_last_move_flash(&self, ctx: &Context, canvas: &mut Canvas) -> GameResult {
+        let flashing = self
+            .last_move_flash
+            .is_some_and(|jumped| jumped.elapsed() < LAST_MOVE_FLASH);
+        let last = match self.history.last() {
+            Some(last) if flashing && self.review.is_none() => last,
+            _ => return Ok(()),
+        };
+        let square = Mesh::new_rectangle(
+            ctx,
+            graphics::DrawMode::fill(),
+            graphics::Rect::new(0., 0., SQUARE_SIZE, SQUARE_SIZE),
+            Color::from_rgba(255, 230, 0, 120),
+        )?;
+        for pos in [last.from, last.to] {
+            canvas.draw(&square, DrawParam::new().dest(self.square_dest(pos)));
+        }
+        Ok(())
+    }
+
+    /// A line for each legal move of the side to move that uncovers a check
+    /// from another of its pieces.
+    fn draw_discovered_checks(&self, ctx: &Context, canvas: &mut Canvas) -> GameResult {
+        if !self.overlays.discovered_checks
+            || self.review.is_some()
+            || !matches!(self.phase, Phase::Move)
+        {
+            return Ok(());
+        }
+        let moves = if let Some(moves) = &self.current_moves {
+            moves
+        } else {
+            return Ok(());
+        };
+        let candidates: Vec<_> = moves
+            .iter()
+            .flatten()
+            .map(|mv| ((mv.from.x, mv.from.y), (mv.to.x, mv.to.y)))
+            .collect();
+        let grid = analysis::grid(&self.board);
+        for (from, to) in analysis::discovered_checks(&grid, self.board.turn, &candidates) {
+            let center = Vec2::new(50., 50.);
+            let points = [
+                self.square_dest(Position {
+                    x: from.0,
+                    y: from.1,
+                }) + center,
+                self.square_dest(Position { x: to.0, y: to.1 }) + center,
+            ];
+            let line = Mesh::new_line(ctx, &points, 4., Color::from_rgba(200, 60, 220, 200))?;
+            canvas.draw(&line, DrawParam::new());
+        }
+        Ok(())
+    }
+
+    /// While the right mouse button is held on a square, or with
+    /// `hover_threats` the cursor is on a piece, the squares of the pieces
+    /// attacking it and of the ones defending it. An empty square is
+    /// defended by the side to move.
+    fn square_control(&self) -> Option<SquareControl> {
+        if self.drag.is_some() {
+            return None;
+        }
+        let held = self.hovered_square()?;
+        let held = (held.0 as usize, held.1 as usize);
+        let board = self.displayed_board();
+        let grid = analysis::grid(board);
+        let hovering_piece = self.overlays.hover_threats && grid[held.0][held.1].is_some();
+        if !self.input.held(MouseButton::Right) && !hovering_piece {
+            return None;
+        }
+        let defender = grid[held.0][held.1].map_or(board.turn, |(_, color)| color);
+        Some(SquareControl {
+            attackers: analysis::attackers(&grid, held, opponent(defender)),
+            defenders: analysis::attackers(&grid, held, defender),
+        })
+    }
+
+    /// Marks the pieces from `square_control` in the palette's colors.
+    fn draw_square_control(&self, canvas: &mut Canvas) -> GameResult {
+        let control = if let Some(control) = self.square_control() {
+            control
+        } else {
+            return Ok(());
+        };
+        let palette = self.config.palette;
+        let defender_marker = if palette == Palette::ColorBlind {
+            &self.graphics().control_ring
+        } else {
+            &self.graphics().control_dot
+        };
+        let sides = [
+            (
+                control.attackers,
+                palette.attacker(),
+                &self.graphics().control_dot,
+            ),
+            (control.defenders, palette.defender(), defender_marker),
+        ];
+        for (squares, color, marker) in sides {
+            for (x, y) in squares {
+                let dest = self.square_dest(Position { x, y }) + Vec2::new(15., 15.);
+                canvas.draw(marker, DrawParam::new().dest(dest).color(color));
+            }
+        }
+        Ok(())
+    }
+
+    /// Each shown piece's point value and where it goes, kings left out.
+    fn piece_value_labels(&self) -> Vec<(String, Vec2)> {
+        self.displayed_board()
+            .board
+            .iter()
+            .flatten()
+            .filter_map(|piece| {
+                let value = piece_value(piece.piece_type)?;
+                let dest = self.square_dest(piece.position) + Vec2::new(46., 80.);
+                Some((value.to_string(), dest))
+            })
+            .collect()
+    }
+
+    /// Every square's name and where it goes, in the square's bottom left
+    /// corner.
+    fn in_square_labels(&self) -> Vec<(String, Vec2)> {
+        (0..BOARD_SQUARES)
+            .flat_map(|x| (0..BOARD_SQUARES).map(move |y| Position { x, y }))
+            .map(|pos| {
+                let label = self.config.coordinates.square_label(pos);
+                (label, self.square_dest(pos) + Vec2::new(3., 86.))
+            })
+            .collect()
+    }
+
+    /// Files along the bottom edge and ranks along the left edge, whichever
+    /// way round the board is.
+    fn draw_coordinates(&self, canvas: &mut Canvas) -> GameResult {
+        if self.clean_view.is_some() {
+            return Ok(());
+        }
+        let label_color = Color::from_rgba(40, 40, 40, 200);
+        if self.config.coordinates_in_squares {
+            for (label, dest) in self.in_square_labels() {
+                let text = Text::new(TextFragment::new(label).color(label_color).scale(12.));
+                canvas.draw(&text, DrawParam::new().dest(dest));
+            }
+            return Ok(());
+        }
+        let bottom = if self.should_reverse() {
+            0
+        } else {
+            BOARD_SQUARES - 1
+        };
+        for i in 0..BOARD_SQUARES {
+            let file_square = Position { x: i, y: bottom };
+            let (file, _) = self.config.coordinates.labels(file_square);
+            let text = Text::new(TextFragment::new(file).color(label_color).scale(16.));
+            let dest = self.square_dest(file_square) + Vec2::new(86., 80.);
+            canvas.draw(&text, DrawParam::new().dest(dest));
+
+            let rank_square = Position { x: 0, y: i };
+            let (_, rank) = self.config.coordinates.labels(rank_square);
+            let text = Text::new(TextFragment::new(rank).color(label_color).scale(16.));
+            let dest = self.square_dest(rank_square) + Vec2::new(4., 2.);
+            canvas.draw(&text, DrawParam::new().dest(dest));
+        }
+        Ok(())
+    }
+
+    fn draw_overlays(&self, canvas: &mut Canvas) -> GameResult {
+        if self.overlays.piece_values {
+            for (value, dest) in self.piece_value_labels() {
+                let text = Text::new(
+                    TextFragment::new(value)
+                        .color(Color::from_rgb(255, 255, 0))
+                        .scale(18.),
+                );
+                canvas.draw(&text, DrawParam::new().dest(dest));
+            }
+        }
+        if self.overlays.pins {
+            let board = self.displayed_board();
+            let grid = analysis::grid(board);
+            for pos in analysis::pinned_pieces(&grid, board.turn) {
+                let dest = self.square_dest(pos) + Vec2::new(85., 15.);
+                canvas.draw(&self.graphics().pin_marker, DrawParam::new().dest(dest));
+            }
+        }
+        if self.overlays.back_rank {
+            let board = self.displayed_board();
+            let grid = analysis::grid(board);
+            for (x, y) in analysis::back_rank_threats(&grid, board.turn) {
+                let dest = self.square_dest(Position { x, y });
+                canvas.draw(
+                    &self.graphics().back_rank_marker,
+                    DrawParam::new().dest(dest),
+                );
+            }
+        }
+        if let Some(dest) = self.en_passant_marker() {
+            canvas.draw(
+                &self.graphics().en_passant_marker,
+                DrawParam::new().dest(dest),
+            );
+        }
+        Ok(())
+    }
+
+    /// Where the en passant target of the reviewed position is marked.
+    fn en_passant_marker(&self) -> Option<Vec2> {
+        let review = self.review.as_ref()?;
+        en_passant_target(&self.history[..review.index]).map(|target| self.square_dest(target))
+    }
+
+    fn player_name(&self, color: ChessColor) -> String {
+        let player = self.player_handler.players.get_player(color);
+        player.name.clone().unwrap_or_else(|| {
+            if color == ChessColor::White {
+                "White".to_owned()
+            } else {
+                "Black".to_owned()
+            }
+        })
+    }
+
+    fn player_label(&self, color: ChessColor) -> String {
+        let player = self.player_handler.players.get_player(color);
+        let name = self.player_name(color);
+        if self.player_handler.both_local() {
+            name
+        } else if player.local {
+            format!("{} (you)", name)
+        } else {
+            format!("{} (opponent)", name)
+        }
+    }
+
+    /// Each side's label and the top of the line it goes on, the side at
+    /// the bottom of the board first.
+    fn player_labels(&self) -> [(ChessColor, String, f32); 2] {
+        let bottom = if self.should_reverse() {
+            ChessColor::White
+        } else {
+            ChessColor::Black
+        };
+        let top = opponent(bottom);
+        [
+            (bottom, self.player_label(bottom), HEIGHT - 30.),
+            (top, self.player_label(top), 10.),
+        ]
+    }
+
+    /// Each player's name in the right corner of their side of the board,
+    /// after a swatch of their color.
+    fn draw_player_labels(&self, ctx: &mut Context, canvas: &mut Canvas) -> GameResult {
+        if self.clean_view.is_some() {
+            return Ok(());
+        }
+        for (color, label, y) in self.player_labels() {
+            let text = Text::new(
+                TextFragment::new(label)
+                    .color(Color::from_rgba(255, 255, 255, 220))
+                    .scale(20.),
+            );
+            let width = text.dimensions(ctx).map_or(0., |dims| dims.w);
+            let x = WIDTH - width - 10.;
+            let swatch_color = if color == ChessColor::White {
+                Color::WHITE
+            } else {
+                Color::BLACK
+            };
+            canvas.draw(
+                &self.graphics().swatch,
+                DrawParam::new()
+                    .dest(Vec2::new(x - 24., y + 2.))
+                    .color(swatch_color),
+            );
+            canvas.draw(&text, DrawParam::new().dest(Vec2::new(x, y)));
+        }
+        Ok(())
+    }
+
+    /// The name of the hovered square, if tooltips are on.
+    fn square_tooltip(&self) -> Option<String> {
+        if !self.config.square_tooltips {
+            return None;
+        }
+        let square = self.hovered_square()?;
+        Some(square_name(Position {
+            x: square.0 as usize,
+            y: square.1 as usize,
+        }))
+    }
+
+    /// The hovered square's name next to the cursor.
+    fn draw_square_tooltip(&self, canvas: &mut Canvas) -> GameResult {
+        let name = if let Some(name) = self.square_tooltip() {
+            name
+        } else {
+            return Ok(());
+        };
+        let text = Text::new(
+            TextFragment::new(name)
+                .color(Color::from_rgba(255, 255, 255, 230))
+                .scale(18.),
+        );
+        let dest = self.view_cursor_position() + Vec2::new(14., 14.);
+        canvas.draw(&text, DrawParam::new().dest(dest));
+        Ok(())
+    }
+
+    /// What the draw watch warns about, if the fifty-move rule is getting
+    /// close or the position has been seen before.
+    fn draw_watch(&self) -> Option<String> {
+        let quiet = halfmove_clock(&self.history);
+        let mut warnings = Vec::new();
+        if quiet >= FIFTY_MOVE_WATCH {
+            warnings.push(format!("{}/100 quiet half-moves", quiet));
+        }
+        if self.repetitions >= 2 {
+            warnings.push(format!("position seen {} times", self.repetitions));
+        }
+        if warnings.is_empty() {
+            None
+        } else {
+            Some(format!("Draw watch: {}", warnings.join(", ")))
+        }
+    }
+
+    /// The draw watch's warning, under the move number.
+    fn draw_draw_watch(&self, ctx: &mut Context, canvas: &mut Canvas) -> GameResult {
+        if self.clean_view.is_some() || !matches!(self.phase, Phase::Move) {
+            return Ok(());
+        }
+        let warning = if let Some(warning) = self.draw_watch() {
+            warning
+        } else {
+            return Ok(());
+        };
+        let text = Text::new(
+            TextFragment::new(warning)
+                .color(Color::from_rgba(255, 200, 80, 220))
+                .scale(18.),
+        );
+        let width = text.dimensions(ctx).map_or(0., |dims| dims.w);
+        canvas.draw(
+            &text,
+            DrawParam::new().dest(Vec2::new(WIDTH - width - 10., 70.)),
+        );
+        Ok(())
+    }
+
+    /// The fullmove number of the position on screen.
+    fn move_number(&self) -> usize {
+        let plies = self
+            .review
+            .as_ref()
+            .map_or(self.history.len(), |review| review.index);
+        fullmove_number(plies)
+    }
+
+    /// "Move 12" under the top player's label, for the position on screen.
+    fn draw_move_number(&self, ctx: &mut Context, canvas: &mut Canvas) -> GameResult {
+        if self.clean_view.is_some() {
+            return Ok(());
+        }
+        let text = Text::new(
+            TextFragment::new(format!("Move {}", self.move_number()))
+                .color(Color::from_rgba(255, 255, 255, 200))
+                .scale(20.),
+        );
+        let width = text.dimensions(ctx).map_or(0., |dims| dims.w);
+        canvas.draw(
+            &text,
+            DrawParam::new().dest(Vec2::new(WIDTH - width - 10., 40.)),
+        );
+        Ok(())
+    }
+
+    fn draw_review_status(&self, canvas: &mut Canvas) -> GameResult {
+        if let Some(review) = &self.review {
+            let text = format!(
+                "Reviewing {}/{} (Esc to return)",
+                review.index,
+                self.history.len()
+            );
+            let text = Text::new(
+                TextFragment::new(text)
+                    .color(Color::from_rgba(255, 255, 255, 200))
+                    .scale(20.),
+            );
+            canvas.draw(&text, DrawParam::new().dest(Vec2::new(10., 40.)));
+        }
+        Ok(())
+    }
+
+    fn draw_retract_hint(&self, canvas: &mut Canvas) -> GameResult {
+        if !self.awaiting_reply {
+            return Ok(());
+        }
+        let text = Text::new(
+            TextFragment::new("Press U to take back your move")
+                .color(Color::from_rgba(255, 255, 255, 200))
+                .scale(20.),
+        );
+        canvas.draw(&text, DrawParam::new().dest(Vec2::new(10., HEIGHT - 30.)));
+        Ok(())
+    }
+
+    fn draw_your_move(&self, ctx: &Context, canvas: &mut Canvas) -> GameResult {
+        if !self.your_move || !matches!(self.phase, Phase::Move) {
+            return Ok(());
+        }
+        let text = Text::new(
+            TextFragment::new("Your move (J to jump to it)")
+                .color(Color::from_rgb(255, 230, 0))
+                .scale(28.),
+        );
+        let width = text.dimensions(ctx).map_or(0., |dims| dims.w);
+        canvas.draw(
+            &text,
+            DrawParam::new().dest(Vec2::new((WIDTH - width) / 2., 10.)),
+        );
+        Ok(())
+    }
+
+    fn draw_waiting_notice(&self, canvas: &mut Canvas) -> GameResult {
+        let recent = self
+            .waiting_notice
+            .is_some_and(|clicked| clicked.elapsed() < Duration::from_millis(1500));
+        if !recent || !matches!(self.phase, Phase::Move) {
+            return Ok(());
+        }
+        let text = Text::new(
+            TextFragment::new("Waiting for the opponent's move")
+                .color(Color::from_rgba(255, 255, 255, 220))
+                .scale(20.),
+        );
+        canvas.draw(&text, DrawParam::new().dest(Vec2::new(10., HEIGHT - 120.)));
+        Ok(())
+    }
+
+    fn draw_paused(&self, ctx: &Context, canvas: &mut Canvas) -> GameResult {
+        if !self.paused {
+            return Ok(());
+        }
+        let text = Text::new(
+            TextFragment::new("Paused")
+                .color(Color::from_rgba(255, 255, 255, 230))
+                .scale(64.),
+        );
+        let dims = text.dimensions(ctx).unwrap();
+        let (sc_width, sc_height) = ctx.gfx.size();
+        let dest = centered_in_window(dims.w, dims.h, sc_width, sc_height);
+        canvas.draw(&text, DrawParam::new().dest(dest));
+        Ok(())
+    }
+
+    fn draw_promotion_edit(&self, canvas: &mut Canvas) -> GameResult {
+        let promoted = self
+            .promotion_edit
+            .and_then(|_| self.history.last())
+            .and_then(|played| played.promotion);
+        let piece = if let Some(piece) = promoted {
+            piece
+        } else {
+            return Ok(());
+        };
+        let text = format!(
+            "Promoted to {}, press Q, R, B or N to change",
+            piece_name(piece)
+        );
+        let text = Text::new(
+            TextFragment::new(text)
+                .color(Color::from_rgba(255, 255, 255, 220))
+                .scale(20.),
+        );
+        canvas.draw(&text, DrawParam::new().dest(Vec2::new(10., HEIGHT - 90.)));
+        Ok(())
+    }
+
+    fn draw_confirm_prompt(&self, canvas: &mut Canvas) -> GameResult {
+        let mv = if let Some(mv) = &self.unconfirmed_move {
+            mv
+        } else {
+            return Ok(());
+        };
+        let text = format!(
+            "Play {} to {}? Enter to confirm, Escape to cancel",
+            square_name(mv.from),
+            square_name(mv.to)
+        );
+        let text = Text::new(
+            TextFragment::new(text)
+                .color(Color::from_rgba(255, 255, 255, 220))
+                .scale(20.),
+        );
+        canvas.draw(&text, DrawParam::new().dest(Vec2::new(10., HEIGHT - 60.)));
+        Ok(())
+    }
+
+    fn draw_discard_prompt(&self, canvas: &mut Canvas) -> GameResult {
+        if !self.confirm_discard {
+            return Ok(());
+        }
+        let text = Text::new(
+            TextFragment::new("Discard the current game? (Y/N)")
+                .color(Color::from_rgba(255, 255, 255, 220))
+                .scale(20.),
+        );
+        canvas.draw(&text, DrawParam::new().dest(Vec2::new(10., HEIGHT - 90.)));
+        Ok(())
+    }
+
+    fn draw_draw_offer(&self, canvas: &mut Canvas) -> GameResult {
+        if !self.draw_offer {
+            return Ok(());
+        }
+        let text = Text::new(
+            TextFragment::new("Your opponent offers a draw. Accept? (Y/N)")
+                .color(Color::from_rgba(255, 255, 255, 220))
+                .scale(20.),
+        );
+        canvas.draw(&text, DrawParam::new().dest(Vec2::new(10., HEIGHT - 90.)));
+        Ok(())
+    }
+
+    fn draw_quit_prompt(&self, ctx: &Context, canvas: &mut Canvas) -> GameResult {
+        if !self.quit_prompt {
+            return Ok(());
+        }
+        let text = Text::new(
+            TextFragment::new("Quit? S to save and quit, Q to quit anyway, Escape to keep playing")
+                .color(Color::from_rgba(255, 255, 255, 230))
+                .scale(24.),
+        );
+        let dims = text.dimensions(ctx).unwrap();
+        let (sc_width, sc_height) = ctx.gfx.size();
+        let dest = centered_in_window(dims.w, dims.h, sc_width, sc_height);
+        canvas.draw(&text, DrawParam::new().dest(dest));
+        Ok(())
+    }
+
+    fn draw_disconnected(&self, canvas: &mut Canvas) -> GameResult {
+        if !self.connection_lost {
+            return Ok(());
+        }
+        let text = Text::new(
+            TextFragment::new("Connection to the opponent was lost")
+                .color(Color::from_rgb(255, 80, 80))
+                .scale(20.),
+        );
+        canvas.draw(&text, DrawParam::new().dest(Vec2::new(10., 70.)));
+        Ok(())
+    }
+
+    fn draw_draw_hint(&self, canvas: &mut Canvas) -> GameResult {
+        if !self.draw_hint_active() {
+            return Ok(());
+        }
+        let text = if self.dead_position() {
+            "Neither side can win anymore, offer a draw? (D to dismiss)".to_owned()
+        } else {
+            format!(
+                "{} moves without a capture or pawn move, offer a draw? (D to dismiss)",
+                halfmove_clock(&self.history) / 2
+            )
+        };
+        let text = Text::new(
+            TextFragment::new(text)
+                .color(Color::from_rgba(255, 255, 255, 200))
+                .scale(20.),
+        );
+        canvas.draw(&text, DrawParam::new().dest(Vec2::new(10., 10.)));
+        Ok(())
+    }
+
+    /// The chat log down the left side, with the message being typed under
+    /// it.
+    fn draw_chat(&self, canvas: &mut Canvas) -> GameResult {
+        if self.chat_log.is_empty() && self.chat_input.is_none() {
+            return Ok(());
+        }
+        let mut lines: Vec<String> = self
+            .chat_log
+            .iter()
+            .map(|(sender, text)| format!("{}: {}", sender, text))
+            .collect();
+        match &self.chat_input {
+            Some(input) => lines.push(format!("> {}_", input)),
+            None if self.clean_view.is_some() => return Ok(()),
+            None => lines.push("(Tab to chat)".to_owned()),
+        }
+        let text = Text::new(
+            TextFragment::new(lines.join("\n"))
+                .color(Color::from_rgba(255, 255, 255, 220))
+                .scale(16.),
+        );
+        canvas.draw(&text, DrawParam::new().dest(Vec2::new(10., 110.)));
+        Ok(())
+    }
+
+    fn draw_errors(&self, canvas: &mut Canvas) -> GameResult {
+        if !self.show_errors {
+            return Ok(());
+        }
+        let mut lines = vec!["Errors (E to hide):".to_owned()];
+        if self.errors.is_empty() {
+            lines.push("none".to_owned());
+        }
+        lines.extend(self.errors.iter().cloned());
+        for (i, line) in lines.into_iter().enumerate() {
+            let text = Text::new(
+                TextFragment::new(line)
+                    .color(Color::from_rgb(255, 120, 120))
+                    .scale(16.),
+            );
+            let dest = Vec2::new(10., 100. + i as f32 * 20.);
+            canvas.draw(&text, DrawParam::new().dest(dest));
+        }
+        Ok(())
+    }
+
+    fn draw_prompt(&self, ctx: &mut Context, canvas: &mut Canvas) -> GameResult {
+        if let Some(text) = &self.text_prompt {
+            let dims = text.dimensions(ctx).unwrap();
+            let (sc_width, sc_height) = ctx.gfx.size();
+            let dest = centered_in_window(dims.w, dims.h, sc_width, sc_height);
+            canvas.draw(text, DrawParam::new().dest(dest));
+        }
+        Ok(())
+    }
+
+    fn update_camera(&mut self) {
+        if self.input.key(KeyCode::Key0) {
+            self.camera = Camera::default();
+        }
+        if self.input.held(MouseButton::Middle) {
+            let delta = self.input.mouse_delta;
+            let (sc_width, sc_height) = self.input.window;
+            let scale = WIDTH / board_viewport(sc_width, sc_height).w;
+            self.camera.pan(Vec2::new(delta.x, delta.y) * scale);
+        }
+    }
+
+    /// Cursor position in view space.
+    fn view_cursor_position(&self) -> Vec2 {
+        let pos = self.input.cursor;
+        let (sc_width, sc_height) = self.input.window;
+        to_view_space(pos.x, pos.y, sc_width, sc_height)
+    }
+
+    /// Cursor position in board space.
+    fn cursor_position(&self) -> Vec2 {
+        self.camera.to_board(self.view_cursor_position())
+    }
+
+    /// The square under the cursor, in board coordinates.
+    fn hovered_square(&self) -> Option<(u8, u8)> {
+        let pos = self.input.cursor;
+        let (sc_width, sc_height) = self.input.window;
+        let mut square = get_board_coordinate(pos.x, pos.y, sc_width, sc_height, self.camera)?;
+        if self.should_reverse() {
+            square.1 = BOARD_SQUARES as u8 - 1 - square.1;
+        }
+        Some(square)
+    }
+
+    /// The legal move from the selected square to `target`, if there is one.
+    /// Clicking a rook the selected king can castle with counts as that
+    /// castling move.
+    fn selected_move_to(&self, target: (u8, u8)) -> Option<Move> {
+        let moves = self.get_moves()?;
+        if let Some(mv) = moves
+            .iter()
+            .find(|mv| (mv.to.x as u8, mv.to.y as u8) == target)
+        {
+            return Some(mv.clone());
+        }
+        let selected = self.selected_square?;
+        let at = |(x, y): (u8, u8)| {
+            piece_at(
+                &self.board,
+                Position {
+                    x: x as usize,
+                    y: y as usize,
+                },
+            )
+        };
+        let king = at(selected)?;
+        let rook = at(target)?;
+        if !matches!(king.0, PieceType::King)
+            || !matches!(rook.0, PieceType::Rook)
+            || king.1 != rook.1
+        {
+            return None;
+        }
+        let toward_rook = target.0 > selected.0;
+        moves
+            .iter()
+            .find(|mv| {
+                mv.to.y as u8 == target.1
+                    && mv.from.x.abs_diff(mv.to.x) == 2
+                    && (mv.to.x > mv.from.x) == toward_rook
+            })
+            .cloned()
+    }
+
+    fn client_move(&mut self) -> GameResult<()> {
+        if !self.player_handler.can_move(self.board.turn) {
+            if self.input.clicked(MouseButton::Left) {
+                self.waiting_notice = Some(Instant::now());
+            }
+            return Ok(());
+        }
+        if self.unconfirmed_move.is_some() {
+            self.update_confirmation();
+            return Ok(());
+        }
+        if self.drag.is_some() {
+            return self.update_drag();
+        }
+        if !self.input.clicked(MouseButton::Left) {
+            return Ok(());
+        }
+        let clicked = if let Some(coords) = self.hovered_square() {
+            coords
+        } else {
+            return Ok(());
+        };
+        if let Some(current) = self.selected_square {
+            if current == clicked {
+                self.selected_square = None;
+                return Ok(());
+            }
+            let clicks = self.config.input_scheme.clicks();
+            if let Some(mv) = self.selected_move_to(clicked).filter(|_| clicks) {
+                self.commit_move(mv);
+                return Ok(());
+            }
+            if self.config.deselect_on_miss {
+                self.selected_square = None;
+                return Ok(());
+            }
+        }
+        self.selected_square = Some(clicked);
+        // Pressing on a piece that can move also picks it up, so it can be
+        // dropped on its target instead of clicked there.
+        if self.config.input_scheme.drags()
+            && self.get_moves().is_some_and(|moves| !moves.is_empty())
+        {
+            self.drag = Some(Drag {
+                from: clicked,
+                cursor: self.cursor_position(),
+                pressed_at: Instant::now(),
+            });
+        }
+        Ok(())
+    }
+
+    fn update_drag(&mut self) -> GameResult<()> {
+        let cursor = self.cursor_position();
+        let (from, pressed_at) = if let Some(drag) = &mut self.drag {
+            drag.cursor = cursor;
+            (drag.from, drag.pressed_at)
+        } else {
+            return Ok(());
+        };
+        if self.input.key(KeyCode::Escape) || self.input.clicked(MouseButton::Right) {
+            self.drag = None;
+            self.selected_square = None;
+            return Ok(());
+        }
+        if !self.input.released(MouseButton::Left) {
+            return Ok(());
+        }
+        self.drag = None;
+        let hovered = self.hovered_square();
+        if self.config.long_press_preview
+            && hovered == Some(from)
+            && is_long_press(pressed_at, Instant::now())
+        {
+            // The moves were shown while it was held, that was all.
+            self.selected_square = None;
+            return Ok(());
+        }
+        self.dropped_move = true;
+        // Dropping back on the starting square leaves the piece selected for
+        // a regular click-click move, if those are allowed.
+        let target = hovered.filter(|&target| target != from);
+        if let Some(mv) = target.and_then(|target| self.selected_move_to(target)) {
+            self.commit_move(mv);
+        } else if !self.config.input_scheme.clicks() {
+            self.selected_square = None;
+        }
+        Ok(())
+    }
+
+    /// Plays `mv`, or holds it back for confirmation if the confirm mode
+    /// asks for that.
+    fn commit_move(&mut self, mv: Move) {
+        if self.config.confirm_moves.requires(&self.board, &mv) {
+            self.unconfirmed_move = Some(mv);
+        } else {
+            self.phase = Phase::Validate(MoveKind::Builtin(mv));
+        }
+    }
+
+    fn update_confirmation(&mut self) {
+        if self.input.key(KeyCode::Return) {
+            if let Some(mv) = self.unconfirmed_move.take() {
+                self.phase = Phase::Validate(MoveKind::Builtin(mv));
+            }
+        } else if self.input.key(KeyCode::Escape)
+            || self.input.clicked(MouseButton::Left)
+            || self.input.clicked(MouseButton::Right)
+        {
+            self.unconfirmed_move = None;
+            self.selected_square = None;
+            self.dropped_move = false;
+        }
+    }
+
+    fn check_connection(&mut self) {
+        if let Some(network) = &self.player_handler.network {
+            network.reject_extra_connections();
+        }
+        let disconnected = self
+            .player_handler
+            .network
+            .as_ref()
+            .is_some_and(Network::is_disconnected);
+        if disconnected && !self.connection_lost {
+            self.connection_lost = true;
+            self.log_error("Connection to the opponent was lost");
+        }
+    }
+
+    /// Keys that switch display options or act outside the game itself.
+    fn handle_toggle_keys(&mut self) {
+        if self.input.key(KeyCode::C) {
+            self.config.coordinates = self.config.coordinates.toggled();
+        }
+        if self.player_handler.both_local() && self.input.key(KeyCode::S) {
+            self.sides_swapped = !self.sides_swapped;
+        }
+        if self.input.key(KeyCode::V) {
+            self.overlays.piece_values = !self.overlays.piece_values;
+        }
+        if self.input.key(KeyCode::P) {
+            self.export_pgn();
+        }
+        if self.input.key(KeyCode::H) {
+            self.toggle_clean_view();
+        }
+        if self.input.key(KeyCode::F) {
+            self.copy_fen();
+        }
+        if self.input.key(KeyCode::M) {
+            self.copy_moves();
+        }
+        if self.input.key(KeyCode::I) {
+            self.import_pgn();
+        }
+        if self.input.key(KeyCode::J) && self.your_move {
+            self.jump_to_move();
+        }
+        if self.input.key(KeyCode::E) {
+            self.show_errors = !self.show_errors;
+        }
+        if self.input.key(KeyCode::T) {
+            self.config.theme = self.config.theme.next();
+        }
+    }
+
+    /// Remakes what the board is drawn with for the current theme.
+    fn retheme(&mut self, ctx: &mut Context) -> GameResult {
+        if let Some(graphics) = &mut self.graphics {
+            (
+                graphics.board_texture,
+                graphics.move_to_dot,
+                graphics.border,
+            ) = themed_graphics(ctx, &self.config)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the game is paused after this update's key presses.
+    fn update_pause(&mut self) -> bool {
+        let can_pause = self.player_handler.network.is_none()
+            && self.playback.is_none()
+            && matches!(self.phase, Phase::Move);
+        if can_pause && self.input.key(KeyCode::Space) {
+            self.paused = !self.paused;
+            self.drag = None;
+            self.selected_square = None;
+        }
+        self.paused
+    }
+
+    /// This update's part of whichever phase the game is in.
+    fn handle_phase(&mut self) -> GameResult {
+        match &self.phase {
+            Phase::Move if self.paused || self.quit_prompt => Ok(()),
+            Phase::Move => self.handle_move_phase(),
+            Phase::Validate(mv) => self.handle_validate_phase(mv.clone()),
+            Phase::End(_) => self.handle_end_phase(),
+        }
+    }
+
+    fn handle_move_phase(&mut self) -> GameResult {
+        self.network_move()?;
+        self.practice_move();
+        self.handle_retract_key();
+        // Nobody moves in a replay, so clicks there are left to review.
+        let replaying = matches!(self.player_handler.game_type, GameType::Replay(_));
+        if self.review.is_none() && !self.typing && !replaying && matches!(self.phase, Phase::Move)
+        {
+            self.client_move()?;
+        }
+        Ok(())
+    }
+
+    /// Plays the saved game's reply on the recorded side's turn. Once the
+    /// game has left the recorded line, the user plays both sides.
+    fn practice_move(&mut self) {
+        let line = if let Some(line) = &self.practice_line {
+            line
+        } else {
+            return;
+        };
+        let turn = self.board.turn;
+        let moves_current =
+            self.current_moves.is_some() && self.moves_key == position_key(&self.history);
+        if self.player_handler.can_move(turn) || !moves_current {
+            return;
+        }
+        let plies = self.history.len();
+        let on_line = plies < line.len()
+            && self
+                .history
+                .iter()
+                .zip(line)
+                .all(|(played, spec)| played.spec().to_uci() == spec.to_uci());
+        let moves = self.current_moves.as_ref().unwrap();
+        let reply = line.get(plies).filter(|_| on_line).and_then(|spec| {
+            moves[move_index(spec.from.x, spec.from.y)]
+                .iter()
+                .find(|mv| mv.to.x == spec.to.x && mv.to.y == spec.to.y)
+                .cloned()
+        });
+        if let Some(mv) = reply {
+            self.phase = Phase::Validate(MoveKind::Builtin(mv));
+        } else {
+            logging::info("Left the recorded game, both sides are played here now");
+            self.practice_line = None;
+            self.player_handler.set_local(turn, true);
+        }
+    }
+
+    fn handle_validate_phase(&mut self, mv: MoveKind) -> GameResult {
+        self.client_validate(mv)
+    }
+
+    /// Shows the result, then starts a new game on Space or once the
+    /// auto-reset delay runs out.
+    fn handle_end_phase(&mut self) -> GameResult {
+        let (message, explanation) = if let Phase::End(outcome) = &self.phase {
+            (outcome.message(), outcome.explanation())
+        } else {
+            return Ok(());
+        };
+        let mut text = Text::new(
+            TextFragment::new(message)
+                .color(Color::from_rgb(255, 0, 0))
+                .scale(64.),
+        );
+        if let Some(explanation) = explanation {
+            text.add(
+                TextFragment::new(format!("\n{}", explanation))
+                    .color(Color::from_rgb(255, 0, 0))
+                    .scale(28.),
+            );
+        }
+        let summary = self.scoreboard.summary(
+            &self.player_name(ChessColor::White),
+            &self.player_name(ChessColor::Black),
+        );
+        text.add(
+            TextFragment::new(format!("\n{}", summary))
+                .color(Color::from_rgb(255, 0, 0))
+                .scale(28.),
+        );
+        let ended_at = *self.ended_at.get_or_insert_with(Instant::now);
+        let mut reset = !self.typing && self.input.key(KeyCode::Space);
+        if let Some(delay) = self.config.auto_reset {
+            let left = delay.saturating_sub(ended_at.elapsed());
+            text.add(
+                TextFragment::new(format!("\nNew game in {}s", left.as_secs_f32().ceil()))
+                    .color(Color::from_rgb(255, 0, 0))
+                    .scale(64.),
+            );
+            reset |= left.is_zero();
+        }
+        self.text_prompt = Some(text);
+        if reset {
+            self.reset_game();
+        }
+        Ok(())
+    }
+
+    /// N starts a new local game, asking first if that would throw away a
+    /// game still in progress.
+    fn handle_new_game_keys(&mut self) {
+        if self.confirm_discard {
+            if self.input.key(KeyCode::Y) {
+                self.confirm_discard = false;
+                let _ = std::fs::remove_file(&self.config.recovery_file);
+                self.reset_game();
+            } else if self.input.key(KeyCode::N) || self.input.key(KeyCode::Escape) {
+                self.confirm_discard = false;
+            }
+            return;
+        }
+        if self.player_handler.network.is_some() || !self.input.key(KeyCode::N) {
+            return;
+        }
+        if self.history.is_empty() || matches!(self.phase, Phase::End(_)) {
+            self.reset_game();
+        } else {
+            self.confirm_discard = true;
+        }
+    }
+
+    fn receive_draw_offer(&mut self) {
+        let sender = self.player_name(opponent(self.player_handler.one_local().unwrap()));
+        logging::info(format!("{} offers a draw", sender));
+        self.draw_offer = true;
+    }
+
+    /// Y accepts the opponent's draw offer and N turns it down. Moving
+    /// turns it down too.
+    fn handle_draw_offer(&mut self) {
+        if !self.draw_offer || !matches!(self.phase, Phase::Move) {
+            return;
+        }
+        if self.input.key(KeyCode::Y) {
+            self.send_packet(PacketType::Ack(Ack {
+                ok: true,
+                end_state: Some(GameState::Draw),
+            }));
+            self.end_game(GameOutcome::Draw(DrawReason::Agreed));
+        } else if self.input.key(KeyCode::N) || self.input.key(KeyCode::Escape) {
+            self.draw_offer = false;
+        }
+    }
+
+    /// Whether closing the window should be held back to ask first, showing
+    /// the quit prompt if so.
+    fn intercept_quit(&mut self) -> bool {
+        let in_progress = matches!(self.player_handler.game_type, GameType::Local)
+            && !self.history.is_empty()
+            && !matches!(self.phase, Phase::End(_));
+        if self.config.confirm_quit && in_progress && !self.quitting {
+            self.quit_prompt = true;
+            return true;
+        }
+        false
+    }
+
+    /// S saves the game to the recovery file and quits, Q quits without
+    /// saving, Escape keeps playing.
+    fn handle_quit_prompt(&mut self, ctx: &mut Context) {
+        if self.input.key(KeyCode::S) {
+            if let Err(err) = save_moves(&self.config.recovery_file, &self.history) {
+                self.log_error(format!("Failed to write recovery file: {}", err));
+                return;
+            }
+            self.autosaved_moves = self.history.len();
+        } else if self.input.key(KeyCode::Escape) {
+            self.quit_prompt = false;
+            return;
+        } else if !self.input.key(KeyCode::Q) {
+            return;
+        }
+        self.quit_prompt = false;
+        self.quitting = true;
+        ctx.request_quit();
+    }
+
+    /// Writes the game so far to `PGN_FILE`.
+    fn export_pgn(&mut self) {
+        let outcome = if let Phase::End(outcome) = &self.phase {
+            Some(outcome)
+        } else {
+            None
+        };
+        let white = self.player_name(ChessColor::White);
+        let black = self.player_name(ChessColor::Black);
+        let result = match pgn::export(&self.history, outcome, &white, &black) {
+            Some(text) => std::fs::write(PGN_FILE, text),
+            None => {
+                self.log_error("The game doesn't replay, so it can't be exported");
+                return;
+            }
+        };
+        match result {
+            Ok(()) => logging::info(format!("Saved the game to {}", PGN_FILE)),
+            Err(err) => self.log_error(format!("Failed to write {}: {}", PGN_FILE, err)),
+        }
+    }
+
+    fn toggle_clean_view(&mut self) {
+        if let Some(saved) = self.clean_view.take() {
+            self.overlays = saved.overlays;
+            self.config.hover_moves = saved.hover_moves;
+            self.config.square_tooltips = saved.square_tooltips;
+            self.config.minimap = saved.minimap;
+            self.show_errors = saved.show_errors;
+        } else {
+            self.clean_view = Some(ViewSnapshot {
+                overlays: std::mem::take(&mut self.overlays),
+                hover_moves: std::mem::take(&mut self.config.hover_moves),
+                square_tooltips: std::mem::take(&mut self.config.square_tooltips),
+                minimap: std::mem::take(&mut self.config.minimap),
+                show_errors: std::mem::take(&mut self.show_errors),
+            });
+        }
+    }
+
+    fn copy_to_clipboard(&mut self, text: String) {
+        let result = match &mut self.clipboard {
+            Some(clipboard) => clipboard.set_text(text),
+            None => arboard::Clipboard::new().and_then(|mut clipboard| {
+                let result = clipboard.set_text(text);
+                self.clipboard = Some(clipboard);
+                result
+            }),
+        };
+        if let Err(err) = result {
+            self.log_error(format!("Failed to copy to the clipboard: {}", err));
+        }
+    }
+
+    /// The FEN of the position on screen, which is an earlier one while
+    /// reviewing.
+    fn displayed_fen(&self) -> String {
+        let plies = self
+            .review
+            .as_ref()
+            .map_or(self.history.len(), |review| review.index);
+        fen::position_fen(self.displayed_board(), &self.history[..plies])
+    }
+
+    fn copy_fen(&mut self) {
+        let fen = self.displayed_fen();
+        logging::info(format!("Copied {}", fen));
+        self.copy_to_clipboard(fen);
+    }
+
+    /// Copies the moves played so far, numbered and in SAN.
+    fn copy_moves(&mut self) {
+        match pgn::san_moves(&self.history) {
+            Some(san) => {
+                let moves = pgn::numbered_moves(&san);
+                logging::info(format!("Copied {}", moves));
+                self.copy_to_clipboard(moves);
+            }
+            None => self.log_error("The game doesn't replay, so its moves can't be copied"),
+        }
+    }
+
+    /// Replaces a local game that hasn't started or is over with the PGN on
+    /// the clipboard, shown from its first move.
+    fn import_pgn(&mut self) {
+        let replaceable = self.history.is_empty() || matches!(self.phase, Phase::End(_));
+        if !matches!(self.player_handler.game_type, GameType::Local) || !replaceable {
+            self.log_error("Games can only be imported over a finished or unstarted local game");
+            return;
+        }
+        let text = match &mut self.clipboard {
+            Some(clipboard) => clipboard.get_text(),
+            None => arboard::Clipboard::new().and_then(|mut clipboard| {
+                let text = clipboard.get_text();
+                self.clipboard = Some(clipboard);
+                text
+            }),
+        };
+        let text = match text {
+            Ok(text) => text,
+            Err(err) => {
+                self.log_error(format!("Failed to read the clipboard: {}", err));
+                return;
+            }
+        };
+        self.load_pgn(&text);
+    }
+
+    /// Replaces the game with the one in `text`, shown from its first move.
+    fn load_pgn(&mut self, text: &str) {
+        let moves = match pgn::parse_moves(text) {
+            Ok(moves) => moves,
+            Err(err) => {
+                self.log_error(format!("Clipboard doesn't hold a PGN game: {}", err));
+                return;
+            }
+        };
+        let (board, history) = match replay_moves(&moves) {
+            Some(game) => game,
+            None => {
+                self.log_error("Clipboard's PGN game doesn't replay from the starting position");
+                return;
+            }
+        };
+        self.reset_game();
+        logging::info(format!("Imported a game of {} plies", history.len()));
+        self.board = board;
+        self.move_times = vec![Duration::ZERO; history.len()];
+        self.history = history;
+        self.set_review(Some(0));
+    }
+
+    /// Starts a new game from the starting position.
+    fn reset_game(&mut self) {
+        self.board = Chess::new();
+        self.selected_square = None;
+        self.drag = None;
+        self.unconfirmed_move = None;
+        self.animation = None;
+        self.review = None;
+        self.text_prompt = None;
+        self.history.clear();
+        self.move_times.clear();
+        self.game_start = Instant::now();
+        self.autosaved_moves = 0;
+        self.ended_at = None;
+        self.awaiting_reply = false;
+        self.retract_pending = false;
+        self.your_move = false;
+        self.draw_offer = false;
+        self.last_move_flash = None;
+        self.phase = Phase::Move;
+        if let Some(network) = &mut self.player_handler.network {
+            let players = network.init(self.config.name.clone());
+            self.player_handler.set_players(players);
+        }
+        if let GameType::Practice { moves_file, user } = &self.player_handler.game_type {
+            self.practice_line = load_moves(moves_file);
+            let recorded = opponent(*user);
+            self.player_handler.set_local(recorded, false);
+        }
+    }
+
+    fn send_packet(&mut self, packet: PacketType) {
+        if let Some(network) = &mut self.player_handler.network {
+            network.send_packet(packet);
+        }
+    }
+
+    /// Takes back the last `plies` moves by replaying the rest of the game.
+    fn take_back(&mut self, plies: usize) {
+        let keep = self.history.len().saturating_sub(plies);
+        let specs: Vec<MoveSpec> = self.history[..keep].iter().map(PlayedMove::spec).collect();
+        if let Some((board, history)) = replay_moves(&specs) {
+            self.board = board;
+            self.history = history;
+        }
+        self.move_times.truncate(self.history.len());
+        self.update_your_move();
+        self.selected_square = None;
+        self.drag = None;
+        self.unconfirmed_move = None;
+        self.animation = None;
+        self.review = None;
+        self.phase = Phase::Move;
+    }
+
+    /// Q, R, B or N changes what the last move promoted to while the
+    /// promotion is still editable. Returns whether it was, so the same key
+    /// press isn't taken for anything else.
+    fn handle_promotion_edit(&mut self) -> bool {
+        let (promoted_at, plies) = if let Some(edit) = self.promotion_edit {
+            edit
+        } else {
+            return false;
+        };
+        if promoted_at.elapsed() > PROMOTION_EDIT_WINDOW
+            || plies != self.history.len()
+            || !matches!(self.phase, Phase::Move)
+        {
+            self.promotion_edit = None;
+            return false;
+        }
+        let piece = [
+            (KeyCode::Q, PieceType::Queen),
+            (KeyCode::R, PieceType::Rook),
+            (KeyCode::B, PieceType::Bishop),
+            (KeyCode::N, PieceType::Knight),
+        ]
+        .into_iter()
+        .find(|&(key, _)| self.input.key(key))
+        .map(|(_, piece)| piece);
+        if let Some(piece) = piece {
+            self.promotion_edit = None;
+            if let Some(last) = self.history.last_mut() {
+                last.promotion = Some(piece);
+            }
+            self.take_back(0);
+            // The recovery file may already have the old promotion.
+            self.autosaved_moves = self.autosaved_moves.min(plies - 1);
+            if let Some(outcome) = GameOutcome::from_position(&mut self.board) {
+                self.end_game(outcome);
+            }
+        }
+        true
+    }
+
+    /// Tab opens the chat box in network games, Enter sends the message and
+    /// Escape throws it away. Returns whether the keyboard went to the chat
+    /// box.
+    fn update_chat(&mut self) -> bool {
+        let input = if let Some(input) = &mut self.chat_input {
+            input
+        } else {
+            if self.player_handler.network.is_some() && self.input.key(KeyCode::Tab) {
+                self.chat_input = Some(String::new());
+                return true;
+            }
+            return false;
+        };
+        if self.input.key(KeyCode::Back) {
+            input.pop();
+        } else if self.input.key(KeyCode::Escape) {
+            self.chat_input = None;
+        } else if self.input.key(KeyCode::Return) {
+            let text = self.chat_input.take().unwrap();
+            if !text.trim().is_empty() {
+                let sender = self.player_name(self.player_handler.one_local().unwrap());
+                self.send_packet(PacketType::Control(Control::Chat { text: text.clone() }));
+                self.push_chat(sender, text);
+            }
+        }
+        true
+    }
+
+    fn push_chat(&mut self, sender: String, text: String) {
+        if self.chat_log.len() == MAX_CHAT_LINES {
+            self.chat_log.pop_front();
+        }
+        self.chat_log.push_back((sender, text));
+    }
+
+    fn handle_retract_key(&mut self) {
+        if self.awaiting_reply && !self.typing && self.input.key(KeyCode::U) {
+            self.awaiting_reply = false;
+            self.retract_pending = true;
+            self.send_packet(PacketType::Control(Control::Retract));
+        }
+    }
+
+    fn network_move(&mut self) -> GameResult<()> {
+        let packet = if let Some(packet) = self
+            .player_handler
+            .network
+            .as_mut()
+            .and_then(Network::get_packet)
+        {
+            packet
+        } else {
+            return Ok(());
+        };
+        logging::debug(format!("Received {:?}", packet));
+        let our_turn = self.player_handler.one_local() == Some(self.board.turn);
+        match packet {
+            PacketType::Move(mv) if mv.forfeit => {
+                let winner = self.player_handler.one_local().unwrap();
+                logging::info(format!("{} resigned", self.player_name(opponent(winner))));
+                self.end_game(GameOutcome::OpponentResigned { winner });
+            }
+            PacketType::Move(mv) if !our_turn => {
+                self.awaiting_reply = false;
+                if mv.offer_draw {
+                    self.receive_draw_offer();
+                }
+                self.phase = Phase::Validate(MoveKind::Network(mv));
+            }
+            // An offer on its own, while we think over our move.
+            PacketType::Move(mv) if mv.offer_draw => self.receive_draw_offer(),
+            PacketType::Ack(Ack {
+                end_state: Some(end_state),
+                ..
+            }) => {
+                // The opponent saw our last move end the game.
+                let mover = opponent(self.board.turn);
+                let outcome = GameOutcome::from_end_state(&end_state, mover, &mut self.board);
+                self.end_game(outcome);
+            }
+            PacketType::Control(Control::Retract) => {
+                // Only honoured while their move is the last one played.
+                let accepted = our_turn && !self.history.is_empty();
+                if accepted {
+                    self.take_back(1);
+                }
+                self.send_packet(PacketType::Control(Control::RetractReply { accepted }));
+            }
+            PacketType::Control(Control::RetractReply { accepted }) => {
+                if accepted && self.retract_pending {
+                    self.take_back(1);
+                }
+                self.retract_pending = false;
+            }
+            PacketType::Control(Control::Chat { text }) => {
+                let sender = self.player_name(opponent(self.player_handler.one_local().unwrap()));
+                let text = text.chars().take(MAX_CHAT_MESSAGE).collect();
+                self.push_chat(sender, text);
+            }
+            PacketType::Ack(_) => {}
+            packet => logging::warn(format!("Ignoring unexpected {:?}", packet)),
+        }
+        Ok(())
+    }
+
+    fn client_validate(&mut self, mv: MoveKind) -> GameResult<()> {
+        let current_turn = self.board.turn;
+        let dropped = std::mem::take(&mut self.dropped_move);
+        let played = PlayedMove::new(
+            &self.board,
+            MoveSpec {
+                from: mv.from(),
+                to: mv.to(),
+                promotion: None,
+            },
+        );
+        // En passant takes the pawn beside the target square.
+        let captured_at = if piece_at(&self.board, mv.to()).is_some() {
+            mv.to()
+        } else {
+            Position {
+                x: mv.to().x,
+                y: mv.from().y,
+            }
+        };
+        let result = self.board.move_piece(mv.from(), mv.to());
+        match result {
+            ValidationResult::Valid(mut status) => {
+                let mut played = played.expect("Valid move from an empty square");
+                if self.board.status == Status::AwaitingPromotion {
+                    if let Some((piece, promoted)) = self.promote_or_reject(&mv) {
+                        status = promoted;
+                        played.promotion = Some(piece);
+                    } else {
+                        return Ok(());
+                    }
+                }
+                self.events.push(GameEvent::MoveApplied {
+                    played,
+                    mover: current_turn,
+                });
+                let local_mover = self.player_handler.players.get_player(current_turn).local;
+                if !(self.config.opponent_sounds_only && local_mover) {
+                    self.queued_sound = Some(played.piece);
+                }
+                self.history.push(played);
+                self.move_times.push(self.game_start.elapsed());
+                if !dropped && self.config.animations {
+                    let captured = played
+                        .captured
+                        .filter(|_| self.config.capture_fade)
+                        .map(|piece| (piece, opponent(current_turn), captured_at));
+                    self.animation = Some(Animation {
+                        from: played.from,
+                        to: played.to,
+                        start: Instant::now(),
+                        captured,
+                    });
+                }
+                let outcome = GameOutcome::from_status(&status, &mut self.board);
+                let end_state = outcome.as_ref().and_then(GameOutcome::end_state);
+                if let Some(outcome) = outcome {
+                    self.end_game(outcome);
+                    // The game is over, nothing left to recover.
+                    let _ = std::fs::remove_file(&self.config.recovery_file);
+                    self.autosaved_moves = self.history.len();
+                } else {
+                    self.phase = Phase::Move;
+                }
+                // Practice replies are builtin moves too, but not ours to change.
+                let editable = self.config.promotion_undo
+                    && self.player_handler.network.is_none()
+                    && matches!(mv, MoveKind::Builtin(_))
+                    && local_mover
+                    && matches!(self.phase, Phase::Move);
+                self.promotion_edit = self
+                    .history
+                    .last()
+                    .filter(|played| editable && played.promotion.is_some())
+                    .map(|_| (Instant::now(), self.history.len()));
+                self.selected_square = None;
+                let one_local = self.player_handler.one_local();
+                if let Some(network) = &mut self.player_handler.network {
+                    if one_local == Some(current_turn) {
+                        // Nothing to take back once the move ended the game.
+                        self.awaiting_reply = matches!(self.phase, Phase::Move);
+                        let packet = PacketType::Move(chess_networking::Move {
+                            from: (mv.from().x as u8, mv.from().y as u8),
+                            to: (mv.to().x as u8, mv.to().y as u8),
+                            promotion: Some(self.config.promotion.clone()),
+                            forfeit: false,
+                            offer_draw: false,
+                        });
+                        network.send_packet(packet);
+                    } else {
+                        let ack = Ack {
+                            ok: true,
+                            end_state,
+                        };
+                        let packet = PacketType::Ack(ack);
+                        network.send_packet(packet);
+                    }
+                }
+            }
+            _ => {
+                let from = (mv.from().x, mv.from().y);
+                let to = (mv.to().x, mv.to().y);
+                let reason =
+                    analysis::illegal_reason(&analysis::grid(&self.board), from, to, current_turn);
+                self.log_error(format!(
+                    "Rejected {}{}: {}",
+                    square_name(mv.from()),
+                    square_name(mv.to()),
+                    reason
+                ));
+                self.reject_move();
+            }
+        }
+        Ok(())
+    }
+
+    fn end_game(&mut self, outcome: GameOutcome) {
+        self.awaiting_reply = false;
+        self.retract_pending = false;
+        self.draw_offer = false;
+        self.phase = Phase::End(outcome);
+        self.events.push(GameEvent::GameEnded);
+    }
+
+    fn game_snapshot(&self) -> GameSnapshot {
+        let phase = match &self.phase {
+            Phase::Move => "moving".to_owned(),
+            Phase::Validate(_) => "validating".to_owned(),
+            Phase::End(outcome) => outcome.message().to_owned(),
+        };
+        GameSnapshot {
+            fen: fen::position_fen(&self.board, &self.history),
+            turn: self.board.turn,
+            phase,
+            white: self.player_name(ChessColor::White),
+            black: self.player_name(ChessColor::Black),
+            last_move: self.history.last().map(|played| played.spec().to_uci()),
+        }
+    }
+
+    fn handle_events(&mut self) {
+        if !self.events.is_empty() {
+            self.pacer.activity(Instant::now());
+        }
+        for event in std::mem::take(&mut self.events) {
+            match event {
+                GameEvent::MoveApplied { played, mover } => {
+                    if self.config.announce_moves {
+                        println!("{}", played.announcement(mover));
+                    }
+                    logging::info(format!("Played {}", played.spec().to_uci()));
+                    if self.player_handler.one_local() == Some(mover) {
+                        self.draw_offer = false;
+                    }
+                    self.update_your_move();
+                    if logging::enabled(logging::Level::Debug) {
+                        logging::debug(self.game_snapshot().to_string());
+                    }
+                }
+                GameEvent::MoveRejected => logging::warn("Rejected an illegal move"),
+                GameEvent::GameEnded => {
+                    self.your_move = false;
+                    if let Phase::End(outcome) = &self.phase {
+                        logging::info(outcome.message());
+                        self.scoreboard.record(outcome);
+                    }
+                    self.save_replay();
+                }
+            }
+        }
+    }
+
+    fn reject_move(&mut self) {
+        self.events.push(GameEvent::MoveRejected);
+        if let Some(network) = &mut self.player_handler.network {
+            let ack = Ack {
+                ok: false,
+                end_state: None,
+            };
+            let packet = PacketType::Ack(ack);
+            network.send_packet(packet);
+        }
+        self.phase = Phase::Move;
+        self.selected_square = None;
+    }
+
+    /// Promotes the pawn `mv` just moved. If the board won't, the move is
+    /// rejected and `None` returned.
+    fn promote_or_reject(&mut self, mv: &MoveKind) -> Option<(PieceType, Status)> {
+        let piece = mv.promotion(&self.config.promotion);
+        if let Some(status) = self.board.promote_piece(piece) {
+            return Some((piece, status));
+        }
+        // The pawn has already moved, so rebuild the position from before
+        // it.
+        self.log_error(format!(
+            "Promotion on {} failed, rejecting the move",
+            square_name(mv.to())
+        ));
+        self.take_back(0);
+        self.reject_move();
+        None
+    }
+
+    /// Everything in an update after the phase handlers that needs no
+    /// input: events, the legal move cache, animations and autosave.
+    fn settle(&mut self) {
+        self.handle_events();
+
+        let key = position_key(&self.history);
+        if self.current_moves.is_none() || self.moves_key != key {
+            self.current_moves = Some(self.board.generate_valid_moves());
+            self.moves_key = key;
+            self.repetitions = repetitions(&self.history);
+        }
+        if self
+            .animation
+            .as_ref()
+            .is_some_and(|animation| animation.start.elapsed() >= self.config.animation_duration)
+        {
+            self.animation = None;
+        }
+        self.autosave();
+    }
+
+    fn should_reverse(&self) -> bool {
+        if self.player_handler.both_local() {
+            let winner = match &self.phase {
+                Phase::End(outcome) if self.config.flip_to_winner => outcome.winner(),
+                _ => None,
+            };
+            if let Some(winner) = winner {
+                return winner == ChessColor::White;
+            }
+            return (self.board.turn == ChessColor::White) != self.sides_swapped;
+        }
+        self.player_handler
+            .one_local()
+            .is_some_and(|color| color == ChessColor::White)
+    }
+}
+
+impl event::EventHandler<ggez::GameError> for MainState {
+    fn update(&mut self, ctx: &mut Context) -> GameResult {
+        let now = Instant::now();
+        if self.animation.is_some() || !ctx.keyboard.pressed_keys().is_empty() {
+            self.pacer.activity(now);
+        }
+        std::thread::sleep(self.pacer.wait(now));
+        self.pacer.frame(Instant::now());
+        self.input = Input::read(ctx);
+        self.typing = self.update_chat();
+        if self.quit_prompt {
+            self.handle_quit_prompt(ctx);
+        } else if !self.typing && !self.update_pause() {
+            self.handle_restore_prompt();
+            self.handle_draw_offer();
+            if !self.handle_promotion_edit() {
+                self.handle_new_game_keys();
+            }
+            self.handle_review_keys();
+            self.update_playback(ctx.time.delta());
+            self.update_draw_hint();
+            self.update_camera();
+            let theme = self.config.theme;
+            self.handle_toggle_keys();
+            if self.config.theme != theme {
+                self.retheme(ctx)?;
+            }
+        }
+        self.check_connection();
+        self.handle_phase()?;
+        if let Some(piece) = self.queued_sound.take() {
+            self.move_sounds.play(ctx, piece)?;
+        }
+        self.settle();
+        Ok(())
+    }
+
+    /// Holds the window open to ask first if `--confirm-quit` is set and a
+    /// local game is in progress.
+    fn quit_event(&mut self, _ctx: &mut Context) -> Result<bool, ggez::GameError> {
+        Ok(self.intercept_quit())
+    }
+
+    fn mouse_motion_event(
+        &mut self,
+        _ctx: &mut Context,
+        _x: f32,
+        _y: f32,
+        _dx: f32,
+        _dy: f32,
+    ) -> GameResult {
+        self.pacer.activity(Instant::now());
+        Ok(())
+    }
+
+    fn mouse_button_down_event(
+        &mut self,
+        _ctx: &mut Context,
+        _button: MouseButton,
+        _x: f32,
+        _y: f32,
+    ) -> GameResult {
+        self.pacer.activity(Instant::now());
+        Ok(())
+    }
+
+    fn mouse_button_up_event(
+        &mut self,
+        _ctx: &mut Context,
+        _button: MouseButton,
+        _x: f32,
+        _y: f32,
+    ) -> GameResult {
+        self.pacer.activity(Instant::now());
+        Ok(())
+    }
+
+    fn resize_event(&mut self, _ctx: &mut Context, _width: f32, _height: f32) -> GameResult {
+        self.pacer.activity(Instant::now());
+        Ok(())
+    }
+
+    fn text_input_event(&mut self, _ctx: &mut Context, character: char) -> GameResult {
+        self.pacer.activity(Instant::now());
+        if let Some(input) = &mut self.chat_input {
+            if !character.is_control() && input.chars().count() < MAX_CHAT_MESSAGE {
+                input.push(character);
+            }
+        }
+        Ok(())
+    }
+
+    fn mouse_wheel_event(&mut self, ctx: &mut Context, _x: f32, y: f32) -> GameResult {
+        self.pacer.activity(Instant::now());
+        let pos = ctx.mouse.position();
+        let (sc_width, sc_height) = ctx.gfx.size();
+        let anchor = to_view_space(pos.x, pos.y, sc_width, sc_height);
+        self.camera.zoom_at(anchor, 1.25f32.powf(y));
+        Ok(())
+    }
+
+    fn draw(&mut self, ctx: &mut Context) -> GameResult {
+        let mut canvas =
+            graphics::Canvas::from_frame(ctx, graphics::Color::from([0.1, 0.2, 0.3, 1.0]));
+        // The board is drawn through the camera, letterboxed in the middle
+        // of the window; text and other overlays go on top unzoomed.
+        let (sc_width, sc_height) = ctx.gfx.size();
+        let view = view_screen_coordinates(sc_width, sc_height);
+        canvas.set_screen_coordinates(self.camera.visible(view));
+        let reverse = self.should_reverse();
+        let offset = if reverse { 800. } else { 0. };
+        let scale = Vec2::new(1.0, if reverse { -1.0 } else { 1.0 });
+        let dest = Vec2::new(0., offset);
+        let draw_params = DrawParam::new().scale(scale).dest(dest);
+        canvas.draw(&self.graphics().board_texture, draw_params);
+        canvas.draw(
+            &self.graphics().border,
+            DrawParam::new().color(turn_border_color(self.board.turn)),
+        );
+
+        self.draw_coordinates(&mut canvas)?;
+        self.draw_pieces(&mut canvas)?;
+        self.draw_overlays(&mut canvas)?;
+        self.draw_hover_moves(&mut canvas)?;
+        self.draw_square_control(&mut canvas)?;
+        self.draw_discovered_checks(ctx, &mut canvas)?;
+        self.draw_last_move_arrow(ctx, &mut canvas)?;
+        self.draw_last_move_flash(ctx, &mut canvas)?;
+        self.draw_selected(&mut canvas)?;
+
+        canvas.set_screen_coordinates(view);
+        self.draw_draw_hint(&mut canvas)?;
+        self.draw_retract_hint(&mut canvas)?;
+        self.draw_promotion_edit(&mut canvas)?;
+        self.draw_waiting_notice(&mut canvas)?;
+        self.draw_your_move(ctx, &mut canvas)?;
+        self.draw_confirm_prompt(&mut canvas)?;
+        self.draw_disconnected(&mut canvas)?;
+        self.draw_discard_prompt(&mut canvas)?;
+        self.draw_draw_offer(&mut canvas)?;
+        self.draw_review_status(&mut canvas)?;
+        self.draw_player_labels(ctx, &mut canvas)?;
+        self.draw_move_number(ctx, &mut canvas)?;
+        self.draw_draw_watch(ctx, &mut canvas)?;
+        self.draw_minimap(ctx, &mut canvas)?;
+        self.draw_chat(&mut canvas)?;
+        self.draw_errors(&mut canvas)?;
+        self.draw_square_tooltip(&mut canvas)?;
+        self.draw_prompt(ctx, &mut canvas)?;
+        self.draw_paused(ctx, &mut canvas)?;
+        self.draw_quit_prompt(ctx, &mut canvas)?;
+
+        canvas.finish(ctx)?;
+
+        Ok(())
+    }
+}
+
+fn setting_value<'a>(values: &mut impl Iterator<Item = &'a str>) -> Result<&'a str, String> {
+    values.next().ok_or_else(|| "missing value".to_owned())
+}
+
+/// `value` as a number, refused unless it falls within `range`.
+fn parse_in_range<T>(value: &str, range: RangeInclusive<T>) -> Result<T, String>
+where
+    T: FromStr + PartialOrd + fmt::Display,
+{
+    let number: T = value
+        .parse()
+        .map_err(|_| format!("'{}' is not a number", value))?;
+    if range.contains(&number) {
+        Ok(number)
+    } else {
+        Err(format!(
+            "{} is not between {} and {}",
+            number,
+            range.start(),
+            range.end()
+        ))
+    }
+}
+
+/// A color written `r,g,b`, each part from 0 to 255.
+fn parse_color(value: &str) -> Result<Color, String> {
+    let parts = value
+        .split(',')
+        .map(|part| parse_in_range(part.trim(), 0..=255))
+        .collect::<Result<Vec<u32>, _>>()?;
+    match parts[..] {
+        [r, g, b] => Ok(Color::from_rgb(r as u8, g as u8, b as u8)),
+        _ => Err(format!("'{}' is not a color like 238,238,210", value)),
+    }
+}
+
+/// Calls `connect` until it succeeds, at most `retries` more times after the
+/// first failure, waiting twice as long after each, and returns the last
+/// error if it never does. Connecting happens before the window shows
+/// anything, so retries are reported on stderr.
+fn connect_with_retries<T>(
+    host: &str,
+    retries: u32,
+    first_delay: Duration,
+    mut connect: impl FnMut(&str) -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut delay = first_delay;
+    let mut attempt = 1;
+    loop {
+        match connect(host) {
+            Ok(stream) => return Ok(stream),
+            Err(err) if attempt <= retries => {
+                attempt += 1;
+                logging::info(format!("Failed to connect to {}: {}", host, err));
+                eprintln!("connecting... (attempt {})", attempt);
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(Duration::from_secs(8));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// How long to wait so that a frame `elapsed` after the previous one
+/// doesn't go over `max_fps`.
+fn frame_wait(elapsed: Duration, max_fps: u32) -> Duration {
+    (Duration::from_secs(1) / max_fps).saturating_sub(elapsed)
+}
+
+/// Spaces frames out to `--max-fps`, and to `IDLE_FPS` once nothing has
+/// happened for `IDLE_AFTER`. ggez presents a frame every loop whether or
+/// not anything was drawn, so an idle board is redrawn less often rather
+/// than not at all.
+struct FramePacer {
+    max_fps: Option<u32>,
+    last_frame: Instant,
+    last_activity: Instant,
+}
+
+impl FramePacer {
+    fn new(max_fps: Option<u32>, now: Instant) -> Self {
+        Self {
+            max_fps,
+            last_frame: now,
+            last_activity: now,
+        }
+    }
+
+    /// Input, a packet or an animation: keep drawing at full speed.
+    fn activity(&mut self, now: Instant) {
+        self.last_activity = now;
+    }
+
+    /// How long to wait at `now` before starting the next frame.
+    fn wait(&self, now: Instant) -> Duration {
+        let idle = now.saturating_duration_since(self.last_activity) >= IDLE_AFTER;
+        let fps = if idle {
+            Some(
+                self.max_fps
+                    .map_or(IDLE_FPS, |max_fps| max_fps.min(IDLE_FPS)),
+            )
+        } else {
+            self.max_fps
+        };
+        fps.map_or(Duration::ZERO, |fps| {
+            frame_wait(now.saturating_duration_since(self.last_frame), fps)
+        })
+    }
+
+    /// A frame started at `now`.
+    fn frame(&mut self, now: Instant) {
+        self.last_frame = now;
+    }
+}
+
+/// ggez only supports 1 and 4 samples, so other counts are rounded down to
+/// one of those.
+fn num_samples(samples: u32) -> NumSamples {
+    if samples >= 4 {
+        NumSamples::Four
+    } else {
+        NumSamples::One
+    }
+}
+
+/// A line of the config file that couldn't be used.
+#[derive(Debug, PartialEq, Eq)]
+struct ConfigError {
+    line: usize,
+    field: String,
+    reason: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.line, self.field, self.reason)
+    }
+}
+
+/// The game type and config set by the config file at `path`, on top of
+/// the defaults. A missing file sets nothing.
+fn load_config(path: &str) -> Result<(GameType, Config), ConfigError> {
+    let contents = std::fs::read_to_string(path).unwrap_or_default();
+    parse_config(&contents)
+}
+
+/// Reads settings with the names of the command line flags, one per line
+/// without the leading dashes: `host = 0.0.0.0:3000`, `show-pins`. Blank
+/// lines and lines starting with `#` are skipped.
+fn parse_config(contents: &str) -> Result<(GameType, Config), ConfigError> {
+    let mut game_type = GameType::Local;
+    let mut config = Config::default();
+    for (i, line) in contents.lines().map(str::trim).enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), Some(value.trim())),
+            None => (line, None),
+        };
+        let error = |reason: &str| ConfigError {
+            line: i + 1,
+            field: key.to_owned(),
+            reason: reason.to_owned(),
+        };
+        if key.is_empty() || key.starts_with('-') || key.contains(char::is_whitespace) {
+            return Err(error("not a setting name"));
+        }
+        let mut values = value
+            .filter(|value| !value.is_empty())
+            .into_iter()
+            .peekable();
+        apply_setting(key, &mut values, &mut game_type, &mut config)
+            .map_err(|reason| error(&reason))?;
+        if values.next().is_some() {
+            return Err(error("takes no value"));
+        }
+    }
+    Ok((game_type, config))
+}
+
+/// The address last used for `role`, "host" or "client".
+fn last_address(path: &str, role: &str) -> Option<String> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    contents.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == role).then(|| value.trim().to_owned())
+    })
+}
+
+fn save_last_address(path: &str, role: &str, address: &str) {
+    let contents = std::fs::read_to_string(path).unwrap_or_default();
+    let mut lines: Vec<String> = contents
+        .lines()
+        .filter(|line| line.split_once('=').map(|(key, _)| key.trim()) != Some(role))
+        .map(str::to_owned)
+        .collect();
+    lines.push(format!("{} = {}", role, address));
+    if let Err(err) = std::fs::write(path, lines.join("\n") + "\n") {
+        logging::warn(format!("Failed to write {}: {}", path, err));
+    }
+}
+
+/// Applies the setting `name`, a flag without its leading dashes, taking
+/// the values it needs from `values`.
+fn apply_setting<'a, I: Iterator<Item = &'a str>>(
+    name: &str,
+    values: &mut Peekable<I>,
+    game_type: &mut GameType,
+    config: &mut Config,
+) -> Result<(), String> {
+    match name {
+        "local" => *game_type = GameType::Local,
+        "host" | "client" => {
+            let address = values
+                .next_if(|arg| !arg.starts_with("--"))
+                .map(str::to_owned)
+                .or_else(|| last_address(&config.last_address_file, name))
+                .unwrap_or_else(|| "localhost:3000".to_owned());
+            *game_type = if name == "host" {
+                GameType::Host(address)
+            } else {
+                GameType::Client(address)
+            };
+        }
+        "stdio" => {
+            let host = match setting_value(values)? {
+                "host" => true,
+                "client" => false,
+                value => return Err(format!("'{}' is not host or client", value)),
+            };
+            *game_type = GameType::Stdio { host };
+        }
+        "practice" => {
+            let moves_file = setting_value(values)?.to_owned();
+            let user = match values.next_if(|arg| !arg.starts_with("--")) {
+                None | Some("white") => ChessColor::White,
+                Some("black") => ChessColor::Black,
+                Some(side) => return Err(format!("'{}' is not white or black", side)),
+            };
+            *game_type = GameType::Practice { moves_file, user };
+        }
+        "replay" => *game_type = GameType::Replay(setting_value(values)?.to_owned()),
+        "record" => config.record_file = Some(setting_value(values)?.to_owned()),
+        "name" => config.name = Some(setting_value(values)?.to_owned()),
+        "websocket" => config.websocket = true,
+        "show-pins" => config.overlays.pins = true,
+        "show-back-rank" => config.overlays.back_rank = true,
+        "show-values" => config.overlays.piece_values = true,
+        "show-discovered-checks" => config.overlays.discovered_checks = true,
+        "last-move-arrow" => config.overlays.last_move_arrow = true,
+        "hover-threats" => config.overlays.hover_threats = true,
+        "flip-to-winner" => config.flip_to_winner = true,
+        "capture-fade" => config.capture_fade = true,
+        "selftest" => config.self_test = true,
+        "announce" => config.announce_moves = true,
+        "hover-moves" => config.hover_moves = true,
+        "deselect-on-miss" => config.deselect_on_miss = true,
+        "promotion-undo" => config.promotion_undo = true,
+        "long-press-preview" => config.long_press_preview = true,
+        "square-names" => config.square_tooltips = true,
+        "minimap" => config.minimap = true,
+        "no-vsync" => config.vsync = false,
+        "max-fps" => config.max_fps = Some(parse_in_range(setting_value(values)?, 1..=1000)?),
+        "msaa" => config.samples = num_samples(parse_in_range(setting_value(values)?, 1..=16)?),
+        "promote-to" => {
+            config.promotion = match setting_value(values)? {
+                "queen" => PromotionPiece::Queen,
+                "rook" => PromotionPiece::Rook,
+                "bishop" => PromotionPiece::Bishop,
+                "knight" => PromotionPiece::Knight,
+                value => return Err(format!("'{}' is not a promotion piece", value)),
+            };
+        }
+        "input" => {
+            let value = setting_value(values)?;
+            config.input_scheme = InputScheme::from_name(value)
+                .ok_or_else(|| format!("'{}' is not an input scheme", value))?;
+        }
+        "confirm-moves" => {
+            let value = setting_value(values)?;
+            config.confirm_moves = ConfirmMode::from_name(value)
+                .ok_or_else(|| format!("'{}' is not a confirm mode", value))?;
+        }
+        "palette" => {
+            let value = setting_value(values)?;
+            config.palette =
+                Palette::from_name(value).ok_or_else(|| format!("'{}' is not a palette", value))?;
+        }
+        "theme" => {
+            let value = setting_value(values)?;
+            config.theme =
+                Theme::from_name(value).ok_or_else(|| format!("'{}' is not a theme", value))?;
+        }
+        "coordinates-in-squares" => config.coordinates_in_squares = true,
+        "coordinates" => {
+            let value = setting_value(values)?;
+            config.coordinates = CoordinateStyle::from_name(value)
+                .ok_or_else(|| format!("'{}' is not a coordinate style", value))?;
+        }
+        "no-animations" => config.animations = false,
+        "easing" => {
+            let value = setting_value(values)?;
+            config.animation_easing =
+                Easing::from_name(value).ok_or_else(|| format!("'{}' is not an easing", value))?;
+        }
+        "light-square" => config.light_square_image = Some(setting_value(values)?.to_owned()),
+        "dark-square" => config.dark_square_image = Some(setting_value(values)?.to_owned()),
+        "light-color" => config.light_square_color = Some(parse_color(setting_value(values)?)?),
+        "dark-color" => config.dark_square_color = Some(parse_color(setting_value(values)?)?),
+        "autosave-moves" => {
+            config.autosave_moves = parse_in_range(setting_value(values)?, 0..=1000)?;
+        }
+        "draw-hint-halfmoves" => {
+            config.draw_hint_halfmoves = parse_in_range(setting_value(values)?, 0..=150)?;
+        }
+        "read-buffer" => {
+            config.network.read_buffer = parse_in_range(setting_value(values)?, 1..=1 << 20)?;
+        }
+        "connect-retries" => {
+            config.network.connect_retries = parse_in_range(setting_value(values)?, 0..=20)?;
+        }
+        "sim-latency" => {
+            let millis = parse_in_range(setting_value(values)?, 0..=10_000)?;
+            config.network.sim_latency = Duration::from_millis(millis);
+        }
+        "max-queued" => {
+            config.network.max_queued = parse_in_range(setting_value(values)?, 1..=1 << 16)?;
+        }
+        "max-packet" => {
+            config.network.max_packet = parse_in_range(setting_value(values)?, 1..=16 << 20)?;
+        }
+        "move-sound" => config.move_sound = Some(setting_value(values)?.to_owned()),
+        "piece-sound" => {
+            let value = setting_value(values)?;
+            let (piece, path) = value
+                .split_once('=')
+                .ok_or_else(|| format!("'{}' is not <piece>=<file>", value))?;
+            let piece =
+                parse_piece_name(piece).ok_or_else(|| format!("'{}' is not a piece", piece))?;
+            config.piece_sounds[piece as usize] = Some(path.to_owned());
+        }
+        "opponent-sounds-only" => config.opponent_sounds_only = true,
+        "log-level" => {
+            let value = setting_value(values)?;
+            config.log_level = logging::Level::from_name(value)
+                .ok_or_else(|| format!("'{}' is not a log level", value))?;
+        }
+        "log-file" => config.log_file = Some(setting_value(values)?.to_owned()),
+        "auto-reset-secs" => {
+            let secs = parse_in_range(setting_value(values)?, 1..=3600)?;
+            config.auto_reset = Some(Duration::from_secs(secs));
+        }
+        "confirm-quit" => config.confirm_quit = true,
+        "autosave-secs" => {
+            let secs = parse_in_range(setting_value(values)?, 1..=3600)?;
+            config.autosave_interval = Duration::from_secs(secs);
+        }
+        _ => return Err("unknown setting".to_owned()),
+    }
+    Ok(())
+}
+
+/// Applies the command line flags on top of `game_type` and `config`, which
+/// come from the config file.
+fn parse_args(
+    cli_flags: &[String],
+    mut game_type: GameType,
+    mut config: Config,
+) -> (GameType, Config) {
+    let mut flags = cli_flags.iter().skip(1).map(String::as_str).peekable();
+    while let Some(flag) = flags.next() {
+        let name = flag
+            .strip_prefix("--")
+            .unwrap_or_else(|| panic!("Invalid flag {}", flag));
+        if let Err(reason) = apply_setting(name, &mut flags, &mut game_type, &mut config) {
+            panic!("{}: {}", flag, reason);
+        }
+    }
+    if config.announce_moves && matches!(game_type, GameType::Stdio { .. }) {
+        panic!("--announce needs stdout, which --stdio uses for packets");
+    }
+    (game_type, config)
+}
+
+/// Exit code when the window can't be created, e.g. on a headless machine.
+const NO_DISPLAY_EXIT_CODE: i32 = 3;
+
+/// Exit code when the game can't be set up, e.g. the opponent never connected.
+const STARTUP_ERROR_EXIT_CODE: i32 = 4;
+
+/// Logs `msg` and exits, for failures before the game has started.
+fn exit_with_error(msg: impl AsRef<str>) -> ! {
+    logging::error(msg);
+    std::process::exit(STARTUP_ERROR_EXIT_CODE);
+}
+
+/// What to tell the user when the window couldn't be opened because of
+/// `err`, before exiting with `NO_DISPLAY_EXIT_CODE`.
+fn no_window_message(err: &GameError) -> [String; 2] {
+    [
+        format!("Couldn't open a window: {}", err),
+        "No display or graphics driver seems to be available. \
+         Check DISPLAY/WAYLAND_DISPLAY, or run --selftest to check the game without one"
+            .to_owned(),
+    ]
+}
+
+pub fn main() -> GameResult {
+    // The config file goes first so the command line overrides it.
+    let (game_type, config) =
+        load_config(CONFIG_FILE).unwrap_or_else(|err| panic!("{}:{}", CONFIG_FILE, err));
+    let cli_flags = std::env::args().collect::<Vec<_>>();
+    let (game_type, config) = parse_args(&cli_flags, game_type, config);
+    logging::init(config.log_level, config.log_file.as_deref()).expect("Failed to open log file");
+    if config.self_test {
+        std::process::exit(self_test());
+    }
+
+    let title = match game_type {
+        GameType::Local => "Chess",
+        GameType::Practice { .. } => "Chess Practice",
+        GameType::Replay(_) => "Chess Replay",
+        GameType::Host(_) | GameType::Stdio { host: true } => "Chess Host",
+        GameType::Client(_) | GameType::Stdio { host: false } => "Chess Client",
+    };
+
+    let cb = ggez::ContextBuilder::new("Chess GUI", "Dexter WS")
+        .window_mode(
+            WindowMode::default()
+                .dimensions(WIDTH, HEIGHT)
+                .min_dimensions(WIDTH / 4., HEIGHT / 4.)
+                .resizable(true),
+        )
+        .window_setup(
+            ggez::conf::WindowSetup::default()
+                .title(title)
+                .samples(config.samples)
+                .vsync(config.vsync),
+        );
+    let (mut ctx, event_loop) = match cb.build() {
+        Ok(built) => built,
+        Err(err) => {
+            for line in no_window_message(&err) {
+                logging::error(line);
+            }
+            std::process::exit(NO_DISPLAY_EXIT_CODE);
+        }
+    };
+
+    let state = MainState::new(&mut ctx, game_type, config)?;
+    // Only remembered once the connection has been made.
+    let path = &state.config.last_address_file;
+    match &state.player_handler.game_type {
+        GameType::Host(address) => save_last_address(path, "host", address),
+        GameType::Client(address) => save_last_address(path, "client", address),
+        _ => {}
+    }
+    // Earlier panics are bad flags, not crashes.
+    install_panic_hook(close_on_panic);
+    event::run(ctx, event_loop, state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One direction of an in-memory connection: the bytes written and not
+    /// yet read, and whether it has been closed.
+    #[derive(Default)]
+    struct Pipe {
+        state: Mutex<(VecDeque<u8>, bool)>,
+        ready: std::sync::Condvar,
+    }
+
+    impl Pipe {
+        fn close(&self) {
+            self.state.lock().unwrap().1 = true;
+            self.ready.notify_all();
+        }
+    }
+
+    /// A connection that never leaves the process, for driving two
+    /// `Network`s against each other.
+    struct MemoryTransport {
+        incoming: Arc<Pipe>,
+        outgoing: Arc<Pipe>,
+    }
+
+    fn memory_pair() -> (MemoryTransport, MemoryTransport) {
+        let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+        let first = MemoryTransport {
+            incoming: a.clone(),
+            outgoing: b.clone(),
+        };
+        let second = MemoryTransport {
+            incoming: b,
+            outgoing: a,
+        };
+        (first, second)
+    }
+
+    impl Read for MemoryTransport {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let mut state = self.incoming.state.lock().unwrap();
+            while state.0.is_empty() && !state.1 {
+                state = self.incoming.ready.wait(state).unwrap();
+            }
+            let size = buf.len().min(state.0.len());
+            for (byte, read) in buf.iter_mut().zip(state.0.drain(..size)) {
+                *byte = read;
+            }
+            Ok(size)
+        }
+    }
+
+    impl Write for MemoryTransport {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut state = self.outgoing.state.lock().unwrap();
+            if state.1 {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            state.0.extend(buf);
+            self.outgoing.ready.notify_all();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for MemoryTransport {
+        fn try_clone(&self) -> std::io::Result<Box<dyn Transport>> {
+            Ok(Box::new(MemoryTransport {
+                incoming: self.incoming.clone(),
+                outgoing: self.outgoing.clone(),
+            }))
+        }
+
+        fn shutdown(&mut self) {
+            self.incoming.close();
+            self.outgoing.close();
+        }
+    }
+
+    /// Held by tests that open a `Network` or touch `OPEN_CONNECTION`,
+    /// since there is only one of it.
+    static CONNECTION_TESTS: Mutex<()> = Mutex::new(());
+
+    fn connection_lock() -> std::sync::MutexGuard<'static, ()> {
+        CONNECTION_TESTS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    #[test]
+    fn panic_hook_runs_the_cleanup() {
+        static CLEANED_UP: AtomicBool = AtomicBool::new(false);
+        install_panic_hook(|| CLEANED_UP.store(true, Ordering::Relaxed));
+        let result = std::panic::catch_unwind(|| panic!("simulated crash"));
+        let _ = std::panic::take_hook();
+        assert!(result.is_err());
+        assert!(CLEANED_UP.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn panic_cleanup_closes_the_connection() {
+        let _lock = connection_lock();
+        let (ours, mut theirs) = memory_pair();
+        register_connection(&ours);
+        close_on_panic();
+        // The opponent's end sees the connection close.
+        assert_eq!(theirs.read(&mut [0u8; 8]).unwrap(), 0);
+        assert!(!close_open_connection());
+    }
+
+    impl MainState {
+        /// A game with no window, so nothing is drawn.
+        fn headless(game_type: GameType, mut config: Config) -> MainState {
+            config.recovery_file = temp_path("recovery");
+            let mut state =
+                MainState::with_resources(None, MoveSounds::default(), game_type, config);
+            state.pending_restore = None;
+            state.text_prompt = None;
+            state.settle();
+            state
+        }
+
+        /// Plays `uci` as if it had been entered on the board, and finishes
+        /// the update.
+        fn play(&mut self, uci: &str) {
+            let mv = MoveSpec::from_uci(uci).unwrap();
+            self.client_validate(MoveKind::Builtin(Move {
+                from: mv.from,
+                to: mv.to,
+            }))
+            .unwrap();
+            self.settle();
+        }
+
+        /// Presses the left button over `square` and runs the move input.
+        fn left_click(&mut self, square: &str) {
+            self.input = Input {
+                cursor: on_screen(square),
+                clicked: HashSet::from([MouseButton::Left]),
+                held: HashSet::from([MouseButton::Left]),
+                ..Input::default()
+            };
+            self.client_move().unwrap();
+        }
+
+        /// Presses and lets go over `square`, in two updates.
+        fn click(&mut self, square: &str) {
+            self.left_click(square);
+            self.left_release(square);
+        }
+
+        /// Lets go of the left button over `square` and runs the move input.
+        fn left_release(&mut self, square: &str) {
+            self.input = Input {
+                cursor: on_screen(square),
+                released: HashSet::from([MouseButton::Left]),
+                ..Input::default()
+            };
+            self.client_move().unwrap();
+        }
+    }
+
+    impl MainState {
+        /// A game over `network`, past the handshake.
+        fn networked(game_type: GameType, mut network: Network, name: &str) -> MainState {
+            let players = network.init(Some(name.to_owned()));
+            MainState::joined(game_type, network, players, name)
+        }
+
+        /// A game over `network`, whose handshake gave `players`.
+        fn joined(
+            game_type: GameType,
+            network: Network,
+            players: Players,
+            name: &str,
+        ) -> MainState {
+            let config = Config {
+                name: Some(name.to_owned()),
+                ..Config::default()
+            };
+            let mut state = MainState::headless(GameType::Local, config);
+            state.player_handler.game_type = game_type;
+            state.player_handler.network = Some(network);
+            state.player_handler.set_players(players);
+            state
+        }
+
+        /// Waits for the next packet from the opponent and handles it the
+        /// way an update in the move phase would.
+        fn receive(&mut self) {
+            let network = self.player_handler.network.as_ref().unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while network.cache.read().unwrap().is_empty() {
+                assert!(Instant::now() < deadline, "Nothing arrived");
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            self.network_move().unwrap();
+            self.finish_move();
+        }
+
+        /// Handles packets until one of them is a move that gets played.
+        fn receive_move(&mut self) {
+            let plies = self.history.len();
+            while self.history.len() == plies {
+                self.receive();
+            }
+        }
+
+        /// Plays the move waiting in the validate phase, if there is one,
+        /// and finishes the update.
+        fn finish_move(&mut self) {
+            if let Phase::Validate(mv) = &self.phase {
+                let mv = mv.clone();
+                self.client_validate(mv).unwrap();
+            }
+            self.settle();
+        }
+    }
+
+    /// A hosted game playing White, and the bare client end of its
+    /// connection.
+    fn hosted_game() -> (MainState, Network) {
+        let (host_end, client_end) = memory_pair();
+        let client = std::thread::spawn(move || {
+            let mut client = Network::new(
+                NetworkType::Client,
+                Box::new(client_end),
+                NetworkSettings::default(),
+            );
+            client.init(Some("client".to_owned()));
+            client
+        });
+        let host = Network::new(
+            NetworkType::Host { listener: None },
+            Box::new(host_end),
+            NetworkSettings::default(),
+        );
+        let state = MainState::networked(GameType::Host("memory".to_owned()), host, "host");
+        (state, client.join().unwrap())
+    }
+
+    /// The middle of `square`, like "e2", in the window with White at the
+    /// bottom.
+    fn on_screen(square: &str) -> Vec2 {
+        let square = square.as_bytes();
+        let x = (square[0] - b'a') as f32;
+        let y = (square[1] - b'1') as f32;
+        Vec2::new((x + 0.5) * SQUARE_SIZE, HEIGHT - (y + 0.5) * SQUARE_SIZE)
+    }
+
+    fn just_pressed(key: KeyCode) -> Input {
+        Input {
+            keys: HashSet::from([key]),
+            ..Input::default()
+        }
+    }
+
+    /// A file name in the temp directory no other test uses.
+    fn temp_path(name: &str) -> String {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let file = format!("chess-test-{}-{}-{}", std::process::id(), name, n);
+        std::env::temp_dir()
+            .join(file)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn pieces_are_drawn_from_the_mover_side() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        let commands = state.piece_draw_commands();
+        assert_eq!(commands.len(), 32);
+        let white_king = PieceType::King as usize;
+        let black_king = white_king + 6;
+        let dest = |commands: &[(usize, Vec2)], texture| {
+            commands
+                .iter()
+                .find(|&&(idx, _)| idx == texture)
+                .map(|&(_, dest)| (dest.x, dest.y))
+                .unwrap()
+        };
+        // White moves first, so White is at the bottom.
+        assert_eq!(dest(&commands, white_king), (400., 700.));
+        assert_eq!(dest(&commands, black_king), (400., 0.));
+
+        let e2e4 = Move {
+            from: Position { x: 4, y: 1 },
+            to: Position { x: 4, y: 3 },
+        };
+        state.client_validate(MoveKind::Builtin(e2e4)).unwrap();
+        let flipped = state.piece_draw_commands();
+        assert_eq!(dest(&flipped, white_king), (400., 0.));
+        assert_eq!(dest(&flipped, black_king), (400., 700.));
+    }
+
+    #[test]
+    fn one_pixel_square_images_match_solid_colors() {
+        let pattern = |pixel: [u8; 4]| SquareFill::Pattern {
+            width: 1,
+            height: 1,
+            pixels: pixel.to_vec(),
+        };
+        let patterned = board_pixels(
+            &pattern([240, 217, 181, 255]),
+            &pattern([181, 136, 99, 255]),
+        );
+        let solid = board_pixels(
+            &SquareFill::Solid(Color::from_rgb(240, 217, 181)),
+            &SquareFill::Solid(Color::from_rgb(181, 136, 99)),
+        );
+        assert!(patterned == solid);
+    }
+
+    #[test]
+    fn autosave_writes_the_recovery_file_every_few_moves() {
+        let config = Config {
+            autosave_moves: 3,
+            autosave_interval: Duration::from_secs(3600),
+            ..Config::default()
+        };
+        let mut state = MainState::headless(GameType::Local, config);
+        state.play("e2e4");
+        state.play("e7e5");
+        assert!(load_moves(&state.config.recovery_file).is_none());
+        state.play("g1f3");
+        let saved = load_moves(&state.config.recovery_file).unwrap();
+        assert_eq!(saved.len(), 3);
+        let _ = std::fs::remove_file(&state.config.recovery_file);
+    }
+
+    #[test]
+    fn unreadable_moves_files_say_what_is_wrong() {
+        let path = temp_path("bad-moves");
+        std::fs::write(&path, "e2e4\ne9e5\n").unwrap();
+        let err = read_moves(&path).err();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(err.as_deref(), Some("line 2: 'e9e5' is not a UCI move"));
+        assert!(read_moves(&path).is_err());
+    }
+
+    #[test]
+    fn recovery_file_from_the_config_is_offered_for_restore() {
+        let config = Config {
+            recovery_file: temp_path("restore"),
+            ..Config::default()
+        };
+        std::fs::write(&config.recovery_file, "e2e4\ne7e5\n").unwrap();
+        let state = MainState::with_resources(None, MoveSounds::default(), GameType::Local, config);
+        let _ = std::fs::remove_file(&state.config.recovery_file);
+        assert_eq!(state.pending_restore.map(|moves| moves.len()), Some(2));
+    }
+
+    #[test]
+    fn draw_hint_shows_once_the_halfmove_clock_passes_the_threshold() {
+        let config = Config {
+            draw_hint_halfmoves: 4,
+            ..Config::default()
+        };
+        let mut state = MainState::headless(GameType::Local, config);
+        for uci in ["g1f3", "g8f6", "f3g1", "f6g8"] {
+            state.play(uci);
+        }
+        assert!(!state.draw_hint_active());
+        state.play("b1c3");
+        assert!(state.draw_hint_active());
+        // A pawn move starts the count again.
+        state.play("e7e5");
+        assert!(!state.draw_hint_active());
+    }
+
+    #[test]
+    fn ack_reporting_checkmate_ends_the_game() {
+        let _lock = connection_lock();
+        let (mut host, mut client) = hosted_game();
+        host.play("e2e4");
+        assert!(matches!(client.get_packet_blocking(), PacketType::Move(_)));
+        client.send_packet(PacketType::Ack(Ack {
+            ok: true,
+            end_state: Some(GameState::CheckMate),
+        }));
+        host.receive();
+        assert!(matches!(
+            host.phase,
+            Phase::End(GameOutcome::Checkmate {
+                winner: ChessColor::White
+            })
+        ));
+    }
+
+    #[test]
+    fn border_is_tinted_for_the_side_to_move() {
+        assert_eq!(
+            turn_border_color(ChessColor::White).to_rgba(),
+            (255, 255, 255, 160)
+        );
+        assert_eq!(
+            turn_border_color(ChessColor::Black).to_rgba(),
+            (0, 0, 0, 160)
+        );
+    }
+
+    #[test]
+    fn stdio_transport_completes_the_handshake() {
+        let _lock = connection_lock();
+        let (host_end, client_end) = memory_pair();
+        // Separate handles, so the reader blocking on input doesn't hold up
+        // the output.
+        let stdio = |end: MemoryTransport| StdioTransport {
+            input: Arc::new(Mutex::new(MemoryTransport {
+                incoming: end.incoming.clone(),
+                outgoing: end.outgoing.clone(),
+            })),
+            output: Arc::new(Mutex::new(end)),
+        };
+        let (host_stdio, client_stdio) = (stdio(host_end), stdio(client_end));
+        let client = std::thread::spawn(move || {
+            let mut client = Network::new(
+                NetworkType::Client,
+                Box::new(client_stdio),
+                NetworkSettings::default(),
+            );
+            client.init(Some("client".to_owned()))
+        });
+        let mut host = Network::new(
+            NetworkType::Host { listener: None },
+            Box::new(host_stdio),
+            NetworkSettings::default(),
+        );
+        let host_players = host.init(Some("host".to_owned()));
+        let client_players = client.join().unwrap();
+        assert_eq!(host_players.black.name.as_deref(), Some("client"));
+        assert!(host_players.white.local && !host_players.black.local);
+        assert_eq!(client_players.white.name.as_deref(), Some("host"));
+        assert!(client_players.black.local && !client_players.white.local);
+    }
+
+    /// A plain move from `from` to `to`, as the opponent would send it.
+    fn network_move(from: (u8, u8), to: (u8, u8)) -> PacketType {
+        PacketType::Move(chess_networking::Move {
+            from,
+            to,
+            promotion: None,
+            forfeit: false,
+            offer_draw: false,
+        })
+    }
+
+    #[test]
+    fn memory_transport_carries_packets_both_ways() {
+        let _lock = connection_lock();
+        let (a, b) = memory_pair();
+        let mut host = Network::new(
+            NetworkType::Host { listener: None },
+            Box::new(a),
+            NetworkSettings::default(),
+        );
+        let mut client = Network::new(NetworkType::Client, Box::new(b), NetworkSettings::default());
+        host.send_packet(network_move((4, 1), (4, 3)));
+        match client.get_packet_blocking() {
+            PacketType::Move(mv) => assert_eq!((mv.from, mv.to), ((4, 1), (4, 3))),
+            packet => panic!("Expected a move, got {:?}", packet),
+        }
+        client.send_packet(PacketType::Ack(Ack {
+            ok: true,
+            end_state: None,
+        }));
+        match host.get_packet_blocking() {
+            PacketType::Ack(ack) => assert!(ack.ok),
+            packet => panic!("Expected an ack, got {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn escape_drops_a_dragged_piece_back() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        state.left_click("e2");
+        assert!(state.drag.is_some());
+        state.input = just_pressed(KeyCode::Escape);
+        state.client_move().unwrap();
+        assert!(state.drag.is_none() && state.selected_square.is_none());
+        assert!(matches!(state.phase, Phase::Move));
+        assert!(state.history.is_empty());
+        assert!(piece_at(&state.board, Position { x: 4, y: 1 }).is_some());
+    }
+
+    /// The next packet that isn't an automatic `Ack`.
+    fn next_reply(network: &mut Network) -> PacketType {
+        loop {
+            match network.get_packet_blocking() {
+                PacketType::Ack(_) => {}
+                packet => return packet,
+            }
+        }
+    }
+
+    #[test]
+    fn retract_before_our_reply_takes_the_move_back() {
+        let _lock = connection_lock();
+        let (mut host, mut client) = hosted_game();
+        host.play("e2e4");
+        next_reply(&mut client);
+        client.send_packet(network_move((4, 6), (4, 4)));
+        host.receive();
+        assert_eq!(host.history.len(), 2);
+        client.send_packet(PacketType::Control(Control::Retract));
+        host.receive();
+        assert_eq!(host.history.len(), 1);
+        assert!(host.board.turn == ChessColor::Black);
+        assert!(matches!(
+            next_reply(&mut client),
+            PacketType::Control(Control::RetractReply { accepted: true })
+        ));
+    }
+
+    #[test]
+    fn retract_after_our_reply_is_refused() {
+        let _lock = connection_lock();
+        let (mut host, mut client) = hosted_game();
+        host.play("e2e4");
+        next_reply(&mut client);
+        client.send_packet(network_move((4, 6), (4, 4)));
+        host.receive();
+        host.play("d2d4");
+        next_reply(&mut client);
+        client.send_packet(PacketType::Control(Control::Retract));
+        host.receive();
+        assert_eq!(host.history.len(), 3);
+        assert!(matches!(
+            next_reply(&mut client),
+            PacketType::Control(Control::RetractReply { accepted: false })
+        ));
+    }
+
+    #[test]
+    fn home_and_end_jump_to_the_ends_of_the_review() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        for uci in ["e2e4", "e7e5", "g1f3"] {
+            state.play(uci);
+        }
+        state.input = just_pressed(KeyCode::Home);
+        state.handle_review_keys();
+        assert_eq!(state.review.as_ref().map(|review| review.index), Some(0));
+        state.input = just_pressed(KeyCode::End);
+        state.handle_review_keys();
+        assert_eq!(state.review.as_ref().map(|review| review.index), Some(3));
+    }
+
+    #[test]
+    fn labels_put_the_local_white_player_at_the_bottom() {
+        let _lock = connection_lock();
+        let (host, _client) = hosted_game();
+        let [(bottom, bottom_label, bottom_y), (top, top_label, top_y)] = host.player_labels();
+        assert!(bottom == ChessColor::White && top == ChessColor::Black);
+        assert_eq!(
+            (bottom_label.as_str(), bottom_y),
+            ("host (you)", HEIGHT - 30.)
+        );
+        assert_eq!((top_label.as_str(), top_y), ("client (opponent)", 10.));
+    }
+
+    #[test]
+    fn square_labels_in_each_coordinate_style() {
+        let a1 = Position { x: 0, y: 0 };
+        assert_eq!(CoordinateStyle::Algebraic.square_label(a1), "a1");
+        assert_eq!(CoordinateStyle::Numeric.square_label(a1), "0,0");
+    }
+
+    #[test]
+    fn failed_promotion_rejects_the_move() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        state.play("e2e4");
+        // A pawn move the board made but won't promote, since it isn't
+        // awaiting a promotion.
+        let d7 = Position { x: 3, y: 6 };
+        let d5 = Position { x: 3, y: 4 };
+        state.board.move_piece(d7, d5);
+        let mv = MoveKind::Builtin(Move { from: d7, to: d5 });
+        assert!(state.promote_or_reject(&mv).is_none());
+        assert!(state.board.turn == ChessColor::Black);
+        assert!(piece_at(&state.board, d7).is_some() && piece_at(&state.board, d5).is_none());
+        assert_eq!(state.history.len(), 1);
+        assert!(matches!(state.phase, Phase::Move));
+        assert!(matches!(state.events.last(), Some(GameEvent::MoveRejected)));
+        assert!(state
+            .errors
+            .back()
+            .unwrap()
+            .contains("Promotion on d5 failed"));
+    }
+
+    #[test]
+    fn configured_host_mode_is_used_without_arguments() {
+        let (game_type, config) = parse_config("host = 0.0.0.0:4000\n").unwrap();
+        let (game_type, _) = parse_args(&["chess".to_owned()], game_type, config);
+        assert!(matches!(game_type, GameType::Host(address) if address == "0.0.0.0:4000"));
+    }
+
+    #[test]
+    fn en_passant_target_is_marked_in_review() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        for uci in ["e2e4", "e7e6", "e4e5", "d7d5"] {
+            state.play(uci);
+        }
+        state.set_review(Some(4));
+        // d6, with White at the bottom.
+        assert_eq!(state.en_passant_marker(), Some(Vec2::new(300., 200.)));
+        state.set_review(Some(3));
+        assert_eq!(state.en_passant_marker(), None);
+    }
+
+    /// RGBA of the top left pixel of square `x, y` of the board texture,
+    /// with the colors `config` picks.
+    fn texture_square(config: &Config, x: usize, y: usize) -> [u8; 4] {
+        let (light, dark) = square_colors(config);
+        let pixels = board_pixels(&SquareFill::Solid(light), &SquareFill::Solid(dark));
+        let size = SQUARE_SIZE as usize;
+        let i = (y * size * WIDTH as usize + x * size) * 4;
+        pixels[i..i + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn high_contrast_texture_is_black_and_white() {
+        let config = Config {
+            theme: Theme::HighContrast,
+            // Only the classic theme takes custom colors.
+            light_square_color: Some(Color::from_rgb(255, 0, 0)),
+            ..Config::default()
+        };
+        assert_eq!(texture_square(&config, 0, 0), [255, 255, 255, 255]);
+        assert_eq!(texture_square(&config, 1, 0), [0, 0, 0, 255]);
+    }
+
+    /// The moves of a game given in UCI, played from the start.
+    fn game(moves: &[&str]) -> (Chess, Vec<PlayedMove>) {
+        let specs: Vec<MoveSpec> = moves
+            .iter()
+            .map(|uci| MoveSpec::from_uci(uci).unwrap())
+            .collect();
+        replay_moves(&specs).unwrap()
+    }
+
+    #[test]
+    fn knight_capture_is_announced() {
+        let (_, history) = game(&["b1c3", "d7d5", "c3d5"]);
+        assert_eq!(
+            history[2].announcement(ChessColor::White),
+            "White knight takes pawn on d5"
+        );
+    }
+
+    #[test]
+    fn hovering_a_knight_previews_its_moves() {
+        let config = Config {
+            hover_moves: true,
+            ..Config::default()
+        };
+        let mut state = MainState::headless(GameType::Local, config);
+        state.input.cursor = on_screen("g1");
+        let mut targets: Vec<_> = state
+            .hover_preview()
+            .iter()
+            .map(|mv| (mv.to.x, mv.to.y))
+            .collect();
+        targets.sort();
+        assert_eq!(targets, [(5, 2), (7, 2)]);
+        assert!(state.selected_square.is_none());
+    }
+
+    #[test]
+    fn only_captures_wait_for_confirmation() {
+        let config = Config {
+            confirm_moves: ConfirmMode::Captures,
+            ..Config::default()
+        };
+        let mut state = MainState::headless(GameType::Local, config);
+        state.click("e2");
+        state.click("e4");
+        assert!(state.unconfirmed_move.is_none());
+        assert!(matches!(state.phase, Phase::Validate(_)));
+        state.finish_move();
+        state.play("d7d5");
+        state.click("e4");
+        state.click("d5");
+        assert!(state.unconfirmed_move.is_some());
+        assert!(matches!(state.phase, Phase::Move));
+    }
+
+    fn packet_bytes(packet: PacketType) -> Vec<u8> {
+        Vec::try_from(packet).unwrap()
+    }
+
+    fn chat(text: &str) -> PacketType {
+        PacketType::Control(Control::Chat {
+            text: text.to_owned(),
+        })
+    }
+
+    #[test]
+    fn oversized_frame_drops_the_connection() {
+        // bin 32 claiming 4 GiB, followed by a little of it.
+        let mut data = vec![0xc6, 0xff, 0xff, 0xff, 0xff];
+        data.extend_from_slice(&[0; 16]);
+        let cache = Arc::new(RwLock::new(VecDeque::new()));
+        let disconnected = Arc::new(AtomicBool::new(false));
+        Network::spawn_thread(
+            std::io::Cursor::new(data).chain(std::io::repeat(0)),
+            cache.clone(),
+            disconnected.clone(),
+            NetworkSettings::default(),
+        )
+        .join()
+        .unwrap();
+        assert!(disconnected.load(Ordering::Relaxed));
+        assert!(cache.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn packets_in_one_read_are_all_queued() {
+        let mut data = packet_bytes(network_move((4, 1), (4, 3)));
+        data.extend(packet_bytes(network_move((3, 6), (3, 4))));
+        let mut reader = PacketReader::new(NetworkSettings::default());
+        let mut cache = VecDeque::new();
+        reader.receive(&data, &mut cache).unwrap();
+        let moves: Vec<_> = cache
+            .into_iter()
+            .map(|packet| match packet {
+                PacketType::Move(mv) => (mv.from, mv.to),
+                packet => panic!("Unexpected {:?}", packet),
+            })
+            .collect();
+        assert_eq!(moves, [((4, 1), (4, 3)), ((3, 6), (3, 4))]);
+    }
+
+    fn start(is_white: bool, name: &str) -> PacketType {
+        PacketType::Start(Start {
+            is_white,
+            name: Some(name.to_owned()),
+            fen: None,
+            time: None,
+            inc: None,
+        })
+    }
+
+    #[test]
+    fn move_right_after_start_waits_for_the_handshake() {
+        let _lock = connection_lock();
+        let (ours, mut host) = memory_pair();
+        // A fast host: its Start and first move arrive in the same read,
+        // before the client has even sent its own Start.
+        let mut data = packet_bytes(start(true, "host"));
+        data.extend(packet_bytes(network_move((4, 1), (4, 3))));
+        host.write_all(&data).unwrap();
+
+        let mut client = Network::new(
+            NetworkType::Client,
+            Box::new(ours),
+            NetworkSettings::default(),
+        );
+        let players = client.init(Some("client".to_owned()));
+        assert!(players.white.name.as_deref() == Some("host") && !players.white.local);
+        assert!(players.black.local);
+        match client.get_packet_blocking() {
+            PacketType::Move(mv) => assert_eq!((mv.from, mv.to), ((4, 1), (4, 3))),
+            packet => panic!("Expected the host's move, got {:?}", packet),
+        }
+    }
+
+    fn ack() -> PacketType {
+        PacketType::Ack(Ack {
+            ok: true,
+            end_state: None,
+        })
+    }
+
+    #[test]
+    fn chat_flood_keeps_the_queue_bounded() {
+        let settings = NetworkSettings {
+            chat_per_second: u32::MAX,
+            ..NetworkSettings::default()
+        };
+        let mut reader = PacketReader::new(settings);
+        let mut cache = VecDeque::new();
+        for i in 0..settings.max_queued * 10 {
+            reader.queue(chat(&i.to_string()), &mut cache).unwrap();
+        }
+        assert_eq!(cache.len(), settings.max_queued);
+    }
+
+    #[test]
+    fn chat_over_the_rate_is_dropped_but_game_packets_are_not() {
+        let settings = NetworkSettings::default();
+        let mut reader = PacketReader::new(settings);
+        let mut cache = VecDeque::new();
+        for _ in 0..settings.chat_per_second * 4 {
+            reader.queue(chat("spam"), &mut cache).unwrap();
+            reader
+                .queue(PacketType::Control(Control::Retract), &mut cache)
+                .unwrap();
+        }
+        let chats = cache
+            .iter()
+            .filter(|packet| matches!(packet, PacketType::Control(Control::Chat { .. })))
+            .count();
+        assert_eq!(chats, settings.chat_per_second as usize);
+        assert_eq!(cache.len() - chats, settings.chat_per_second as usize * 4);
+    }
+
+    #[test]
+    fn full_queue_makes_room_for_game_packets() {
+        let settings = NetworkSettings {
+            max_queued: 4,
+            chat_per_second: u32::MAX,
+            ..NetworkSettings::default()
+        };
+        let mut reader = PacketReader::new(settings);
+        let mut cache = VecDeque::new();
+        for _ in 0..4 {
+            reader.queue(chat("filler"), &mut cache).unwrap();
+        }
+        for _ in 0..4 {
+            reader.queue(ack(), &mut cache).unwrap();
+        }
+        assert_eq!(cache.len(), 4);
+        assert!(cache
+            .iter()
+            .all(|packet| matches!(packet, PacketType::Ack(_))));
+        // Nothing left to push out, so the opponent is flooding the game.
+        assert!(reader.queue(ack(), &mut cache).is_err());
+        assert_eq!(cache.len(), 4);
+    }
+
+    #[test]
+    fn packet_split_over_reads_is_put_back_together() {
+        let data = packet_bytes(network_move((4, 1), (4, 3)));
+        let mut reader = PacketReader::new(NetworkSettings::default());
+        let mut cache = VecDeque::new();
+        let (first, second) = data.split_at(data.len() / 2);
+        reader.receive(first, &mut cache).unwrap();
+        assert!(cache.is_empty());
+        reader.receive(second, &mut cache).unwrap();
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn move_number_counts_full_moves() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        assert_eq!(state.move_number(), 1);
+        for uci in ["e2e4", "e7e5", "g1f3"] {
+            state.play(uci);
+        }
+        assert_eq!(state.move_number(), 2);
+        assert!(fen::position_fen(&state.board, &state.history).ends_with(" 2"));
+        state.set_review(Some(1));
+        assert_eq!(state.move_number(), 1);
+    }
+
+    #[test]
+    fn swapping_sides_flips_the_board_but_not_the_position() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        state.play("e2e4");
+        let before = fen::position_fen(&state.board, &state.history);
+        let bottom = |state: &MainState| state.player_labels()[0].1.clone();
+        assert!(!state.should_reverse());
+        assert_eq!(bottom(&state), "Black");
+        state.input = just_pressed(KeyCode::S);
+        state.handle_toggle_keys();
+        assert!(state.should_reverse());
+        assert_eq!(bottom(&state), "White");
+        assert_eq!(fen::position_fen(&state.board, &state.history), before);
+    }
+
+    #[test]
+    fn letterboxed_clicks_map_to_the_board_or_nothing() {
+        // 1200x600: the board is a 600 pixel square from x = 300.
+        let at = |x, y| get_board_coordinate(x, y, 1200., 600., Camera::default());
+        assert_eq!(at(150., 300.), None);
+        assert_eq!(at(1050., 300.), None);
+        assert_eq!(at(300.5, 0.5), Some((0, 0)));
+        assert_eq!(at(899.5, 599.5), Some((7, 7)));
+        assert_eq!(at(899.5, 0.5), Some((7, 0)));
+        assert_eq!(at(900.5, 0.5), None);
+    }
+
+    #[test]
+    fn error_console_keeps_the_latest_errors() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        for i in 0..MAX_LOGGED_ERRORS + 5 {
+            state.log_error(format!("error {}", i));
+        }
+        assert_eq!(state.errors.len(), MAX_LOGGED_ERRORS);
+        assert_eq!(state.errors.front().unwrap(), "error 5");
+        assert_eq!(
+            state.errors.back().unwrap(),
+            &format!("error {}", MAX_LOGGED_ERRORS + 4)
+        );
+    }
+
+    #[test]
+    fn tooltip_names_the_hovered_square_either_way_up() {
+        let config = Config {
+            square_tooltips: true,
+            ..Config::default()
+        };
+        let mut state = MainState::headless(GameType::Local, config);
+        state.input.cursor = on_screen("b1");
+        assert_eq!(state.square_tooltip().as_deref(), Some("b1"));
+        // Flipping only turns the ranks over.
+        state.sides_swapped = true;
+        assert_eq!(state.square_tooltip().as_deref(), Some("b8"));
+    }
+
+    #[test]
+    fn minimap_mirrors_the_board_at_its_scale() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        state.play("e2e4");
+        // With the pawn at rest, so both are drawn from the same position.
+        state.animation = None;
+        let origin = Vec2::new(810., 10.);
+        let board = state.piece_draw_commands();
+        let minimap = state.minimap_draw_commands(origin);
+        assert_eq!(minimap.len(), board.len());
+        for ((board_idx, board_dest), (mini_idx, mini_dest)) in board.into_iter().zip(minimap) {
+            assert_eq!(board_idx, mini_idx);
+            assert_eq!(mini_dest, origin + board_dest * MINIMAP_SCALE);
+        }
+    }
+
+    #[test]
+    fn clicks_follow_zoom_and_pan() {
+        let mut camera = Camera::default();
+        camera.zoom_at(Vec2::new(400., 400.), 2.);
+        // Dragging 200 pixels right and down shows 100 units further up
+        // and left of the board.
+        camera.pan(Vec2::new(200., 200.));
+        assert_eq!(camera.center, Vec2::new(300., 300.));
+        let at = |x, y| get_board_coordinate(x, y, WIDTH, HEIGHT, camera);
+        assert_eq!(at(400., 400.), Some((3, 3)));
+        assert_eq!(at(10., 10.), Some((1, 1)));
+        assert_eq!(at(790., 790.), Some((4, 4)));
+    }
+
+    const FOOLS_MATE: [&str; 4] = ["f2f3", "e7e5", "g2g4", "d8h4"];
+
+    #[test]
+    fn finished_game_resets_after_the_delay() {
+        let delay = Duration::from_secs(10);
+        let config = Config {
+            auto_reset: Some(delay),
+            ..Config::default()
+        };
+        let mut state = MainState::headless(GameType::Local, config);
+        for uci in FOOLS_MATE {
+            state.play(uci);
+        }
+        state.handle_end_phase().unwrap();
+        assert!(matches!(state.phase, Phase::End(_)));
+        // Let the delay run out.
+        state.ended_at = Some(Instant::now() - delay);
+        state.handle_end_phase().unwrap();
+        assert!(matches!(state.phase, Phase::Move));
+        assert!(state.history.is_empty());
+    }
+
+    #[test]
+    fn legal_moves_are_cached_until_the_position_changes() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        let g1 = move_index(6, 0);
+        // Marks the cache, so regenerating it would show.
+        state.current_moves.as_mut().unwrap()[g1].clear();
+        state.settle();
+        state.settle();
+        assert!(state.current_moves.as_ref().unwrap()[g1].is_empty());
+        state.play("e2e4");
+        state.play("e7e5");
+        assert!(!state.current_moves.as_ref().unwrap()[g1].is_empty());
+    }
+
+    #[test]
+    fn in_square_label_of_the_bottom_left_square() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        let bottom_left = |state: &MainState| {
+            let dest = Vec2::new(3., HEIGHT - SQUARE_SIZE + 86.);
+            let labels = state.in_square_labels();
+            labels.into_iter().find(|(_, at)| *at == dest).unwrap().0
+        };
+        assert_eq!(bottom_left(&state), "a1");
+        // Flipping turns the ranks over and leaves the files.
+        state.sides_swapped = true;
+        assert_eq!(bottom_left(&state), "a8");
+    }
+
+    #[test]
+    fn new_game_mid_game_asks_before_discarding() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        state.play("e2e4");
+        state.input = just_pressed(KeyCode::N);
+        state.handle_new_game_keys();
+        assert!(state.confirm_discard);
+        assert_eq!(state.history.len(), 1);
+        // N again answers no.
+        state.handle_new_game_keys();
+        assert!(!state.confirm_discard);
+        assert_eq!(state.history.len(), 1);
+        state.handle_new_game_keys();
+        state.input = just_pressed(KeyCode::Y);
+        state.handle_new_game_keys();
+        assert!(!state.confirm_discard);
+        assert!(state.history.is_empty());
+    }
+
+    #[test]
+    fn piece_values_are_labelled_on_their_squares() {
+        let state = MainState::headless(GameType::Local, Config::default());
+        let labels = state.piece_value_labels();
+        // Everything but the two kings.
+        assert_eq!(labels.len(), 30);
+        let label_on = |square: &str| {
+            let pos = parse_square(square).unwrap();
+            let dest = state.square_dest(pos) + Vec2::new(46., 80.);
+            labels
+                .iter()
+                .find(|(_, at)| *at == dest)
+                .map(|l| l.0.as_str())
+        };
+        assert_eq!(label_on("d1"), Some("9"));
+        assert_eq!(label_on("a8"), Some("5"));
+        assert_eq!(label_on("c1"), Some("3"));
+        assert_eq!(label_on("g8"), Some("3"));
+        assert_eq!(label_on("e2"), Some("1"));
+        assert_eq!(label_on("e1"), None);
+        assert_eq!(label_on("e4"), None);
+    }
+
+    #[test]
+    fn client_retries_until_the_host_is_up() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let host = format!("127.0.0.1:{}", port);
+        let mut listener = None;
+        let mut attempts = 0;
+        connect_with_retries(&host, 5, Duration::from_millis(1), |host| {
+            attempts += 1;
+            // The host starts listening while the client is on its third try.
+            if attempts == 3 {
+                listener = Some(TcpListener::bind(host).unwrap());
+            }
+            TcpStream::connect(host)
+        })
+        .unwrap();
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn client_gives_up_after_the_configured_retries() {
+        let mut attempts = 0;
+        let result = connect_with_retries("nowhere", 2, Duration::from_millis(1), |_| {
+            attempts += 1;
+            Err::<(), _>(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+        });
+        assert_eq!(
+            result.unwrap_err().kind(),
+            std::io::ErrorKind::ConnectionRefused
+        );
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn move_phase_picks_a_move_to_validate() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        state.click("e2");
+        state.input = Input {
+            cursor: on_screen("e4"),
+            clicked: HashSet::from([MouseButton::Left]),
+            held: HashSet::from([MouseButton::Left]),
+            ..Input::default()
+        };
+        state.handle_move_phase().unwrap();
+        assert!(matches!(state.phase, Phase::Validate(_)));
+        assert!(state.history.is_empty());
+    }
+
+    #[test]
+    fn validate_phase_plays_the_move() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        let spec = MoveSpec::from_uci("e2e4").unwrap();
+        let mv = MoveKind::Builtin(Move {
+            from: spec.from,
+            to: spec.to,
+        });
+        state.handle_validate_phase(mv).unwrap();
+        assert_eq!(state.history.len(), 1);
+        assert_eq!(state.board.turn, ChessColor::Black);
+        assert!(matches!(state.phase, Phase::Move));
+    }
+
+    #[test]
+    fn end_phase_starts_over_on_space() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        for uci in FOOLS_MATE {
+            state.play(uci);
+        }
+        assert!(matches!(state.phase, Phase::End(_)));
+        state.handle_end_phase().unwrap();
+        assert!(matches!(state.phase, Phase::End(_)));
+        state.input = just_pressed(KeyCode::Space);
+        state.handle_end_phase().unwrap();
+        assert!(matches!(state.phase, Phase::Move));
+        assert!(state.history.is_empty());
+    }
+
+    #[test]
+    fn no_legal_moves_is_explained_by_check() {
+        let (mut board, _) = game(&FOOLS_MATE);
+        let outcome = GameOutcome::from_position(&mut board).unwrap();
+        assert_eq!(
+            outcome.explanation(),
+            Some("Checkmate: in check with no legal moves")
+        );
+        // Loyd's ten move stalemate.
+        let (mut board, _) = game(&[
+            "e2e3", "a7a5", "d1h5", "a8a6", "h5a5", "h7h5", "h2h4", "a6h6", "a5c7", "f7f6", "c7d7",
+            "e8f7", "d7b7", "d8d3", "b7b8", "d3h7", "b8c8", "f7g6", "c8e6",
+        ]);
+        let outcome = GameOutcome::from_position(&mut board).unwrap();
+        assert_eq!(outcome.message(), "Stalemate");
+        assert_eq!(
+            outcome.explanation(),
+            Some("No legal moves, not in check: draw")
+        );
+    }
+
+    #[test]
+    fn moves_carry_the_configured_promotion() {
+        let _lock = connection_lock();
+        let (mut host, mut client) = hosted_game();
+        host.config.promotion = PromotionPiece::Knight;
+        host.play("e2e4");
+        match client.get_packet_blocking() {
+            PacketType::Move(mv) => {
+                assert!(matches!(mv.promotion, Some(PromotionPiece::Knight)))
+            }
+            _ => panic!("Expected a move"),
+        }
+    }
+
+    #[test]
+    fn scoreboard_tallies_each_result() {
+        let mut scoreboard = Scoreboard::default();
+        for outcome in [
+            GameOutcome::Checkmate {
+                winner: ChessColor::White,
+            },
+            GameOutcome::Draw(DrawReason::Stalemate),
+            GameOutcome::Checkmate {
+                winner: ChessColor::Black,
+            },
+            GameOutcome::OpponentResigned {
+                winner: ChessColor::White,
+            },
+            GameOutcome::Draw(DrawReason::Reported),
+        ] {
+            scoreboard.record(&outcome);
+        }
+        assert_eq!(scoreboard.summary("Ann", "Bo"), "Ann 2 - 1 Bo, 2 drawn");
+    }
+
+    #[test]
+    fn a_finished_game_is_scored_once() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        for uci in FOOLS_MATE {
+            state.play(uci);
+        }
+        state.handle_end_phase().unwrap();
+        state.handle_end_phase().unwrap();
+        assert_eq!(state.scoreboard.black_wins, 1);
+        assert_eq!(state.scoreboard.white_wins + state.scoreboard.draws, 0);
+    }
+
+    #[test]
+    fn reviewed_fen_is_the_position_after_that_many_moves() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        for uci in ["e2e4", "e7e5", "g1f3"] {
+            state.play(uci);
+        }
+        state.set_review(Some(1));
+        assert_eq!(
+            state.displayed_fen(),
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1"
+        );
+        state.set_review(Some(2));
+        assert_eq!(
+            state.displayed_fen(),
+            "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq e6 0 2"
+        );
+        state.set_review(None);
+        assert_eq!(
+            state.displayed_fen(),
+            "rnbqkbnr/pppp1ppp/8/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2"
+        );
+    }
+
+    #[test]
+    fn sample_counts_round_down_to_supported_ones() {
+        for samples in [0, 1, 2, 3] {
+            assert!(matches!(num_samples(samples), NumSamples::One));
+        }
+        for samples in [4, 8, 16] {
+            assert!(matches!(num_samples(samples), NumSamples::Four));
+        }
+    }
+
+    #[test]
+    fn practice_opponent_follows_the_recorded_game_until_we_leave_it() {
+        let moves_file = temp_path("practice");
+        let (_, recorded) = game(&["e2e4", "e7e5", "g1f3", "b8c6"]);
+        save_moves(&moves_file, &recorded).unwrap();
+        let game_type = GameType::Practice {
+            moves_file,
+            user: ChessColor::White,
+        };
+        let mut state = MainState::headless(game_type, Config::default());
+        state.play("e2e4");
+        state.practice_move();
+        state.finish_move();
+        assert_eq!(state.history[1].spec().to_uci(), "e7e5");
+        state.play("d2d4");
+        state.practice_move();
+        assert!(state.practice_line.is_none());
+        assert!(matches!(state.phase, Phase::Move));
+        assert!(state.player_handler.can_move(ChessColor::Black));
+    }
+
+    #[test]
+    fn clean_view_hides_everything_and_brings_it_back() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        let overlays = Overlays {
+            pins: true,
+            piece_values: true,
+            last_move_arrow: true,
+            ..Overlays::default()
+        };
+        state.overlays = overlays;
+        state.config.hover_moves = true;
+        state.config.minimap = true;
+        state.show_errors = true;
+        state.toggle_clean_view();
+        assert_eq!(state.overlays, Overlays::default());
+        assert!(!state.config.hover_moves);
+        assert!(!state.config.square_tooltips);
+        assert!(!state.config.minimap);
+        assert!(!state.show_errors);
+        state.toggle_clean_view();
+        assert_eq!(state.overlays, overlays);
+        assert!(state.config.hover_moves);
+        assert!(!state.config.square_tooltips);
+        assert!(state.config.minimap);
+        assert!(state.show_errors);
+    }
+
+    #[test]
+    fn moving_a_pinned_knight_is_refused_for_exposing_the_king() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        for uci in ["e2e4", "e7e5", "g1f3", "b8c6", "f1b5", "d7d6", "a2a3"] {
+            state.play(uci);
+        }
+        state.play("c6d4");
+        assert_eq!(state.history.len(), 7);
+        assert_eq!(
+            state.errors.back().unwrap(),
+            "Rejected c6d4: it would expose the king to check"
+        );
+    }
+
+    #[test]
+    fn missing_the_targets_can_deselect() {
+        let config = Config {
+            deselect_on_miss: true,
+            ..Config::default()
+        };
+        let mut state = MainState::headless(GameType::Local, config);
+        state.click("e2");
+        assert_eq!(state.selected_square, Some((4, 1)));
+        state.click("e5");
+        assert_eq!(state.selected_square, None);
+        assert!(matches!(state.phase, Phase::Move));
+    }
+
+    #[test]
+    fn clicking_the_rook_after_the_king_castles() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        for uci in ["e2e4", "e7e5", "g1f3", "b8c6", "f1c4", "g8f6"] {
+            state.play(uci);
+        }
+        state.click("e1");
+        state.click("h1");
+        state.finish_move();
+        assert_eq!(state.history.len(), 7);
+        let at = |square| piece_at(&state.board, parse_square(square).unwrap());
+        assert!(matches!(
+            at("g1"),
+            Some((PieceType::King, ChessColor::White))
+        ));
+        assert!(matches!(
+            at("f1"),
+            Some((PieceType::Rook, ChessColor::White))
+        ));
+        assert!(at("e1").is_none() && at("h1").is_none());
+    }
+
+    /// A local game in which White's last move promoted to a queen on a8,
+    /// editable while the promotion undo window is open.
+    fn promoted_game() -> MainState {
+        let config = Config {
+            promotion_undo: true,
+            ..Config::default()
+        };
+        let mut state = MainState::headless(GameType::Local, config);
+        for uci in [
+            "a2a4", "b7b5", "a4b5", "a7a6", "b5a6", "c8b7", "a6b7", "g8f6", "b7a8",
+        ] {
+            state.play(uci);
+        }
+        state
+    }
+
+    #[test]
+    fn promotion_can_be_changed_inside_the_window() {
+        let mut state = promoted_game();
+        let a8 = parse_square("a8").unwrap();
+        assert!(matches!(
+            piece_at(&state.board, a8),
+            Some((PieceType::Queen, _))
+        ));
+        state.input = just_pressed(KeyCode::N);
+        assert!(state.handle_promotion_edit());
+        assert!(matches!(
+            piece_at(&state.board, a8),
+            Some((PieceType::Knight, _))
+        ));
+        assert_eq!(state.history.len(), 9);
+        assert_eq!(state.board.turn, ChessColor::Black);
+    }
+
+    #[test]
+    fn promotion_is_locked_after_the_window() {
+        let mut state = promoted_game();
+        let (_, plies) = state.promotion_edit.unwrap();
+        let past = Instant::now() - PROMOTION_EDIT_WINDOW - Duration::from_millis(1);
+        state.promotion_edit = Some((past, plies));
+        state.input = just_pressed(KeyCode::N);
+        assert!(!state.handle_promotion_edit());
+        assert!(state.promotion_edit.is_none());
+        let a8 = parse_square("a8").unwrap();
+        assert!(matches!(
+            piece_at(&state.board, a8),
+            Some((PieceType::Queen, _))
+        ));
+    }
+
+    #[test]
+    fn frames_are_spaced_to_the_cap() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(Some(50), start);
+        pacer.frame(start);
+        let ms = Duration::from_millis;
+        assert_eq!(pacer.wait(start + ms(5)), ms(15));
+        assert_eq!(pacer.wait(start + ms(25)), Duration::ZERO);
+        let uncapped = FramePacer::new(None, start);
+        assert_eq!(uncapped.wait(start + ms(1)), Duration::ZERO);
+    }
+
+    #[test]
+    fn idle_frames_slow_down_until_something_happens() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(None, start);
+        let later = start + IDLE_AFTER;
+        pacer.frame(later);
+        let interval = Duration::from_secs(1) / IDLE_FPS;
+        assert_eq!(pacer.wait(later), interval);
+        pacer.activity(later);
+        assert_eq!(pacer.wait(later), Duration::ZERO);
+        // A cap below the idle rate still applies.
+        let mut slow = FramePacer::new(Some(2), start);
+        slow.frame(later);
+        assert_eq!(slow.wait(later), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn clicking_on_the_opponents_turn_says_to_wait() {
+        let _lock = connection_lock();
+        let (mut host, _client) = hosted_game();
+        host.play("e2e4");
+        assert!(host.waiting_notice.is_none());
+        host.left_click("d2");
+        assert!(host.waiting_notice.is_some());
+        assert_eq!(host.selected_square, None);
+    }
+
+    #[test]
+    fn clicks_map_to_every_square() {
+        let mut indices = Vec::new();
+        for x in 0..BOARD_SQUARES {
+            for y in 0..BOARD_SQUARES {
+                let center = |i: usize| (i as f32 + 0.5) * SQUARE_SIZE;
+                let square =
+                    get_board_coordinate(center(x), center(y), WIDTH, HEIGHT, Camera::default());
+                assert_eq!(square, Some((x as u8, y as u8)));
+                indices.push(move_index(x, y));
+            }
+        }
+        indices.sort_unstable();
+        assert_eq!(
+            indices,
+            (0..BOARD_SQUARES * BOARD_SQUARES).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            get_board_coordinate(WIDTH + 1., 0., WIDTH, HEIGHT, Camera::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn board_texture_alternates_square_colors() {
+        let light = SquareFill::Solid(Color::from_rgb(255, 255, 255));
+        let dark = SquareFill::Solid(Color::from_rgb(0, 0, 0));
+        let pixels = board_pixels(&light, &dark);
+        assert_eq!(pixels.len(), WIDTH as usize * HEIGHT as usize * 4);
+        let size = SQUARE_SIZE as usize;
+        let pixel = |x: usize, y: usize| {
+            let i = (y * WIDTH as usize + x) * 4;
+            pixels[i..i + 4].to_vec()
+        };
+        for x in 0..BOARD_SQUARES {
+            for y in 0..BOARD_SQUARES {
+                let expected = if (x + y) % 2 == 0 { 255 } else { 0 };
+                // Both corners, so no square spills into its neighbour.
+                let corners = [
+                    (x * size, y * size),
+                    ((x + 1) * size - 1, (y + 1) * size - 1),
+                ];
+                for (px, py) in corners {
+                    assert_eq!(pixel(px, py), vec![expected, expected, expected, 255]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_local_game_queues_its_events_in_order() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        let mut events = Vec::new();
+        for uci in ["f2f3", "e7e5", "g2g5", "g2g4", "d8h4"] {
+            let spec = MoveSpec::from_uci(uci).unwrap();
+            let mv = MoveKind::Builtin(Move {
+                from: spec.from,
+                to: spec.to,
+            });
+            state.client_validate(mv).unwrap();
+            events.extend(state.events.iter().map(|event| match event {
+                GameEvent::MoveApplied { played, mover } => {
+                    format!("{:?} {}", mover, played.spec().to_uci())
+                }
+                GameEvent::MoveRejected => "rejected".to_owned(),
+                GameEvent::GameEnded => "ended".to_owned(),
+            }));
+            state.settle();
+            assert!(state.events.is_empty());
+        }
+        assert_eq!(
+            events,
+            [
+                "White f2f3",
+                "Black e7e5",
+                "rejected",
+                "White g2g4",
+                "Black d8h4",
+                "ended"
+            ]
+        );
+    }
+
+    #[test]
+    fn host_and_client_default_to_the_last_address_used() {
+        let config = Config {
+            last_address_file: temp_path("last-address"),
+            ..Config::default()
+        };
+        let args = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            parse_args(&args, GameType::Local, config.clone()).0
+        };
+        let game_type = args(&["chess", "--client"]);
+        assert!(matches!(game_type, GameType::Client(address) if address == "localhost:3000"));
+        save_last_address(&config.last_address_file, "client", "10.0.0.2:3000");
+        save_last_address(&config.last_address_file, "host", "0.0.0.0:3001");
+        save_last_address(&config.last_address_file, "client", "10.0.0.3:3000");
+        let game_type = args(&["chess", "--client"]);
+        assert!(matches!(game_type, GameType::Client(address) if address == "10.0.0.3:3000"));
+        let game_type = args(&["chess", "--host"]);
+        assert!(matches!(game_type, GameType::Host(address) if address == "0.0.0.0:3001"));
+        let game_type = args(&["chess", "--client", "example.com:80"]);
+        assert!(matches!(game_type, GameType::Client(address) if address == "example.com:80"));
+    }
+
+    #[test]
+    fn presses_from_half_a_second_are_long() {
+        let pressed = Instant::now();
+        let after = |ms| pressed + Duration::from_millis(ms);
+        assert!(!is_long_press(pressed, after(0)));
+        assert!(!is_long_press(pressed, after(499)));
+        assert!(is_long_press(pressed, after(500)));
+        assert!(is_long_press(pressed, after(2000)));
+        // A release stamped before the press is a tap, not a panic.
+        assert!(!is_long_press(after(10), pressed));
+    }
+
+    #[test]
+    fn a_long_press_only_previews_the_moves() {
+        let config = Config {
+            long_press_preview: true,
+            ..Config::default()
+        };
+        let mut state = MainState::headless(GameType::Local, config);
+        state.left_click("e2");
+        state.drag.as_mut().unwrap().pressed_at -= LONG_PRESS;
+        state.left_release("e2");
+        assert_eq!(state.selected_square, None);
+        state.click("e2");
+        assert_eq!(state.selected_square, Some((4, 1)));
+    }
+
+    #[test]
+    fn draw_watch_lights_up_for_either_rule() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        for uci in ["g1f3", "g8f6", "f3g1"] {
+            state.play(uci);
+        }
+        assert_eq!(state.draw_watch(), None);
+        state.play("f6g8");
+        assert_eq!(
+            state.draw_watch().as_deref(),
+            Some("Draw watch: position seen 2 times")
+        );
+        // Only the history is looked at for the clock.
+        let (_, quiet) = game(&["g1f3"]);
+        state.history = vec![quiet[0]; FIFTY_MOVE_WATCH];
+        state.repetitions = 1;
+        assert_eq!(
+            state.draw_watch().as_deref(),
+            Some("Draw watch: 80/100 quiet half-moves")
+        );
+    }
+
+    #[test]
+    fn received_chat_is_logged_under_the_opponents_name() {
+        let _lock = connection_lock();
+        let (mut host, mut client) = hosted_game();
+        client.send_packet(PacketType::Control(Control::Chat {
+            text: "good luck".to_owned(),
+        }));
+        host.receive();
+        assert_eq!(
+            host.chat_log.back(),
+            Some(&("client".to_owned(), "good luck".to_owned()))
+        );
+    }
+
+    #[test]
+    fn received_forfeit_ends_the_game_as_a_win() {
+        let _lock = connection_lock();
+        let (mut host, mut client) = hosted_game();
+        host.play("e2e4");
+        next_reply(&mut client);
+        client.send_packet(PacketType::Move(chess_networking::Move {
+            from: (0, 0),
+            to: (0, 0),
+            promotion: None,
+            forfeit: true,
+            offer_draw: false,
+        }));
+        host.receive();
+        match &host.phase {
+            Phase::End(outcome) => {
+                assert!(matches!(
+                    outcome,
+                    GameOutcome::OpponentResigned {
+                        winner: ChessColor::White
+                    }
+                ));
+                assert_eq!(outcome.message(), "Opponent resigned — you win");
+            }
+            _ => panic!("The game should be over"),
+        }
+        assert_eq!(host.history.len(), 1);
+    }
+
+    #[test]
+    fn draw_offer_lapses_when_we_move_or_can_be_accepted() {
+        let _lock = connection_lock();
+        let (mut host, mut client) = hosted_game();
+        let offering = |from, to| {
+            PacketType::Move(chess_networking::Move {
+                from,
+                to,
+                promotion: None,
+                forfeit: false,
+                offer_draw: true,
+            })
+        };
+        host.play("e2e4");
+        next_reply(&mut client);
+        client.send_packet(offering((4, 6), (4, 4)));
+        host.receive();
+        assert!(host.draw_offer);
+        assert_eq!(host.history.len(), 2);
+        host.play("g1f3");
+        assert!(!host.draw_offer);
+        next_reply(&mut client);
+        client.send_packet(offering((1, 7), (2, 5)));
+        host.receive();
+        host.input = just_pressed(KeyCode::Y);
+        host.handle_draw_offer();
+        assert!(matches!(
+            host.phase,
+            Phase::End(GameOutcome::Draw(DrawReason::Agreed))
+        ));
+        let accepted = loop {
+            if let PacketType::Ack(ack) = client.get_packet_blocking() {
+                if ack.end_state.is_some() {
+                    break ack;
+                }
+            }
+        };
+        assert!(matches!(accepted.end_state, Some(GameState::Draw)));
+    }
+
+    #[test]
+    fn opponent_sounds_only_skips_our_own_moves() {
+        let _lock = connection_lock();
+        let (mut host, mut client) = hosted_game();
+        host.config.opponent_sounds_only = true;
+        host.play("e2e4");
+        assert!(host.queued_sound.is_none());
+        next_reply(&mut client);
+        client.send_packet(network_move((6, 7), (5, 5)));
+        host.receive();
+        assert!(matches!(host.queued_sound, Some(PieceType::Knight)));
+    }
+
+    #[test]
+    fn extra_connections_are_told_the_game_is_full() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let address = listener.local_addr().unwrap();
+        let (ours, _theirs) = memory_pair();
+        let host = Network::new(
+            NetworkType::Host {
+                listener: Some(listener),
+            },
+            Box::new(ours),
+            NetworkSettings::default(),
+        );
+        let mut late = TcpStream::connect(address).unwrap();
+        late.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        host.reject_extra_connections();
+        let mut reply = String::new();
+        late.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, GAME_FULL);
+    }
+
+    #[test]
+    fn missing_config_file_keeps_the_defaults() {
+        let (game_type, config) = load_config("/nonexistent/chess.conf").unwrap();
+        assert!(matches!(game_type, GameType::Local));
+        assert_eq!(config.theme, Theme::Classic);
+        assert_eq!(config.max_fps, None);
+        assert_eq!(config.network.max_packet, 64 * 1024);
+        assert_eq!(config.autosave_interval, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn valid_config_is_applied() {
+        let contents = "\
+            # Always host a blue game.
+            host = 0.0.0.0:4000
+
+            theme = blue
+            show-pins
+            max-fps = 60
+            light-color = 238, 238, 210
+            sim-latency = 250
+        ";
+        let (game_type, config) = parse_config(contents).unwrap();
+        assert!(matches!(game_type, GameType::Host(address) if address == "0.0.0.0:4000"));
+        assert_eq!(config.theme, Theme::Blue);
+        assert!(config.overlays.pins);
+        assert_eq!(config.max_fps, Some(60));
+        assert_eq!(
+            config.light_square_color.map(Color::to_rgb),
+            Some((238, 238, 210))
+        );
+        assert_eq!(config.network.sim_latency, Duration::from_millis(250));
+        // Anything not in the file stays at its default.
+        assert_eq!(config.network.connect_retries, 5);
+    }
+
+    #[test]
+    fn out_of_range_value_names_its_line_and_field() {
+        let contents = "show-pins\nauto-reset-secs = 0\n";
+        assert_eq!(
+            parse_config(contents).unwrap_err(),
+            ConfigError {
+                line: 2,
+                field: "auto-reset-secs".to_owned(),
+                reason: "0 is not between 1 and 3600".to_owned(),
+            }
+        );
+        let err = parse_config("dark-color = 300,0,0").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1: dark-color: 300 is not between 0 and 255"
+        );
+    }
+
+    #[test]
+    fn unknown_key_names_its_line() {
+        let err = parse_config("\n# comment\ncolour = red\n").unwrap_err();
+        assert_eq!(err.to_string(), "3: colour: unknown setting");
+        let err = parse_config("show-pins = yes").unwrap_err();
+        assert_eq!(err.to_string(), "1: show-pins: takes no value");
+    }
+
+    #[test]
+    fn prompt_is_centered_in_any_window() {
+        // The canvas is in view space, where the middle of the window is
+        // the middle of the board however the board is letterboxed.
+        for (sc_width, sc_height) in [(WIDTH, HEIGHT), (1600., 800.), (1000., 1400.)] {
+            let dest = centered_in_window(200., 100., sc_width, sc_height);
+            assert_eq!(dest, Vec2::new(300., 350.));
+        }
+    }
+
+    #[test]
+    fn color_blind_palette_swaps_red_and_green_for_orange_and_blue() {
+        let args: Vec<String> = ["chess", "--palette", "color-blind"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let (_, config) = parse_args(&args, GameType::Local, Config::default());
+        assert_eq!(config.palette, Palette::ColorBlind);
+        assert_eq!(config.palette.attacker().to_rgb(), (230, 159, 0));
+        assert_eq!(config.palette.defender().to_rgb(), (0, 114, 178));
+        assert_eq!(Palette::Standard.attacker().to_rgb(), (220, 40, 40));
+        assert_eq!(Palette::Standard.defender().to_rgb(), (40, 200, 80));
+    }
+
+    /// A host playing White and a client playing Black, connected in
+    /// memory. Hold `connection_lock` while they're open.
+    fn joined_games() -> (MainState, MainState) {
+        let (host_end, client_end) = memory_pair();
+        let client = std::thread::spawn(move || {
+            let mut client = Network::new(
+                NetworkType::Client,
+                Box::new(client_end),
+                NetworkSettings::default(),
+            );
+            let players = client.init(Some("client".to_owned()));
+            (client, players)
+        });
+        let host = Network::new(
+            NetworkType::Host { listener: None },
+            Box::new(host_end),
+            NetworkSettings::default(),
+        );
+        let host = MainState::networked(GameType::Host("memory".to_owned()), host, "host");
+        let (network, players) = client.join().unwrap();
+        let client = MainState::joined(
+            GameType::Client("memory".to_owned()),
+            network,
+            players,
+            "client",
+        );
+        (host, client)
+    }
+
+    #[test]
+    fn host_and_client_play_to_checkmate() {
+        let _lock = connection_lock();
+        let (mut host, mut client) = joined_games();
+        assert_eq!(host.player_name(ChessColor::Black), "client");
+        assert_eq!(client.player_name(ChessColor::White), "host");
+        for (i, uci) in FOOLS_MATE.into_iter().enumerate() {
+            let (mover, other) = if i % 2 == 0 {
+                (&mut host, &mut client)
+            } else {
+                (&mut client, &mut host)
+            };
+            assert!(mover.player_handler.can_move(mover.board.turn));
+            assert!(!other.player_handler.can_move(other.board.turn));
+            mover.play(uci);
+            other.receive_move();
+            assert_eq!(host.displayed_fen(), client.displayed_fen());
+        }
+        for state in [&host, &client] {
+            assert!(matches!(
+                state.phase,
+                Phase::End(GameOutcome::Checkmate {
+                    winner: ChessColor::Black
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn click_only_input_ignores_drags() {
+        let config = Config {
+            input_scheme: InputScheme::Click,
+            ..Config::default()
+        };
+        let mut state = MainState::headless(GameType::Local, config);
+        state.left_click("e2");
+        assert!(state.drag.is_none());
+        state.left_release("e4");
+        assert!(matches!(state.phase, Phase::Move));
+        assert_eq!(state.selected_square, Some((4, 1)));
+        state.click("e4");
+        state.finish_move();
+        assert_eq!(state.history.len(), 1);
+        assert_eq!(state.history[0].spec().to_uci(), "e2e4");
+    }
+
+    #[test]
+    fn last_move_arrow_follows_the_move_either_way_up() {
+        let _lock = connection_lock();
+        let (mut host, mut client) = joined_games();
+        host.play("e2e4");
+        client.receive_move();
+        // Black is at the bottom for the client, so White's pawn comes down
+        // from the top.
+        assert_eq!(
+            client.last_move_arrow(),
+            (Vec2::new(450., 150.), Vec2::new(450., 350.))
+        );
+        client.play("e7e5");
+        host.receive_move();
+        assert_eq!(
+            host.last_move_arrow(),
+            (Vec2::new(450., 150.), Vec2::new(450., 350.))
+        );
+    }
+
+    #[test]
+    fn clicks_do_nothing_while_paused() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        state.click("e2");
+        state.input = just_pressed(KeyCode::Space);
+        assert!(state.update_pause());
+        assert_eq!(state.selected_square, None);
+        state.input = Input {
+            cursor: on_screen("e2"),
+            clicked: HashSet::from([MouseButton::Left]),
+            held: HashSet::from([MouseButton::Left]),
+            ..Input::default()
+        };
+        assert!(state.update_pause());
+        state.handle_phase().unwrap();
+        assert_eq!(state.selected_square, None);
+        assert!(state.drag.is_none());
+        state.input = just_pressed(KeyCode::Space);
+        assert!(!state.update_pause());
+        state.click("e2");
+        assert_eq!(state.selected_square, Some((4, 1)));
+    }
+
+    #[test]
+    fn zero_resets_zoom_and_pan() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        state.camera.zoom_at(Vec2::new(400., 400.), 2.);
+        state.camera.pan(Vec2::new(200., 200.));
+        let at = |camera| get_board_coordinate(50., 50., WIDTH, HEIGHT, camera);
+        assert_eq!(at(state.camera), Some((1, 1)));
+        state.input = just_pressed(KeyCode::Key0);
+        state.update_camera();
+        assert_eq!(state.camera.zoom, 1.);
+        assert_eq!(state.camera.center, Vec2::new(400., 400.));
+        assert_eq!(at(state.camera), Some((0, 0)));
+    }
+
+    #[test]
+    fn checkmate_can_turn_the_board_to_the_winner() {
+        let mated = |flip_to_winner| {
+            let config = Config {
+                flip_to_winner,
+                ..Config::default()
+            };
+            let mut state = MainState::headless(GameType::Local, config);
+            for uci in FOOLS_MATE {
+                state.play(uci);
+            }
+            assert!(matches!(state.phase, Phase::End(_)));
+            state
+        };
+        // White, to move and mated, stays at the bottom without the option.
+        assert!(mated(false).should_reverse());
+        assert!(!mated(true).should_reverse());
+    }
+
+    #[test]
+    fn the_local_color_decides_who_moves_and_which_way_up() {
+        let _lock = connection_lock();
+        let (mut host, _client) = hosted_game();
+        assert_eq!(host.player_handler.local_color, Some(ChessColor::White));
+        // Locality changed behind the handler's back doesn't move the user
+        // to the other side.
+        host.player_handler.players.black.local = true;
+        host.player_handler.players.white.local = false;
+        assert!(host.player_handler.can_move(ChessColor::White));
+        assert!(!host.player_handler.can_move(ChessColor::Black));
+        assert!(host.should_reverse());
+        host.player_handler.local_color = Some(ChessColor::Black);
+        assert!(!host.player_handler.can_move(ChessColor::White));
+        assert!(host.player_handler.can_move(ChessColor::Black));
+        assert!(!host.should_reverse());
+    }
+
+    #[test]
+    fn self_test_plays_random_games_to_an_end() {
+        for _ in 0..5 {
+            assert_eq!(self_test(), 0);
+        }
+    }
+
+    #[test]
+    fn capturing_onto_the_last_rank_promotes() {
+        let config = Config {
+            promotion: PromotionPiece::Knight,
+            ..Config::default()
+        };
+        let mut state = MainState::headless(GameType::Local, config);
+        for uci in [
+            "a2a4", "b7b5", "a4b5", "a7a6", "b5a6", "c8b7", "a6b7", "g8f6", "b7a8",
+        ] {
+            state.play(uci);
+        }
+        let last = state.history.last().unwrap();
+        assert_eq!(last.spec().to_uci(), "b7a8n");
+        assert!(matches!(last.captured, Some(PieceType::Rook)));
+        let a8 = parse_square("a8").unwrap();
+        assert!(matches!(
+            piece_at(&state.board, a8),
+            Some((PieceType::Knight, ChessColor::White))
+        ));
+        assert_eq!(state.board.turn, ChessColor::Black);
+    }
+
+    #[test]
+    fn hovering_a_piece_shows_what_attacks_and_defends_it() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        for uci in ["e2e4", "d7d5", "d2d3", "g8f6"] {
+            state.play(uci);
+        }
+        state.input.cursor = on_screen("e4");
+        assert_eq!(state.square_control(), None);
+        state.overlays.hover_threats = true;
+        assert_eq!(
+            state.square_control(),
+            Some(SquareControl {
+                attackers: vec![(3, 4), (5, 5)],
+                defenders: vec![(3, 2)],
+            })
+        );
+        state.input.cursor = on_screen("e5");
+        assert_eq!(state.square_control(), None);
+    }
+
+    #[test]
+    fn recorded_game_plays_back_to_the_same_position() {
+        let record_file = temp_path("replay");
+        let config = Config {
+            record_file: Some(record_file.clone()),
+            ..Config::default()
+        };
+        let mut recorded = MainState::headless(GameType::Local, config);
+        for uci in ["e2e4", "e7e5", "g1f3", "b8c6", "f1b5", "a7a6", "b5c6"] {
+            recorded.play(uci);
+        }
+        recorded.save_replay();
+
+        let mut replay = MainState::headless(GameType::Replay(record_file), Config::default());
+        assert_eq!(replay.displayed_fen(), fen::STARTING_POSITION);
+        for _ in 0..recorded.history.len() {
+            replay.update_playback(Duration::from_secs(1));
+        }
+        assert_eq!(
+            replay.review.as_ref().unwrap().index,
+            recorded.history.len()
+        );
+        assert_eq!(replay.displayed_fen(), recorded.displayed_fen());
+        replay.update_playback(Duration::from_secs(1));
+        assert!(!replay.playback.as_ref().unwrap().playing);
+    }
+
+    #[test]
+    fn captured_pieces_fade_from_opaque_to_gone() {
+        let duration = Duration::from_millis(200);
+        let at = |ms| fade_alpha(Duration::from_millis(ms), duration);
+        assert_eq!(at(0), 1.);
+        assert_eq!(at(100), 0.5);
+        assert_eq!(at(200), 0.);
+        assert_eq!(at(500), 0.);
+        assert_eq!(fade_alpha(Duration::ZERO, Duration::ZERO), 0.);
+    }
+
+    #[test]
+    fn pasted_pgn_loads_into_review_or_reports_why_not() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        state.load_pgn("[White \"A\"]\n\n1. e4 e5 2. Nf3 {develops} Nc6 3. Bb5 *\n");
+        assert_eq!(state.history.len(), 5);
+        assert_eq!(state.review.as_ref().unwrap().index, 0);
+        state.load_pgn("1. e4 e5 2. Ke3 *");
+        assert_eq!(state.history.len(), 5);
+        assert!(state
+            .errors
+            .back()
+            .unwrap()
+            .starts_with("Clipboard doesn't hold a PGN game: move 3"));
+    }
+
+    #[test]
+    fn snapshot_reflects_the_game_so_far() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        for uci in ["e2e4", "c7c5", "g1f3"] {
+            state.play(uci);
+        }
+        let snapshot = state.game_snapshot();
+        assert_eq!(
+            snapshot.to_string(),
+            "White vs Black, black to move, moving, last move g1f3: \
+             rnbqkbnr/pp1ppppp/8/2p5/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 1 2"
+        );
+        for uci in ["d7d6", "f3g5", "d8a5"] {
+            state.play(uci);
+        }
+        let snapshot = state.game_snapshot();
+        assert!(snapshot.turn == ChessColor::White);
+        assert_eq!(snapshot.last_move.as_deref(), Some("d8a5"));
+        assert_eq!(snapshot.phase, "moving");
+    }
+
+    #[test]
+    fn failing_to_open_a_window_explains_itself() {
+        let err = GameError::CustomError("no adapter".to_owned());
+        let [cause, advice] = no_window_message(&err);
+        assert!(cause.starts_with("Couldn't open a window: "));
+        assert!(advice.contains("--selftest"));
+        assert_ne!(NO_DISPLAY_EXIT_CODE, 0);
+    }
+
+    #[test]
+    fn t_cycles_the_schemes_and_their_square_colors() {
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        let order = [
+            (Theme::Green, [238, 238, 210, 255], [118, 150, 86, 255]),
+            (Theme::Blue, [222, 227, 230, 255], [140, 162, 173, 255]),
+            (Theme::Gray, [200, 200, 200, 255], [120, 120, 120, 255]),
+            (Theme::HighContrast, [255, 255, 255, 255], [0, 0, 0, 255]),
+            (Theme::Classic, [255, 206, 158, 255], [209, 139, 71, 255]),
+        ];
+        for (theme, light, dark) in order {
+            state.input = just_pressed(KeyCode::T);
+            state.handle_toggle_keys();
+            assert_eq!(state.config.theme, theme);
+            // The texture is remade from these whenever the theme changes.
+            assert_eq!(texture_square(&state.config, 0, 0), light);
+            assert_eq!(texture_square(&state.config, 1, 0), dark);
+        }
+    }
+
+    #[test]
+    fn closing_mid_game_asks_first_when_confirming() {
+        let config = Config {
+            confirm_quit: true,
+            ..Config::default()
+        };
+        let mut state = MainState::headless(GameType::Local, config.clone());
+        assert!(!state.intercept_quit(), "nothing to lose before a move");
+        state.play("e2e4");
+        state.play("e7e5");
+        assert!(state.intercept_quit());
+        assert!(state.quit_prompt);
+        // The board waits on the prompt.
+        state.input = Input {
+            cursor: on_screen("d2"),
+            clicked: HashSet::from([MouseButton::Left]),
+            held: HashSet::from([MouseButton::Left]),
+            ..Input::default()
+        };
+        state.handle_phase().unwrap();
+        assert_eq!(state.selected_square, None);
+
+        let mut state = MainState::headless(GameType::Local, Config::default());
+        state.play("e2e4");
+        assert!(!state.intercept_quit());
+        assert!(!state.quit_prompt);
+    }
+
+    #[test]
+    fn banner_comes_up_on_our_turn_and_goes_once_we_move() {
+        let _lock = connection_lock();
+        let (mut host, mut client) = joined_games();
+        host.play("e2e4");
+        assert!(!host.your_move);
+        client.receive_move();
+        assert!(client.your_move);
+        // J brings a reviewing player back to the live board.
+        client.set_review(Some(0));
+        client.input = just_pressed(KeyCode::J);
+        client.handle_toggle_keys();
+        assert!(client.review.is_none());
+        assert!(client.last_move_flash.is_some());
+        client.play("e7e5");
+        assert!(!client.your_move);
+        host.receive_move();
+        assert!(host.your_move);
+    }
+
+    #[test]
+    fn without_animations_a_move_is_drawn_on_its_square_at_once() {
+        // Where the white pawns are drawn, and where e4 is.
+        let white_pawns = |state: &MainState| {
+            let pawns: Vec<Vec2> = state
+                .piece_draw_commands()
+                .into_iter()
+                .filter(|&(texture, _)| texture == PieceType::Pawn as usize)
+                .map(|(_, dest)| dest)
+                .collect();
+            (pawns, state.square_dest(Position { x: 4, y: 3 }))
+        };
+        let config = Config {
+            animations: false,
+            ..Config::default()
+        };
+        let mut state = MainState::headless(GameType::Local, config);
+        state.play("e2e4");
+        assert!(state.animation.is_none());
+        let (pawns, e4) = white_pawns(&state);
+        assert!(pawns.contains(&e4));
+
+        let config = Config {
+            animation_duration: Duration::from_secs(60),
+            ..Config::default()
+        };
+        let mut state = MainState::headless(GameType::Local, config);
+        state.play("e2e4");
+        assert!(state.animation.is_some());
+        let (pawns, e4) = white_pawns(&state);
+        assert!(!pawns.contains(&e4), "still sliding from e2");
+    }
 }
@@ -0,0 +1,154 @@
+//! Finding where one msgpack value ends, so packets that arrive together in
+//! one read, or split over several, can be told apart. Only the headers are
+//! looked at; nothing is decoded or allocated.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// `0xc1`, which msgpack never uses.
+    InvalidMarker,
+    /// The value says it is longer than the limit.
+    TooLarge,
+}
+
+/// The length of the msgpack value at the start of `data`, or `None` if
+/// more bytes are needed to tell. Values declaring more than `max` bytes,
+/// or more items than could fit in that, are refused before they arrive.
+pub fn value_len(data: &[u8], max: usize) -> Result<Option<usize>, FrameError> {
+    let mut pos = 0;
+    // Values still to be skipped, counting the items of every container
+    // already entered.
+    let mut remaining: usize = 1;
+    while remaining > 0 {
+        remaining -= 1;
+        let marker = if let Some(&marker) = data.get(pos) {
+            marker
+        } else {
+            return Ok(None);
+        };
+        pos += 1;
+        // Payload bytes, the width of a length field adding more payload,
+        // the width of a count field adding items, and items.
+        let (fixed, length_width, count_width, items) = match marker {
+            0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => (0, 0, 0, 0),
+            0x80..=0x8f => (0, 0, 0, 2 * (marker & 0x0f) as usize),
+            0x90..=0x9f => (0, 0, 0, (marker & 0x0f) as usize),
+            0xa0..=0xbf => ((marker & 0x1f) as usize, 0, 0, 0),
+            0xc1 => return Err(FrameError::InvalidMarker),
+            // bin 8/16/32 and str 8/16/32
+            0xc4 | 0xd9 => (0, 1, 0, 0),
+            0xc5 | 0xda => (0, 2, 0, 0),
+            0xc6 | 0xdb => (0, 4, 0, 0),
+            // ext 8/16/32, whose data follows a type byte
+            0xc7 => (1, 1, 0, 0),
+            0xc8 => (1, 2, 0, 0),
+            0xc9 => (1, 4, 0, 0),
+            0xcc | 0xd0 => (1, 0, 0, 0),
+            0xcd | 0xd1 => (2, 0, 0, 0),
+            0xca | 0xce | 0xd2 => (4, 0, 0, 0),
+            0xcb | 0xcf | 0xd3 => (8, 0, 0, 0),
+            // fixext 1/2/4/8/16, type byte included
+            0xd4 => (2, 0, 0, 0),
+            0xd5 => (3, 0, 0, 0),
+            0xd6 => (5, 0, 0, 0),
+            0xd7 => (9, 0, 0, 0),
+            0xd8 => (17, 0, 0, 0),
+            // array 16/32 and map 16/32
+            0xdc | 0xde => (0, 0, 2, 0),
+            0xdd | 0xdf => (0, 0, 4, 0),
+        };
+        let length = if let Some(length) = read_size(data, pos, length_width) {
+            length
+        } else {
+            return Ok(None);
+        };
+        pos += length_width;
+        let count = if let Some(count) = read_size(data, pos, count_width) {
+            count
+        } else {
+            return Ok(None);
+        };
+        pos += count_width;
+        let count = if matches!(marker, 0xde | 0xdf) {
+            2 * count
+        } else {
+            count
+        };
+        pos += fixed + length;
+        remaining += items + count;
+        // Every item takes at least a byte, so this bounds the whole value.
+        if pos > max || pos + remaining > max {
+            return Err(FrameError::TooLarge);
+        }
+    }
+    if pos > data.len() {
+        Ok(None)
+    } else {
+        Ok(Some(pos))
+    }
+}
+
+/// The big endian number of `bytes` bytes at `pos`, zero for no bytes.
+fn read_size(data: &[u8], pos: usize, bytes: usize) -> Option<usize> {
+    let field = data.get(pos..pos + bytes)?;
+    Some(field.iter().fold(0, |size, &b| (size << 8) | b as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_values() {
+        assert_eq!(value_len(&[0x05], 64), Ok(Some(1)));
+        assert_eq!(value_len(&[0xc3, 0x00], 64), Ok(Some(1)));
+        assert_eq!(value_len(&[0xa3, b'a', b'b', b'c'], 64), Ok(Some(4)));
+        assert_eq!(value_len(&[0xcd, 0x01, 0x00], 64), Ok(Some(3)));
+    }
+
+    #[test]
+    fn nested_containers() {
+        // {"a": [1, true], "b": nil}
+        let map = [0x82, 0xa1, b'a', 0x92, 0x01, 0xc3, 0xa1, b'b', 0xc0];
+        assert_eq!(value_len(&map, 64), Ok(Some(map.len())));
+        // array 16 of three items
+        let array = [0xdc, 0x00, 0x03, 0x01, 0x02, 0x03];
+        assert_eq!(value_len(&array, 64), Ok(Some(array.len())));
+    }
+
+    #[test]
+    fn stops_at_the_end_of_the_first_value() {
+        let two = [0x92, 0x01, 0x02, 0x91, 0x03];
+        assert_eq!(value_len(&two, 64), Ok(Some(3)));
+    }
+
+    #[test]
+    fn waits_for_the_rest() {
+        let map = [0x82, 0xa1, b'a', 0x92, 0x01, 0xc3, 0xa1, b'b', 0xc0];
+        for end in 0..map.len() {
+            assert_eq!(value_len(&map[..end], 64), Ok(None));
+        }
+        assert_eq!(value_len(&[0xda, 0x00], 64), Ok(None));
+    }
+
+    #[test]
+    fn refuses_a_declared_length_over_the_limit() {
+        // bin 32 claiming 4 GiB, with none of it sent.
+        assert_eq!(
+            value_len(&[0xc6, 0xff, 0xff, 0xff, 0xff], 64 * 1024),
+            Err(FrameError::TooLarge)
+        );
+        // array 32 claiming four billion items.
+        assert_eq!(
+            value_len(&[0xdd, 0xff, 0xff, 0xff, 0xff], 64 * 1024),
+            Err(FrameError::TooLarge)
+        );
+        let mut str8 = vec![0xd9, 100];
+        str8.resize(102, b'x');
+        assert_eq!(value_len(&str8, 64), Err(FrameError::TooLarge));
+    }
+
+    #[test]
+    fn refuses_the_unused_marker() {
+        assert_eq!(value_len(&[0xc1], 64), Err(FrameError::InvalidMarker));
+    }
+}
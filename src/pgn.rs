@@ -0,0 +1,352 @@
+//! Standard algebraic notation and PGN export. Positions are rebuilt by
+//! replaying the game from the start, since SAN depends on which other moves
+//! were legal at the time.
+
+use std::fmt;
+
+use chess::{Chess, Color, PieceType, Position, Status, ValidationResult};
+
+use crate::{
+    analysis, move_index, piece_at, square_name, DrawReason, GameOutcome, MoveSpec, PlayedMove,
+    BOARD_SQUARES,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PgnError {
+    /// A `{` comment or `(` variation that is never closed.
+    Unclosed(char),
+    /// The move with this number in the movetext, counted in plies from 1,
+    /// isn't legal or isn't SAN.
+    IllegalMove(usize, String),
+}
+
+impl fmt::Display for PgnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unclosed(c) => write!(f, "unclosed '{}'", c),
+            Self::IllegalMove(ply, san) => write!(f, "move {} ({}) is not legal", ply, san),
+        }
+    }
+}
+
+fn piece_letter(piece: PieceType) -> &'static str {
+    match piece {
+        PieceType::King => "K",
+        PieceType::Queen => "Q",
+        PieceType::Rook => "R",
+        PieceType::Bishop => "B",
+        PieceType::Knight => "N",
+        PieceType::Pawn => "",
+    }
+}
+
+fn file_letter(pos: Position) -> char {
+    (b'a' + pos.x as u8) as char
+}
+
+/// The SAN of `mv` up to the check suffix. `board` is the position before the
+/// move and `legal` its legal moves, indexed like `generate_valid_moves`.
+fn san_body(
+    board: &Chess,
+    legal: &[Vec<chess::Move>; BOARD_SQUARES * BOARD_SQUARES],
+    mv: &PlayedMove,
+) -> String {
+    if matches!(mv.piece, PieceType::King) && mv.from.x.abs_diff(mv.to.x) == 2 {
+        return if mv.to.x > mv.from.x { "O-O" } else { "O-O-O" }.to_owned();
+    }
+    let mut san = piece_letter(mv.piece).to_owned();
+    if matches!(mv.piece, PieceType::Pawn) {
+        if mv.captured.is_some() {
+            san.push(file_letter(mv.from));
+        }
+    } else {
+        // Other pieces of the same kind that could also have gone there.
+        let rivals: Vec<Position> = board
+            .board
+            .iter()
+            .flatten()
+            .filter(|piece| {
+                piece.piece_type as usize == mv.piece as usize
+                    && (piece.position.x, piece.position.y) != (mv.from.x, mv.from.y)
+            })
+            .map(|piece| piece.position)
+            .filter(|pos| {
+                legal[move_index(pos.x, pos.y)]
+                    .iter()
+                    .any(|other| (other.to.x, other.to.y) == (mv.to.x, mv.to.y))
+            })
+            .collect();
+        if !rivals.is_empty() {
+            if rivals.iter().all(|pos| pos.x != mv.from.x) {
+                san.push(file_letter(mv.from));
+            } else if rivals.iter().all(|pos| pos.y != mv.from.y) {
+                san += &(mv.from.y + 1).to_string();
+            } else {
+                san += &square_name(mv.from);
+            }
+        }
+    }
+    if mv.captured.is_some() {
+        san.push('x');
+    }
+    san += &square_name(mv.to);
+    if let Some(promotion) = mv.promotion {
+        san += "=";
+        san += piece_letter(promotion);
+    }
+    san
+}
+
+/// Every move of the game in SAN, or `None` if it doesn't replay.
+pub fn san_moves(history: &[PlayedMove]) -> Option<Vec<String>> {
+    let mut board = Chess::new();
+    let mut moves = Vec::with_capacity(history.len());
+    for mv in history {
+        let legal = board.generate_valid_moves();
+        let mut san = san_body(&board, &legal, mv);
+        let mut status = match board.move_piece(mv.from, mv.to) {
+            ValidationResult::Valid(status) => status,
+            _ => return None,
+        };
+        if let Some(promotion) = mv.promotion {
+            status = board.promote_piece(promotion)?;
+        }
+        if matches!(status, Status::Checkmate(_)) {
+            san.push('#');
+        } else if analysis::in_check(&analysis::grid(&board), board.turn) {
+            san.push('+');
+        }
+        moves.push(san);
+    }
+    Some(moves)
+}
+
+/// The moves numbered the way PGN writes them: "1. e4 e5 2. Nf3".
+pub fn numbered_moves(san: &[String]) -> String {
+    san.chunks(2)
+        .enumerate()
+        .map(|(i, pair)| format!("{}. {}", i + 1, pair.join(" ")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The PGN result token, `*` while the game is still going.
+pub fn result_token(outcome: Option<&GameOutcome>) -> &'static str {
+    match outcome {
+        Some(GameOutcome::Checkmate {
+            winner: Color::White,
+        }) => "1-0",
+        Some(GameOutcome::Checkmate { .. }) => "0-1",
+        Some(GameOutcome::OpponentResigned {
+            winner: Color::White,
+        }) => "1-0",
+        Some(GameOutcome::OpponentResigned { .. }) => "0-1",
+        Some(GameOutcome::Draw(_)) => "1/2-1/2",
+        None => "*",
+    }
+}
+
+pub fn termination(outcome: &GameOutcome) -> &'static str {
+    match outcome {
+        GameOutcome::Checkmate {
+            winner: Color::White,
+        } => "White wins by checkmate",
+        GameOutcome::Checkmate { .. } => "Black wins by checkmate",
+        GameOutcome::OpponentResigned {
+            winner: Color::White,
+        } => "White wins by resignation",
+        GameOutcome::OpponentResigned { .. } => "Black wins by resignation",
+        GameOutcome::Draw(DrawReason::Stalemate) => "Draw by stalemate",
+        GameOutcome::Draw(DrawReason::ThreefoldRepetition) => "Draw by threefold repetition",
+        GameOutcome::Draw(DrawReason::FiftyMoveRule) => "Draw by the fifty-move rule",
+        GameOutcome::Draw(DrawReason::Reported) => "Draw reported by the opponent",
+        GameOutcome::Draw(DrawReason::Agreed) => "Draw by agreement",
+    }
+}
+
+fn tag(name: &str, value: &str) -> String {
+    let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("[{} \"{}\"]\n", name, value)
+}
+
+/// The game as PGN, with the reason it ended in a comment before the result.
+pub fn export(
+    history: &[PlayedMove],
+    outcome: Option<&GameOutcome>,
+    white: &str,
+    black: &str,
+) -> Option<String> {
+    let san = san_moves(history)?;
+    let result = result_token(outcome);
+    let mut pgn = String::new();
+    pgn += &tag("Event", "Casual game");
+    pgn += &tag("Site", "?");
+    pgn += &tag("Date", "????.??.??");
+    pgn += &tag("Round", "-");
+    pgn += &tag("White", white);
+    pgn += &tag("Black", black);
+    pgn += &tag("Result", result);
+    pgn += "\n";
+    let mut movetext = numbered_moves(&san);
+    if let Some(outcome) = outcome {
+        movetext += &format!(" {{{}}}", termination(outcome));
+    }
+    movetext += " ";
+    movetext += result;
+    pgn += movetext.trim_start();
+    pgn += "\n";
+    Some(pgn)
+}
+
+/// The SAN moves of the main line, without tags, comments, variations,
+/// move numbers, annotations or the result.
+fn movetext_tokens(pgn: &str) -> Result<Vec<String>, PgnError> {
+    let mut text = String::new();
+    let mut open: Vec<char> = Vec::new();
+    for line in pgn
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('['))
+    {
+        for c in line.chars() {
+            match (open.last(), c) {
+                (Some('{'), '}') | (Some('('), ')') => {
+                    open.pop();
+                    text.push(' ');
+                }
+                (Some('{'), _) => {}
+                (_, '{' | '(') => open.push(c),
+                (Some('('), _) => {}
+                (None, ';') => break,
+                (None, _) => text.push(c),
+                _ => {}
+            }
+        }
+        text.push(' ');
+    }
+    if let Some(&c) = open.last() {
+        return Err(PgnError::Unclosed(c));
+    }
+    Ok(text
+        .split_whitespace()
+        .filter(|token| !["1-0", "0-1", "1/2-1/2", "*"].contains(token))
+        .map(|token| token.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.'))
+        .map(|token| token.trim_end_matches(['+', '#', '!', '?']))
+        .filter(|token| !token.is_empty() && !token.starts_with('$'))
+        // Castling is sometimes written with zeros.
+        .map(|token| token.replace('0', "O"))
+        .collect())
+}
+
+/// The moves of a PGN game's main line, for replaying from the start.
+pub fn parse_moves(pgn: &str) -> Result<Vec<MoveSpec>, PgnError> {
+    let mut board = Chess::new();
+    let mut moves = Vec::new();
+    for (i, san) in movetext_tokens(pgn)?.into_iter().enumerate() {
+        let illegal = || PgnError::IllegalMove(i + 1, san.clone());
+        let legal = board.generate_valid_moves();
+        let spec = legal
+            .iter()
+            .flatten()
+            .flat_map(|mv| {
+                let pawn = matches!(piece_at(&board, mv.from), Some((PieceType::Pawn, _)));
+                let promotions = if pawn && (mv.to.y == 0 || mv.to.y == BOARD_SQUARES - 1) {
+                    vec![
+                        Some(PieceType::Queen),
+                        Some(PieceType::Rook),
+                        Some(PieceType::Bishop),
+                        Some(PieceType::Knight),
+                    ]
+                } else {
+                    vec![None]
+                };
+                promotions.into_iter().map(|promotion| MoveSpec {
+                    from: mv.from,
+                    to: mv.to,
+                    promotion,
+                })
+            })
+            .find(|&spec| {
+                PlayedMove::new(&board, spec)
+                    .is_some_and(|played| san_body(&board, &legal, &played) == san)
+            })
+            .ok_or_else(illegal)?;
+        if !matches!(
+            board.move_piece(spec.from, spec.to),
+            ValidationResult::Valid(_)
+        ) {
+            return Err(illegal());
+        }
+        if let Some(promotion) = spec.promotion {
+            board.promote_piece(promotion).ok_or_else(illegal)?;
+        }
+        moves.push(spec);
+    }
+    Ok(moves)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay_moves;
+
+    #[test]
+    fn each_outcome_has_its_result_and_reason() {
+        let outcomes = [
+            (
+                GameOutcome::Checkmate {
+                    winner: Color::White,
+                },
+                "1-0",
+                "White wins by checkmate",
+            ),
+            (
+                GameOutcome::Checkmate {
+                    winner: Color::Black,
+                },
+                "0-1",
+                "Black wins by checkmate",
+            ),
+            (
+                GameOutcome::OpponentResigned {
+                    winner: Color::Black,
+                },
+                "0-1",
+                "Black wins by resignation",
+            ),
+            (
+                GameOutcome::Draw(DrawReason::Stalemate),
+                "1/2-1/2",
+                "Draw by stalemate",
+            ),
+            (
+                GameOutcome::Draw(DrawReason::Reported),
+                "1/2-1/2",
+                "Draw reported by the opponent",
+            ),
+            (
+                GameOutcome::Draw(DrawReason::Agreed),
+                "1/2-1/2",
+                "Draw by agreement",
+            ),
+        ];
+        let moves = ["f2f3", "e7e5", "g2g4", "d8h4"].map(|uci| MoveSpec::from_uci(uci).unwrap());
+        let (_, history) = replay_moves(&moves).unwrap();
+        for (outcome, token, reason) in outcomes {
+            assert_eq!(result_token(Some(&outcome)), token);
+            let pgn = export(&history, Some(&outcome), "W", "B").unwrap();
+            assert!(pgn.contains(&format!("[Result \"{}\"]", token)));
+            assert!(pgn.ends_with(&format!("Qh4# {{{}}} {}\n", reason, token)));
+        }
+        let pgn = export(&history, None, "W", "B").unwrap();
+        assert!(pgn.ends_with("Qh4# *\n"));
+    }
+
+    #[test]
+    fn copied_move_list_is_numbered_san() {
+        let moves =
+            ["e2e4", "e7e5", "g1f3", "b8c6", "f1b5"].map(|uci| MoveSpec::from_uci(uci).unwrap());
+        let (_, history) = replay_moves(&moves).unwrap();
+        let san = san_moves(&history).unwrap();
+        assert_eq!(numbered_moves(&san), "1. e4 e5 2. Nf3 Nc6 3. Bb5");
+    }
+}
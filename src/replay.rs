@@ -0,0 +1,113 @@
+//! Replay files: a game's moves with the time each one was played, plus who
+//! played it and how, so it can be watched back at its own pace. Details come
+//! first as `key = value` lines, then one `<uci> <seconds>` line per move,
+//! the seconds counted from the start of the game.
+
+use std::time::Duration;
+
+use crate::MoveSpec;
+
+pub struct Replay {
+    pub white: String,
+    pub black: String,
+    /// How the game was played, e.g. `local` or `host 0.0.0.0:3000`.
+    pub mode: String,
+    pub moves: Vec<(MoveSpec, Duration)>,
+}
+
+pub fn save(path: &str, replay: &Replay) -> std::io::Result<()> {
+    let mut text = format!(
+        "white = {}\nblack = {}\nmode = {}\n",
+        replay.white, replay.black, replay.mode
+    );
+    for (spec, at) in &replay.moves {
+        text += &format!("{} {:.3}\n", spec.to_uci(), at.as_secs_f64());
+    }
+    std::fs::write(path, text)
+}
+
+/// `None` if the file can't be read or a line is neither a detail nor a
+/// move. Missing details are left empty.
+pub fn load(path: &str) -> Option<Replay> {
+    let text = std::fs::read_to_string(path).ok()?;
+    let mut replay = Replay {
+        white: String::new(),
+        black: String::new(),
+        mode: String::new(),
+        moves: Vec::new(),
+    };
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim().to_owned();
+            match key.trim() {
+                "white" => replay.white = value,
+                "black" => replay.black = value,
+                "mode" => replay.mode = value,
+                _ => return None,
+            }
+            continue;
+        }
+        let (uci, seconds) = line.split_once(' ')?;
+        let seconds: f64 = seconds.trim().parse().ok()?;
+        if !seconds.is_finite() || seconds < 0. {
+            return None;
+        }
+        replay
+            .moves
+            .push((MoveSpec::from_uci(uci)?, Duration::from_secs_f64(seconds)));
+    }
+    Some(replay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_replay_loads_back() {
+        let path = std::env::temp_dir().join("chess-replay-round-trip.txt");
+        let path = path.to_str().unwrap();
+        let moves = ["e2e4", "e7e5", "g1f3", "b8c6", "a7a8q"];
+        let replay = Replay {
+            white: "Alice".to_owned(),
+            black: "Bob".to_owned(),
+            mode: "host 0.0.0.0:3000".to_owned(),
+            moves: moves
+                .iter()
+                .enumerate()
+                .map(|(i, uci)| {
+                    let at = Duration::from_millis(1250 * i as u64 + 7);
+                    (MoveSpec::from_uci(uci).unwrap(), at)
+                })
+                .collect(),
+        };
+        save(path, &replay).unwrap();
+        let loaded = load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.white, "Alice");
+        assert_eq!(loaded.black, "Bob");
+        assert_eq!(loaded.mode, "host 0.0.0.0:3000");
+        let loaded_moves: Vec<(String, Duration)> = loaded
+            .moves
+            .into_iter()
+            .map(|(spec, at)| (spec.to_uci(), at))
+            .collect();
+        let saved_moves: Vec<(String, Duration)> = replay
+            .moves
+            .into_iter()
+            .map(|(spec, at)| (spec.to_uci(), at))
+            .collect();
+        assert_eq!(loaded_moves, saved_moves);
+    }
+
+    #[test]
+    fn load_rejects_a_bad_line() {
+        let path = std::env::temp_dir().join("chess-replay-bad-line.txt");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "white = Alice\ne2e4 0.5\nnot a move\n").unwrap();
+        let loaded = load(path);
+        std::fs::remove_file(path).unwrap();
+        assert!(loaded.is_none());
+    }
+}
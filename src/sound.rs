@@ -0,0 +1,96 @@
+//! Sounds played as pieces move. Each piece type can have its own sound,
+//! falling back to a generic one, and no sound at all when neither is set.
+
+use chess::PieceType;
+use ggez::{
+    audio::{SoundData, SoundSource, Source},
+    Context, GameError, GameResult,
+};
+
+#[derive(Default)]
+pub struct MoveSounds {
+    generic: Option<Source>,
+    /// Indexed by `PieceType as usize`.
+    pieces: [Option<Source>; 6],
+}
+
+impl MoveSounds {
+    pub fn load(
+        ctx: &mut Context,
+        generic: Option<&str>,
+        pieces: &[Option<String>; 6],
+    ) -> GameResult<Self> {
+        let generic = generic.map(|path| load_source(ctx, path)).transpose()?;
+        let mut sources: [Option<Source>; 6] = Default::default();
+        for (source, path) in sources.iter_mut().zip(pieces) {
+            *source = path
+                .as_deref()
+                .map(|path| load_source(ctx, path))
+                .transpose()?;
+        }
+        Ok(Self {
+            generic,
+            pieces: sources,
+        })
+    }
+
+    /// The sound for a move of `piece`.
+    fn for_piece(&mut self, piece: PieceType) -> Option<&mut Source> {
+        pick(&mut self.pieces, &mut self.generic, piece)
+    }
+
+    /// Plays on the source itself rather than detached, so a sound still
+    /// going stops when these are dropped, the game crashing included.
+    pub fn play(&mut self, ctx: &mut Context, piece: PieceType) -> GameResult {
+        if let Some(source) = self.for_piece(piece) {
+            source.play(ctx)?;
+        }
+        Ok(())
+    }
+}
+
+/// The entry for `piece`, or `generic` when it has none.
+fn pick<'a, T>(
+    pieces: &'a mut [Option<T>; 6],
+    generic: &'a mut Option<T>,
+    piece: PieceType,
+) -> Option<&'a mut T> {
+    pieces[piece as usize].as_mut().or(generic.as_mut())
+}
+
+fn load_source(ctx: &mut Context, path: &str) -> GameResult<Source> {
+    let bytes = std::fs::read(path)
+        .map_err(|err| GameError::ResourceLoadError(format!("{}: {}", path, err)))?;
+    Source::from_data(ctx, SoundData::from_bytes(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pieces_without_a_sound_use_the_generic_one() {
+        let mut pieces = [None, None, None, None, Some("knight.ogg"), None];
+        let mut generic = Some("move.ogg");
+        let all = [
+            PieceType::King,
+            PieceType::Queen,
+            PieceType::Rook,
+            PieceType::Bishop,
+            PieceType::Knight,
+            PieceType::Pawn,
+        ];
+        for piece in all {
+            let expected = if matches!(piece, PieceType::Knight) {
+                "knight.ogg"
+            } else {
+                "move.ogg"
+            };
+            assert_eq!(
+                pick(&mut pieces, &mut generic, piece).copied(),
+                Some(expected)
+            );
+        }
+        assert_eq!(pick(&mut pieces, &mut None, PieceType::Pawn), None);
+    }
+}
@@ -0,0 +1,381 @@
+//! A minimal WebSocket (RFC 6455) transport, so browser clients can join a
+//! game. Every packet is sent as a single binary message.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Mutex},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::Transport;
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+pub struct WsTransport {
+    stream: TcpStream,
+    /// Shared by every clone, so the pongs and closes the reader thread
+    /// sends can't land in the middle of a frame the game is writing.
+    writer: Arc<Mutex<TcpStream>>,
+    /// Clients have to mask every frame they send, servers must not.
+    mask: bool,
+    /// Messages whose frames add up to more than this are refused before
+    /// their payload is read.
+    max_message_size: usize,
+    /// The part of the last received message that `read` has not handed out.
+    pending: Vec<u8>,
+    pending_offset: usize,
+}
+
+impl WsTransport {
+    /// Performs the server side of the opening handshake.
+    pub fn accept(mut stream: TcpStream, max_message_size: usize) -> io::Result<Self> {
+        let request = read_headers(&mut stream)?;
+        let key = header(&request, "sec-websocket-key")
+            .ok_or_else(|| invalid("Missing Sec-WebSocket-Key"))?;
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        );
+        stream.write_all(response.as_bytes())?;
+        Self::new(stream, false, max_message_size)
+    }
+
+    /// Performs the client side of the opening handshake against `host`.
+    pub fn connect(mut stream: TcpStream, host: &str, max_message_size: usize) -> io::Result<Self> {
+        let mut nonce = [0u8; 16];
+        nonce[..8].copy_from_slice(&random_u64().to_le_bytes());
+        nonce[8..].copy_from_slice(&random_u64().to_le_bytes());
+        let key = STANDARD.encode(nonce);
+        let request = format!(
+            "GET / HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            host, key
+        );
+        stream.write_all(request.as_bytes())?;
+        let response = read_headers(&mut stream)?;
+        if !response.starts_with("HTTP/1.1 101") {
+            return Err(invalid("Server refused the WebSocket upgrade"));
+        }
+        if header(&response, "sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+            return Err(invalid("Bad Sec-WebSocket-Accept"));
+        }
+        Self::new(stream, true, max_message_size)
+    }
+
+    fn new(stream: TcpStream, mask: bool, max_message_size: usize) -> io::Result<Self> {
+        let writer = Arc::new(Mutex::new(stream.try_clone()?));
+        Ok(Self {
+            stream,
+            writer,
+            mask,
+            max_message_size,
+            pending: Vec::new(),
+            pending_offset: 0,
+        })
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | opcode);
+        let mask_bit = if self.mask { 0x80 } else { 0 };
+        if payload.len() < 126 {
+            frame.push(mask_bit | payload.len() as u8);
+        } else if payload.len() <= u16::MAX as usize {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        } else {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+        if self.mask {
+            let key = (random_u64() as u32).to_be_bytes();
+            frame.extend_from_slice(&key);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+        } else {
+            frame.extend_from_slice(payload);
+        }
+        self.writer.lock().unwrap().write_all(&frame)
+    }
+
+    /// Reads frames until a full data message has arrived. Returns `None`
+    /// once the peer closes the connection.
+    fn read_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut message = Vec::new();
+        loop {
+            let mut head = [0u8; 2];
+            self.stream.read_exact(&mut head)?;
+            let fin = head[0] & 0x80 != 0;
+            let opcode = head[0] & 0x0f;
+            let masked = head[1] & 0x80 != 0;
+            let len = match head[1] & 0x7f {
+                126 => {
+                    let mut len = [0u8; 2];
+                    self.stream.read_exact(&mut len)?;
+                    u16::from_be_bytes(len) as usize
+                }
+                127 => {
+                    let mut len = [0u8; 8];
+                    self.stream.read_exact(&mut len)?;
+                    u64::from_be_bytes(len) as usize
+                }
+                len => len as usize,
+            };
+            if len > self.max_message_size.saturating_sub(message.len()) {
+                return Err(invalid("WebSocket message too large"));
+            }
+            let mut key = [0u8; 4];
+            if masked {
+                self.stream.read_exact(&mut key)?;
+            }
+            let mut payload = vec![0u8; len];
+            self.stream.read_exact(&mut payload)?;
+            if masked {
+                for (i, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= key[i % 4];
+                }
+            }
+            match opcode {
+                OP_CONTINUATION | OP_TEXT | OP_BINARY => {
+                    message.extend_from_slice(&payload);
+                    if fin {
+                        return Ok(Some(message));
+                    }
+                }
+                OP_PING => self.write_frame(OP_PONG, &payload)?,
+                OP_PONG => {}
+                OP_CLOSE => {
+                    let _ = self.write_frame(OP_CLOSE, &payload);
+                    return Ok(None);
+                }
+                _ => return Err(invalid("Unknown WebSocket opcode")),
+            }
+        }
+    }
+}
+
+impl Read for WsTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending_offset == self.pending.len() {
+            match self.read_message()? {
+                Some(message) => {
+                    self.pending = message;
+                    self.pending_offset = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let remaining = &self.pending[self.pending_offset..];
+        let size = remaining.len().min(buf.len());
+        buf[..size].copy_from_slice(&remaining[..size]);
+        self.pending_offset += size;
+        Ok(size)
+    }
+}
+
+impl Write for WsTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_frame(OP_BINARY, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+impl Transport for WsTransport {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self {
+            stream: self.stream.try_clone()?,
+            writer: self.writer.clone(),
+            mask: self.mask,
+            max_message_size: self.max_message_size,
+            pending: Vec::new(),
+            pending_offset: 0,
+        }))
+    }
+
+    fn shutdown(&mut self) {
+        let _ = self.write_frame(OP_CLOSE, &[]);
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+fn accept_key(key: &str) -> String {
+    let digest = sha1_smol::Sha1::from(format!("{}{}", key, ACCEPT_GUID)).digest();
+    STANDARD.encode(digest.bytes())
+}
+
+/// Reads an HTTP request or response up to and including the blank line.
+fn read_headers(stream: &mut TcpStream) -> io::Result<String> {
+    let mut data = Vec::new();
+    let mut byte = [0u8; 1];
+    while !data.ends_with(b"\r\n\r\n") {
+        if data.len() >= MAX_HEADER_SIZE {
+            return Err(invalid("HTTP headers too large"));
+        }
+        stream.read_exact(&mut byte)?;
+        data.push(byte[0]);
+    }
+    String::from_utf8(data).map_err(|_| invalid("HTTP headers are not UTF-8"))
+}
+
+fn header<'a>(headers: &'a str, name: &str) -> Option<&'a str> {
+    headers.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use chess_networking::{Move, Start};
+
+    use std::time::Duration;
+
+    use super::*;
+    use crate::PacketType;
+
+    fn send(transport: &mut WsTransport, packet: PacketType) {
+        let data: Vec<u8> = Vec::try_from(packet).unwrap();
+        transport.write_all(&data).unwrap();
+        transport.flush().unwrap();
+    }
+
+    fn receive(transport: &mut WsTransport) -> PacketType {
+        let message = transport.read_message().unwrap().unwrap();
+        PacketType::try_from(&message[..]).unwrap()
+    }
+
+    #[test]
+    fn endpoints_exchange_start_and_move() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let host = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut host = WsTransport::accept(stream, 1024).unwrap();
+            let start = match receive(&mut host) {
+                PacketType::Start(start) => start,
+                packet => panic!("Expected Start, got {:?}", packet),
+            };
+            assert!(!start.is_white);
+            assert_eq!(start.name.as_deref(), Some("client"));
+            send(
+                &mut host,
+                PacketType::Move(Move {
+                    from: (4, 1),
+                    to: (4, 3),
+                    promotion: None,
+                    forfeit: false,
+                    offer_draw: false,
+                }),
+            );
+        });
+        let stream = TcpStream::connect(&address).unwrap();
+        let mut client = WsTransport::connect(stream, &address, 1024).unwrap();
+        send(
+            &mut client,
+            PacketType::Start(Start {
+                is_white: false,
+                name: Some("client".to_owned()),
+                fen: None,
+                time: None,
+                inc: None,
+            }),
+        );
+        match receive(&mut client) {
+            PacketType::Move(mv) => {
+                assert_eq!((mv.from, mv.to), ((4, 1), (4, 3)));
+                assert!(mv.promotion.is_none());
+            }
+            packet => panic!("Expected Move, got {:?}", packet),
+        }
+        host.join().unwrap();
+    }
+
+    #[test]
+    fn fragment_claiming_too_much_is_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let mut host = WsTransport::new(server, false, 1024).unwrap();
+        // A first fragment of 3 bytes, then a continuation claiming
+        // u64::MAX more, which must not wrap around the size check.
+        let mut frames = vec![OP_BINARY, 3, 1, 2, 3, 0x80 | OP_CONTINUATION, 127];
+        frames.extend_from_slice(&u64::MAX.to_be_bytes());
+        client.write_all(&frames).unwrap();
+        let err = host.read_message().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn pongs_from_the_reader_do_not_break_up_data_frames() {
+        const MESSAGES: usize = 50;
+        const SIZE: usize = 200_000;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let mut host = WsTransport::new(server, false, SIZE).unwrap();
+        let mut client = WsTransport::new(client, true, SIZE).unwrap();
+
+        // Answers every ping until the client says it is done.
+        let mut host_reader = host.try_clone().unwrap();
+        let host_reader = std::thread::spawn(move || {
+            let mut done = [0u8; 4];
+            host_reader.read_exact(&mut done).unwrap();
+            assert_eq!(&done, b"done");
+        });
+        let host_writer = std::thread::spawn(move || {
+            for i in 0..MESSAGES {
+                host.write_all(&vec![i as u8; SIZE]).unwrap();
+            }
+        });
+        let mut client_reader = client.try_clone().unwrap();
+        let client_reader = std::thread::spawn(move || {
+            // Let the socket fill up, so the host's writes go out in pieces.
+            std::thread::sleep(Duration::from_millis(100));
+            let mut message = vec![0u8; SIZE];
+            for i in 0..MESSAGES {
+                client_reader.read_exact(&mut message).unwrap();
+                assert!(message.iter().all(|&b| b == i as u8));
+            }
+        });
+        for _ in 0..MESSAGES {
+            client.write_frame(OP_PING, b"ping").unwrap();
+        }
+        client.write_all(b"done").unwrap();
+
+        host_writer.join().unwrap();
+        client_reader.join().unwrap();
+        host_reader.join().unwrap();
+    }
+}